@@ -1,30 +1,110 @@
-use anyhow::{bail, Ok, Result};
-use fuser::FileType;
+use anyhow::{anyhow, bail, Ok, Result};
+use fuser::{FileType, FUSE_ROOT_ID};
+use libc::{
+    FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE, PATH_MAX, RENAME_EXCHANGE,
+    RENAME_NOREPLACE, S_ISGID, XATTR_CREATE, XATTR_REPLACE,
+};
 use serde::{Deserialize, Serialize};
-use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::ffi::{OsStr, OsString};
 use std::fs::OpenOptions;
-use std::io::{Read, Seek, SeekFrom};
-use std::mem::size_of;
+use std::io::{BufWriter, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::str::FromStr;
-use std::usize;
+use std::time::{Duration, SystemTime};
 use std::{fs::File, io::Write};
 
-use crate::sector::{self, DirData, Empty, FileData, FileMetadata, Sector, DATA_CHUNK_SIZE};
+use crate::encoding;
+use crate::error::FsError;
+use crate::sector::{
+    self, Bitmap, DirData, DirEntry, Empty, FileData, FileMetadata, Geometry, InodeTable,
+    Permissions, Sector, SymlinkMetadata, XattrData, INODE_TABLE_SIZE, SYMLINK_INLINE_SIZE,
+};
 
-use sector::FILE_NAME_SIZE;
+use cache::{SectorCache, DEFAULT_CACHE_SIZE};
+use journal::{Journal, METADATA_TARGET, SUPERBLOCK_TARGET};
+use superblock::{Superblock, SUPERBLOCK_SIZE};
+
+/// Bytes reserved for `Metadata`, right after the superblock.
+///
+/// It is fixed so that new `Metadata` fields do not move the sectors of existing containers.
+const METADATA_SIZE: usize = 56;
+/// Like relatime, atime is refreshed at least once per period even if the file did not change.
+const RELATIME_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+/// Limits of extended attributes, the same as Linux
+const XATTR_NAME_MAX: usize = 255;
+const XATTR_SIZE_MAX: usize = 65536;
+const XATTR_LIST_MAX: usize = 65536;
+/// Namespaces accepted for extended attribute names
+const XATTR_NAMESPACES: [&str; 4] = ["security.", "system.", "trusted.", "user."];
+/// Largest file size, the kernel passing offsets as an i64
+const MAX_FILE_SIZE: u64 = i64::MAX as u64;
+/// Records a single step of a long operation can add between two checkpoints
+const CHECKPOINT_MARGIN: usize = 8;
 
 pub struct Container {
     _container_name: String,
     file: File,
+    superblock: Superblock,
     metadata: Metadata,
+    /// Sectors of the inode table, in chain order
+    inode_table: Vec<u64>,
+    /// Metadata sector of each inode, indexed by inode number
+    inodes: Vec<Option<u64>>,
+    /// Sectors of the refcount table, in chain order
+    refcount_table: Vec<u64>,
+    /// References beyond the first of each shared sector
+    refcounts: BTreeMap<u64, u32>,
+    /// Number of free sectors
+    free_sectors: u64,
+    /// Free sectors of each group, with the bitmap feature
+    group_free: Vec<u64>,
+    journal: Option<Journal>,
+    /// Images written by the current transaction, by sector, `METADATA_TARGET` or
+    /// `SUPERBLOCK_TARGET`
+    pending: Option<BTreeMap<u64, Vec<u8>>>,
+    /// Images written by committed transactions, waiting for the next write-back
+    dirty: BTreeMap<u64, Vec<u8>>,
+    cache: SectorCache,
 }
 #[derive(Debug)]
 pub struct Attr {
     pub ino: u64,
     pub filetype: FileType,
     pub size: u64,
+    /// 512-byte blocks allocated to the inode
+    pub blocks: u64,
+    pub nlink: u32,
+    pub perm: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    pub crtime: SystemTime,
+}
+#[derive(Debug)]
+pub struct StatFs {
+    pub block_size: u32,
+    pub blocks: u64,
+    pub free_blocks: u64,
+    pub files: u64,
+    pub free_files: u64,
+    pub name_max: u32,
+}
+
+/// Choices made when creating a container with `Container::mkfs`.
+#[derive(Debug, Clone, Default)]
+pub struct MkfsOptions {
+    pub geometry: Geometry,
+    pub label: String,
+    /// Identifier of the container, random if None
+    pub uuid: Option<[u8; 16]>,
+    /// Bytes preallocated as free sectors when creating the container
+    pub size: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -34,116 +114,573 @@ pub struct Metadata {
     first_empty_sector: Option<u64>,
     last_empty_sector: Option<u64>,
     next_ino: u64,
+    inode_table: Option<u64>,
+    /// Maximum number of sectors, None for no limit. A u32 fills the last bytes of
+    /// `METADATA_SIZE` and still allows more than a terabyte.
+    max_sectors: Option<u32>,
 }
 
 impl Container {
+    /// Open the container, creating it with the default options if the file does not exist.
     pub fn new(container_name: String) -> Result<Self> {
-        //check if file exist
-        let (file, metadata) = if Path::new(&container_name).exists() {
-            //Load an existing container
-            let mut file = OpenOptions::new()
-                .write(true)
-                .read(true)
-                .open(&container_name)?;
-            let mut buff = [0; size_of::<Metadata>()];
-            let read_count = file.read(&mut buff)?;
-            if read_count < size_of::<Metadata>() {
-                bail!("The file {container_name} is smaller than the container metadata.");
+        if !Path::new(&container_name).exists() {
+            return Self::mkfs(container_name, &MkfsOptions::default());
+        }
+        let mut container = Self::open(container_name)?;
+        container.count_free_sectors()?;
+        container.load_inode_table()?;
+        container.load_refcounts()?;
+        Ok(container)
+    }
+    /// Create a container, failing if the file already exists.
+    pub fn mkfs(container_name: String, options: &MkfsOptions) -> Result<Self> {
+        let mut superblock = Superblock::new();
+        superblock.set_geometry(options.geometry)?;
+        superblock.set_label(&options.label)?;
+        superblock.set_uuid(match options.uuid {
+            Some(uuid) => uuid,
+            None => Self::random_uuid()?,
+        });
+        let mut file = File::create_new(&container_name)?;
+        //With the bitmap feature, sector 0 is the bitmap of the first group
+        let bitmap = superblock
+            .group_size()
+            .map(|_| Self::group_bitmap(&superblock));
+        let root_dir_sector = u64::from(bitmap.is_some());
+        let metadata = Metadata {
+            root_dir_sector,
+            sector_count: root_dir_sector + 1,
+            first_empty_sector: None,
+            last_empty_sector: None,
+            next_ino: 2,
+            inode_table: None,
+            max_sectors: None,
+        };
+        //The root directory belongs to the owner of the container
+        let owner = file.metadata()?;
+        let mut root_metadata = FileMetadata::new(FUSE_ROOT_ID, None);
+        root_metadata.set_permissions(Permissions {
+            mode: 0o755,
+            uid: owner.uid(),
+            gid: owner.gid(),
+        });
+        root_metadata.set_nlink(2);
+        let first_sector = Sector::DirMetadata(root_metadata);
+
+        file.write_all(&encoding::encode(&superblock, SUPERBLOCK_SIZE)?)?;
+        //The journal starts empty, its bytes are left as a hole
+        file.seek(SeekFrom::Start(superblock.metadata_offset()))?;
+        file.write_all(&encoding::encode(&metadata, METADATA_SIZE)?)?;
+        if let Some(mut bitmap) = bitmap {
+            bitmap.set_used(1, true);
+            file.write_all(&encoding::encode_sector(
+                &Sector::Bitmap(bitmap),
+                superblock.sector_size(),
+                superblock.has_checksums(),
+            )?)?;
+        }
+        file.write_all(&encoding::encode_sector(
+            &first_sector,
+            superblock.sector_size(),
+            superblock.has_checksums(),
+        )?)?;
+
+        let mut container = Self::with_header(container_name, file, superblock, metadata);
+        container.count_free_sectors()?;
+        container.load_inode_table()?;
+        if let Some(size) = options.size {
+            container.preallocate(size)?;
+        }
+        Ok(container)
+    }
+    /// Open an existing container file, without loading the inode table and free list.
+    fn open(container_name: String) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .read(true)
+            .open(&container_name)?;
+        let (mut superblock, mut metadata) = Self::read_header(&mut file, &container_name)?;
+        if let Some(journal) = superblock.journal() {
+            let records = journal.committed(&mut file)?;
+            if !records.is_empty() {
+                //The container was not closed properly after a commit
+                Self::apply(&mut file, &superblock, &records)?;
+                file.sync_data()?;
+                journal.clear(&mut file)?;
+                file.seek(SeekFrom::Start(0))?;
+                (superblock, metadata) = Self::read_header(&mut file, &container_name)?;
             }
-            let metadata: Metadata = bincode::deserialize(&buff[..])?;
-            (file, metadata)
-        } else {
-            //Initialize the container
-            let mut file = File::create_new(&container_name)?;
-            let metadata = Metadata {
-                root_dir_sector: 0,
-                sector_count: 1,
-                first_empty_sector: None,
-                last_empty_sector: None,
-                next_ino: 2,
-            };
-            let first_sector = Sector::DirMetadata(FileMetadata::new(1, None));
-
-            let mut buff = Vec::with_capacity(size_of::<Metadata>());
-            bincode::serialize_into(&mut buff, &metadata)?;
-            buff.resize(size_of::<Metadata>(), 0);
-            file.write_all(&buff)?;
-
-            let mut buff = Vec::with_capacity(size_of::<Sector>());
-            bincode::serialize_into(&mut buff, &first_sector)?;
-            buff.resize(size_of::<Sector>(), 0);
-            file.write_all(&buff)?;
-
-            (file, metadata)
-        };
-        Ok(Self {
+        }
+        Ok(Self::with_header(
+            container_name,
+            file,
+            superblock,
+            metadata,
+        ))
+    }
+    fn with_header(
+        container_name: String,
+        file: File,
+        superblock: Superblock,
+        metadata: Metadata,
+    ) -> Self {
+        Self {
             _container_name: container_name,
             file,
+            journal: superblock.journal(),
+            cache: SectorCache::new(DEFAULT_CACHE_SIZE, superblock.sector_size()),
+            superblock,
             metadata,
-        })
+            inode_table: Vec::new(),
+            inodes: Vec::new(),
+            refcount_table: Vec::new(),
+            refcounts: BTreeMap::new(),
+            free_sectors: 0,
+            group_free: Vec::new(),
+            pending: None,
+            dirty: BTreeMap::new(),
+        }
+    }
+    /// Random version 4 UUID.
+    fn random_uuid() -> Result<[u8; 16]> {
+        let mut uuid = [0; 16];
+        File::open("/dev/urandom")?.read_exact(&mut uuid)?;
+        uuid[6] = (uuid[6] & 0x0f) | 0x40;
+        uuid[8] = (uuid[8] & 0x3f) | 0x80;
+        Ok(uuid)
+    }
+    /// Grow a new container to `size` bytes, the sectors added forming the free list.
+    fn preallocate(&mut self, size: u64) -> Result<()> {
+        let sector_size = self.sector_size() as u64;
+        let sector_count = size.saturating_sub(self.superblock.sectors_offset()) / sector_size;
+        let first_sector_id = self.metadata.sector_count;
+        if sector_count <= first_sector_id {
+            return Ok(());
+        }
+        if self.metadata.first_empty_sector.is_some() {
+            bail!("Only a container without free sectors can be preallocated.");
+        }
+        let group_size = self.superblock.group_size();
+        let offset = self.superblock.sectors_offset() + first_sector_id * sector_size;
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut writer = BufWriter::new(&mut self.file);
+        for sector_id in first_sector_id..sector_count {
+            let sector = match group_size {
+                Some(group_size) if sector_id % group_size == 0 => {
+                    Sector::Bitmap(Self::group_bitmap(&self.superblock))
+                }
+                Some(_) => Sector::Empty(Empty::default()),
+                None => {
+                    let mut empty_sector = Empty::default();
+                    if sector_id > first_sector_id {
+                        empty_sector.set_previous(sector_id - 1);
+                    }
+                    if sector_id + 1 < sector_count {
+                        empty_sector.set_next(sector_id + 1);
+                    }
+                    Sector::Empty(empty_sector)
+                }
+            };
+            writer.write_all(&encoding::encode_sector(
+                &sector,
+                sector_size as usize,
+                self.superblock.has_checksums(),
+            )?)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        self.metadata.sector_count = sector_count;
+        if group_size.is_some() {
+            self.count_free_sectors()?;
+        } else {
+            self.metadata.first_empty_sector = Some(first_sector_id);
+            self.metadata.last_empty_sector = Some(sector_count - 1);
+            self.free_sectors += sector_count - first_sector_id;
+        }
+        self.write_metadata()?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+    /// Bitmap of a new group, where only the bitmap itself is used.
+    fn group_bitmap(superblock: &Superblock) -> Bitmap {
+        let mut bitmap = Bitmap::new(encoding::bitmap_size(superblock.sector_size()));
+        bitmap.set_used(0, true);
+        bitmap
+    }
+    /// Bytes taken by every sector of the container
+    pub fn sector_size(&self) -> usize {
+        self.superblock.sector_size()
+    }
+    pub fn geometry(&self) -> Geometry {
+        self.superblock.geometry()
+    }
+    pub fn label(&self) -> &str {
+        self.superblock.label()
+    }
+    pub const fn uuid(&self) -> [u8; 16] {
+        self.superblock.uuid()
+    }
+    /// Bytes of file data or extended attributes held by a sector
+    fn chunk_size(&self) -> usize {
+        self.superblock.geometry().data_chunk_size
+    }
+    /// Read the superblock and `Metadata` of an existing container file, making sure it
+    /// really is a container this build can use.
+    fn read_header(file: &mut File, container_name: &str) -> Result<(Superblock, Metadata)> {
+        let mut buff = Vec::with_capacity(SUPERBLOCK_SIZE + METADATA_SIZE);
+        file.take((SUPERBLOCK_SIZE + METADATA_SIZE) as u64)
+            .read_to_end(&mut buff)?;
+        if let Some(superblock) = Superblock::decode(&buff)? {
+            superblock.check()?;
+            if file.metadata()?.len() < superblock.sectors_offset() {
+                bail!("The file {container_name} is smaller than the container metadata.");
+            }
+            let metadata = Self::read_metadata(file, &superblock)?;
+            return Ok((superblock, metadata));
+        }
+        //Containers from before the superblock start with the metadata, they are recognised
+        //by metadata consistent with the size of the file
+        let superblock = Superblock::legacy();
+        let metadata: Option<Metadata> = encoding::decode(&buff).ok();
+        let file_length = file.metadata()?.len();
+        let is_container = metadata.as_ref().is_some_and(|metadata| {
+            let in_bounds = |sector_id: Option<u64>| {
+                sector_id.map_or(true, |sector_id| sector_id < metadata.sector_count)
+            };
+            metadata
+                .sector_count
+                .checked_mul(superblock.sector_size() as u64)
+                .and_then(|length| length.checked_add(superblock.sectors_offset()))
+                == Some(file_length)
+                && in_bounds(Some(metadata.root_dir_sector))
+                && in_bounds(metadata.first_empty_sector)
+                && in_bounds(metadata.last_empty_sector)
+                && in_bounds(metadata.inode_table)
+        });
+        match metadata {
+            Some(metadata) if is_container => Ok((superblock, metadata)),
+            _ => bail!("The file {container_name} is not a mini-fs container."),
+        }
+    }
+    fn read_metadata(file: &mut File, superblock: &Superblock) -> Result<Metadata> {
+        file.seek(SeekFrom::Start(superblock.metadata_offset()))?;
+        let mut buff = [0; METADATA_SIZE];
+        file.read_exact(&mut buff)?;
+        encoding::decode(&buff)
+    }
+    /// Superblock at the beginning of `file`, None for containers from before it.
+    fn read_superblock(file: &mut File) -> Result<Option<Superblock>> {
+        file.seek(SeekFrom::Start(0))?;
+        let mut buff = Vec::with_capacity(SUPERBLOCK_SIZE);
+        file.take(SUPERBLOCK_SIZE as u64).read_to_end(&mut buff)?;
+        Superblock::decode(&buff)
     }
     fn read_sector(&mut self, sector_id: u64) -> Result<Sector> {
+        //Sectors changed by the current transaction are not cached
+        let pending = self
+            .pending
+            .as_ref()
+            .is_some_and(|pending| pending.contains_key(&sector_id));
+        if !pending {
+            if let Some(sector) = self.cache.get(sector_id) {
+                return Ok(sector);
+            }
+        }
+        let buff = self.read_sector_bytes(sector_id)?;
+        if self.superblock.has_checksums() && !encoding::verify_sector(&buff) {
+            let owner = self
+                .sector_owner(sector_id)
+                .map_or_else(|| "unknown".to_string(), |ino| ino.to_string());
+            let message = format!("Checksum mismatch in sector {sector_id} (inode {owner})");
+            return Err(anyhow!(FsError::Io).context(message));
+        }
+        let sector: Sector = encoding::decode(&buff)?;
+        if !pending {
+            self.cache.insert(sector_id, sector.clone());
+        }
+        Ok(sector)
+    }
+    /// Decode a sector without verifying its checksum.
+    fn read_sector_unchecked(&mut self, sector_id: u64) -> Option<Sector> {
+        let buff = self.read_sector_bytes(sector_id).ok()?;
+        encoding::decode(&buff).ok()
+    }
+    /// Inode whose metadata, data, entries or extended attributes use `sector_id`.
+    ///
+    /// It is only used to report corruption, so sectors are read without checking them and
+    /// chains are not followed further than the number of sectors.
+    fn sector_owner(&mut self, sector_id: u64) -> Option<u64> {
+        let mut inodes: Vec<(u64, u64)> = self
+            .inodes
+            .iter()
+            .enumerate()
+            .filter_map(|(ino, sector)| sector.map(|sector| (ino as u64, sector)))
+            .collect();
+        inodes.push((FUSE_ROOT_ID, self.metadata.root_dir_sector));
+        for (ino, metadata_sector_id) in inodes {
+            if metadata_sector_id == sector_id {
+                return Some(ino);
+            }
+            let Some(sector) = self.read_sector_unchecked(metadata_sector_id) else {
+                continue;
+            };
+            let Some(metadata) = sector.metadata() else {
+                continue;
+            };
+            //Block maps are walked node by node, with the same bound as chains
+            let mut nodes: Vec<u64> = metadata.first_sector().into_iter().collect();
+            for _ in 0..self.metadata.sector_count {
+                let Some(node_id) = nodes.pop() else {
+                    break;
+                };
+                let Some(Sector::BlockMap(node)) = self.read_sector_unchecked(node_id) else {
+                    continue;
+                };
+                if node_id == sector_id || node.entries().contains(&Some(sector_id)) {
+                    return Some(ino);
+                }
+                if node.depth() > 0 {
+                    nodes.extend(node.entries().iter().flatten());
+                }
+            }
+            for first_sector in [metadata.first_sector(), metadata.xattr_sector()] {
+                let mut next_sector = first_sector;
+                for _ in 0..self.metadata.sector_count {
+                    let Some(current_sector_id) = next_sector else {
+                        break;
+                    };
+                    if current_sector_id == sector_id {
+                        return Some(ino);
+                    }
+                    next_sector = match self.read_sector_unchecked(current_sector_id) {
+                        Some(Sector::FileData(file_data)) => file_data.next(),
+                        Some(Sector::DirData(dir_data)) => dir_data.next_sector(),
+                        Some(Sector::XattrData(xattr_data)) => xattr_data.next_sector(),
+                        _ => None,
+                    };
+                }
+            }
+        }
+        None
+    }
+    fn read_sector_bytes(&mut self, sector_id: u64) -> Result<Vec<u8>> {
         if sector_id >= self.metadata.sector_count {
             bail!("Seeking out-of-bound sector {sector_id}");
         }
-        //Skip the metadata and seek
-        let offset = size_of::<Metadata>() as u64 + sector_id * size_of::<Sector>() as u64;
+        //Sectors written by the current transaction are not in the file yet
+        if let Some(image) = self
+            .pending
+            .as_ref()
+            .and_then(|pending| pending.get(&sector_id))
+        {
+            return Ok(image.clone());
+        }
+        if let Some(image) = self.dirty.get(&sector_id) {
+            return Ok(image.clone());
+        }
+        let sector_size = self.sector_size();
+        let mut buff = vec![0; sector_size];
+        //Skip the header and seek
+        let offset = self.superblock.sectors_offset() + sector_id * sector_size as u64;
         let offset = SeekFrom::Start(offset);
         self.file.seek(offset)?;
 
         //Read the sector
-        let mut buff = [0; size_of::<Sector>()];
         let read_count = self.file.read(&mut buff)?;
-        if read_count < size_of::<Sector>() {
+        if read_count < sector_size {
             bail!("Reading not enough byte for sector {sector_id}.");
         }
-
-        //Deserialize
-        let sector: Sector = bincode::deserialize(&buff[..])?;
-        Ok(sector)
+        Ok(buff)
     }
     fn write_metadata(&mut self) -> Result<()> {
-        self.file.seek(SeekFrom::Start(0))?;
-        let mut buff = Vec::with_capacity(size_of::<Metadata>());
-        bincode::serialize_into(&mut buff, &self.metadata)?;
-        buff.resize(size_of::<Metadata>(), 0);
-        self.file.write_all(&buff)?;
-        Ok(())
+        let buff = encoding::encode(&self.metadata, METADATA_SIZE)?;
+        self.write_target(METADATA_TARGET, buff)
+    }
+    fn write_superblock(&mut self) -> Result<()> {
+        let buff = encoding::encode(&self.superblock, SUPERBLOCK_SIZE)?;
+        self.write_target(SUPERBLOCK_TARGET, buff)
     }
     fn write_sector(&mut self, sector_id: u64, sector: &Sector) -> Result<u64> {
         if sector_id >= self.metadata.sector_count {
             bail!("Seeking out-of-bound sector {sector_id}");
         }
-        //Skip the metadata and seek
-        let offset = size_of::<Metadata>() as u64 + sector_id * size_of::<Sector>() as u64;
-        let offset = SeekFrom::Start(offset);
-        self.file.seek(offset)?;
-
-        //Write the sector
-        let mut buff = Vec::with_capacity(size_of::<Sector>());
-        bincode::serialize_into(&mut buff, sector)?;
-        buff.resize(size_of::<Sector>(), 0);
-        self.file.write_all(&buff)?;
-        self.file.flush()?;
-        Ok(size_of::<Sector>() as u64)
+        let buff =
+            encoding::encode_sector(sector, self.sector_size(), self.superblock.has_checksums())?;
+        self.write_target(sector_id, buff)?;
+        Ok(self.sector_size() as u64)
+    }
+    /// Keep `buff` for the commit of the current transaction, or for the next write-back.
+    fn write_target(&mut self, target: u64, buff: Vec<u8>) -> Result<()> {
+        if let Some(pending) = &mut self.pending {
+            pending.insert(target, buff);
+            return Ok(());
+        }
+        self.commit(BTreeMap::from([(target, buff)]))
+    }
+    /// Write images at their place in the container.
+    fn apply(file: &mut File, superblock: &Superblock, records: &[(u64, Vec<u8>)]) -> Result<()> {
+        for (target, image) in records {
+            let (offset, size) = if *target == METADATA_TARGET {
+                (superblock.metadata_offset(), METADATA_SIZE)
+            } else if *target == SUPERBLOCK_TARGET {
+                (0, SUPERBLOCK_SIZE)
+            } else {
+                let sector_size = superblock.sector_size();
+                let offset = superblock.sectors_offset() + target * sector_size as u64;
+                (offset, sector_size)
+            };
+            let Some(image) = image.get(..size) else {
+                bail!(
+                    "Image of {size} bytes expected, found {} bytes.",
+                    image.len()
+                );
+            };
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(image)?;
+        }
+        Ok(())
+    }
+    /// Run `op` as a transaction: its writes reach the container together through the
+    /// journal, or not at all if it fails.
+    ///
+    /// Nested transactions are part of the outer one. Without a journal `op` writes directly.
+    fn transaction<T>(&mut self, op: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if self.journal.is_none() || self.pending.is_some() {
+            return op(self);
+        }
+        self.pending = Some(BTreeMap::new());
+        let result = op(self);
+        let writes = self.pending.take().unwrap_or_default();
+        let written = !writes.is_empty();
+        let result = match result {
+            std::result::Result::Ok(value) => self.commit(writes).map(|()| value),
+            Err(err) => Err(err),
+        };
+        if result.is_err() {
+            self.rollback(written)?;
+        }
+        result
+    }
+    /// Commit the writes of the current transaction early when the journal is nearly full.
+    ///
+    /// Long operations call it between two steps where the container is consistent, leaking
+    /// sectors at worst, so that a crash never leaves it half updated.
+    fn checkpoint(&mut self) -> Result<()> {
+        if !self.checkpoint_due() {
+            return Ok(());
+        }
+        let writes = self.pending.replace(BTreeMap::new()).unwrap_or_default();
+        self.commit(writes)
+    }
+    fn checkpoint_due(&self) -> bool {
+        match (&self.journal, &self.pending) {
+            (Some(journal), Some(pending)) => {
+                pending.len() + self.dirty.len() + CHECKPOINT_MARGIN >= journal.capacity()
+            }
+            _ => false,
+        }
+    }
+    /// Add committed writes to those waiting for the write-back, and write them all back if
+    /// the next transaction could not join them in the journal.
+    ///
+    /// A transaction larger than the journal fails, as it could not be written back at once.
+    fn commit(&mut self, writes: BTreeMap<u64, Vec<u8>>) -> Result<()> {
+        if let Some(journal) = &self.journal {
+            if writes.len() > journal.capacity() {
+                bail!(
+                    "A transaction of {} sectors does not fit in the journal of {} sectors.",
+                    writes.len(),
+                    journal.capacity()
+                );
+            }
+            let joined = writes
+                .keys()
+                .filter(|target| !self.dirty.contains_key(target))
+                .count();
+            if self.dirty.len() + joined > journal.capacity() {
+                self.flush()?;
+            }
+        }
+        for target in writes.keys() {
+            self.cache.remove(*target);
+        }
+        self.dirty.extend(writes);
+        let limit = match &self.journal {
+            Some(journal) => journal.capacity().saturating_sub(CHECKPOINT_MARGIN),
+            None => self.cache.capacity(),
+        };
+        if self.dirty.len() >= limit {
+            self.flush()?;
+        }
+        Ok(())
+    }
+    /// Write back the sectors changed since the last write-back, through the journal if the
+    /// container has one.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+        let records: Vec<(u64, Vec<u8>)> = self
+            .dirty
+            .iter()
+            .map(|(target, image)| (*target, image.clone()))
+            .collect();
+        match &self.journal {
+            Some(journal) => {
+                journal.commit(&mut self.file, &records)?;
+                Self::apply(&mut self.file, &self.superblock, &records)?;
+                self.file.sync_data()?;
+                journal.clear(&mut self.file)?;
+            }
+            None => {
+                Self::apply(&mut self.file, &self.superblock, &records)?;
+                self.file.sync_data()?;
+            }
+        }
+        self.dirty.clear();
+        Ok(())
+    }
+    /// Limit the memory used to cache sectors to about `size` bytes.
+    pub fn set_cache_size(&mut self, size: usize) {
+        self.cache.resize(size, self.sector_size());
+    }
+    /// Reload the state kept in memory after a failed transaction. The superblock, the inode
+    /// table, the refcounts and the free list only change along with writes, so they are
+    /// kept if nothing was written.
+    fn rollback(&mut self, written: bool) -> Result<()> {
+        self.metadata = match self.dirty.get(&METADATA_TARGET) {
+            Some(image) => encoding::decode(image)?,
+            None => Self::read_metadata(&mut self.file, &self.superblock)?,
+        };
+        if written {
+            let superblock = match self.dirty.get(&SUPERBLOCK_TARGET) {
+                Some(image) => Some(encoding::decode(image)?),
+                None => Self::read_superblock(&mut self.file)?,
+            };
+            if let Some(superblock) = superblock {
+                self.superblock = superblock;
+            }
+            self.inode_table.clear();
+            self.inodes.clear();
+            self.count_free_sectors()?;
+            self.load_inode_table()?;
+            self.load_refcounts()?;
+        }
+        Ok(())
     }
     fn append_empty_sector(&mut self) -> Result<u64> {
+        if self.available_sectors() == 0 {
+            bail!(FsError::NoSpace);
+        }
         let mut empty_sector = Empty::default();
         //Set previous if any
         if let Some(last_sector) = self.metadata.last_empty_sector {
             empty_sector.set_previous(last_sector);
         }
-        //Place the cursor
-        let offset =
-            size_of::<Metadata>() as u64 + self.metadata.sector_count * size_of::<Sector>() as u64; //TODO maybe add a -1
-        let offset = SeekFrom::Start(offset);
-        self.file.seek(offset)?;
-        //Write the empty sector
-        let mut buff = Vec::with_capacity(size_of::<Sector>());
-        bincode::serialize_into(&mut buff, &Sector::Empty(empty_sector))?;
-        buff.resize(size_of::<Sector>(), 0);
-        self.file.write_all(&buff)?;
-        self.file.flush()?;
+        //Write the empty sector at the end of the container
+        let new_sector_id = self.metadata.sector_count;
+        self.metadata.sector_count += 1;
+        self.write_sector(new_sector_id, &Sector::Empty(empty_sector))?;
 
         //Modify the previous last_empty_sector if any
         if let Some(last_empty_sector_id) = self.metadata.last_empty_sector {
@@ -152,20 +689,70 @@ impl Container {
             else {
                 bail!("Last empty sector {last_empty_sector_id} is not empty.");
             };
-            last_empty_sector.set_next(self.metadata.sector_count);
+            last_empty_sector.set_next(new_sector_id);
             self.write_sector(last_empty_sector_id, &Sector::Empty(last_empty_sector))?;
         }
 
         //If this one is the first empty sector update the list
         if self.metadata.first_empty_sector.is_none() {
-            self.metadata.first_empty_sector = Some(self.metadata.sector_count);
+            self.metadata.first_empty_sector = Some(new_sector_id);
         }
-        self.metadata.last_empty_sector = Some(self.metadata.sector_count);
-        self.metadata.sector_count += 1;
+        self.metadata.last_empty_sector = Some(new_sector_id);
+        self.free_sectors += 1;
         self.write_metadata()?;
         Ok(1)
     }
+    /// Sectors that can still be allocated, from the free list or by growing the container.
+    fn available_sectors(&self) -> u64 {
+        match self.metadata.max_sectors {
+            Some(max_sectors) => {
+                let max_sectors = u64::from(max_sectors);
+                let sector_count = self.metadata.sector_count;
+                let mut growth = max_sectors.saturating_sub(sector_count);
+                if let Some(group_size) = self.superblock.group_size() {
+                    //New groups start with their bitmap
+                    let bitmaps =
+                        max_sectors.div_ceil(group_size) - sector_count.div_ceil(group_size);
+                    growth = growth.saturating_sub(bitmaps);
+                }
+                self.free_sectors + growth
+            }
+            None => u64::MAX,
+        }
+    }
+    /// Fail with ENOSPC unless `count` sectors can be allocated. Operations call it before
+    /// changing anything so that running out of space never leaves a half-built chain.
+    fn reserve_sectors(&self, count: u64) -> Result<()> {
+        if count > self.available_sectors() {
+            bail!(FsError::NoSpace);
+        }
+        Ok(())
+    }
+    /// Limit the container to `max_size` bytes, or lift the limit with None.
+    pub fn set_max_size(&mut self, max_size: Option<u64>) -> Result<()> {
+        let max_sectors = match max_size {
+            Some(max_size) => {
+                let max_sectors = max_size / self.sector_size() as u64;
+                if max_sectors < self.metadata.sector_count {
+                    bail!(
+                        "The container already uses {} bytes.",
+                        self.metadata.sector_count * self.sector_size() as u64
+                    );
+                }
+                let Some(max_sectors) = u32::try_from(max_sectors).ok() else {
+                    bail!("The maximum size is limited to {} sectors.", u32::MAX);
+                };
+                Some(max_sectors)
+            }
+            None => None,
+        };
+        self.metadata.max_sectors = max_sectors;
+        self.write_metadata()
+    }
     fn get_empty_sector(&mut self) -> Result<u64> {
+        if let Some(group_size) = self.superblock.group_size() {
+            return self.take_free_sector(group_size, 0);
+        }
         if let Some(empty_sector_id) = self.metadata.first_empty_sector {
             let Sector::Empty(empty_sector_data) = self.read_sector(empty_sector_id)? else {
                 bail!("Empty sector is not true empty sector");
@@ -176,6 +763,7 @@ impl Container {
             } else {
                 self.metadata.first_empty_sector = empty_sector_data.next();
             }
+            self.free_sectors = self.free_sectors.saturating_sub(1);
             self.write_metadata()?;
             return Ok(empty_sector_id);
         }
@@ -190,12 +778,74 @@ impl Container {
             } else {
                 self.metadata.first_empty_sector = empty_sector_data.next();
             }
+            self.free_sectors = self.free_sectors.saturating_sub(1);
             self.write_metadata()?;
             Ok(empty_sector_id)
         } else {
             bail!("No empty sector available");
         }
     }
+    /// Allocate a sector, preferably the first free one from `goal` on. With the bitmap,
+    /// sectors allocated one after the other with the previous one as goal are contiguous.
+    fn get_empty_sector_after(&mut self, goal: u64) -> Result<u64> {
+        match self.superblock.group_size() {
+            Some(group_size) => self.take_free_sector(group_size, goal),
+            None => self.get_empty_sector(),
+        }
+    }
+    /// Mark the first free sector from `goal` on as used, wrapping around to the beginning,
+    /// or grow the container if none is free.
+    fn take_free_sector(&mut self, group_size: u64, goal: u64) -> Result<u64> {
+        let sector_count = self.metadata.sector_count;
+        let goal = if goal < sector_count { goal } else { 0 };
+        let goal_group = (goal / group_size) as usize;
+        let groups = std::iter::once((goal_group, goal % group_size))
+            .chain((goal_group + 1..self.group_free.len()).map(|group| (group, 0)))
+            .chain((0..=goal_group).map(|group| (group, 0)));
+        for (group, start) in groups {
+            if self.group_free.get(group).copied().unwrap_or(0) == 0 {
+                continue;
+            }
+            let group_start = group as u64 * group_size;
+            let end = (sector_count - group_start).min(group_size);
+            let Sector::Bitmap(mut bitmap) = self.read_sector(group_start)? else {
+                bail!("Sector {group_start} is not a bitmap.");
+            };
+            let Some(idx) = bitmap.first_free(start as usize..end as usize) else {
+                continue;
+            };
+            bitmap.set_used(idx, true);
+            self.write_sector(group_start, &Sector::Bitmap(bitmap))?;
+            self.group_free[group] -= 1;
+            self.free_sectors = self.free_sectors.saturating_sub(1);
+            return Ok(group_start + idx as u64);
+        }
+        self.grow_sector(group_size)
+    }
+    /// Add a used sector at the end of the container, after the bitmap of a new group if it
+    /// starts one.
+    fn grow_sector(&mut self, group_size: u64) -> Result<u64> {
+        if self.available_sectors() == 0 {
+            bail!(FsError::NoSpace);
+        }
+        let mut sector_id = self.metadata.sector_count;
+        let group_start = sector_id / group_size * group_size;
+        let mut bitmap = if sector_id == group_start {
+            sector_id += 1;
+            self.group_free.push(0);
+            Self::group_bitmap(&self.superblock)
+        } else {
+            let Sector::Bitmap(bitmap) = self.read_sector(group_start)? else {
+                bail!("Sector {group_start} is not a bitmap.");
+            };
+            bitmap
+        };
+        bitmap.set_used((sector_id - group_start) as usize, true);
+        self.metadata.sector_count = sector_id + 1;
+        self.write_sector(group_start, &Sector::Bitmap(bitmap))?;
+        self.write_metadata()?;
+        Ok(sector_id)
+    }
     fn get_empty_entry(&mut self, dir_metadata: &FileMetadata) -> Result<Option<(u64, usize)>> {
         let mut next_sector = dir_metadata.first_sector();
 
@@ -219,129 +869,46 @@ impl Container {
         }
         Ok(None)
     }
-    fn find_ino_sector(&mut self, ino: u64) -> Result<(u64, Sector)> {
-        for i in 0..self.metadata.sector_count {
-            let sector = self.read_sector(i)?;
-            if let Sector::DirMetadata(ref dir_metadata) = sector {
-                if dir_metadata.ino() == ino {
-                    return Ok((i, sector));
-                }
-            } else if let Sector::FileMetadata(ref file_metadata) = sector {
-                if file_metadata.ino() == ino {
-                    return Ok((i, sector));
-                }
-            }
-        }
-        bail!("Inode {ino} not found");
-    }
-    fn new_inode(&mut self) -> Result<u64> {
-        if self.metadata.next_ino == u64::max_value() {
-            bail!("No more inode");
-        }
-        self.metadata.next_ino += 1;
-        Ok(self.metadata.next_ino - 1)
-    }
-    fn free_sector(&mut self, sector_id: u64) -> Result<()> {
-        let mut empty_sector = Empty::default();
-        if let Sector::Empty(_) = self.read_sector(sector_id)? {
-            //Sector is already empty
-            return Ok(());
-        }
-        if let Some(first_empty_sector_id) = self.metadata.first_empty_sector {
-            let mut base_first_empty_sector = self.read_sector(first_empty_sector_id)?;
-            let Sector::Empty(first_empty_sector) = &mut base_first_empty_sector else {
-                bail!("First empty sector ({first_empty_sector_id}) is not an empty sector.");
-            };
-            first_empty_sector.set_previous(sector_id);
-            self.write_sector(first_empty_sector_id, &base_first_empty_sector)?;
-            empty_sector.set_next(first_empty_sector_id);
-        }
-        self.write_sector(sector_id, &Sector::Empty(empty_sector))?;
-        self.metadata.first_empty_sector = Some(sector_id);
-        if self.metadata.last_empty_sector.is_none() {
-            self.metadata.last_empty_sector = Some(sector_id);
-        }
-        self.write_metadata()?;
-        Ok(())
-    }
-    fn delete_file(&mut self, ino: u64) -> Result<()> {
-        let (metadata_sector_id, metadata_sector) = self.find_ino_sector(ino)?;
-        let Sector::FileMetadata(file_metadata) = &metadata_sector else {
-            bail!("Inode {ino} is not a file.");
-        };
-        let mut current_sector_id = file_metadata.first_sector();
-        self.free_sector(metadata_sector_id)?;
-
-        while let Some(sector_id) = current_sector_id {
-            let Sector::FileData(file_data) = self.read_sector(sector_id)? else {
-                bail!("Sector is not of type FileData.");
-            };
-            self.free_sector(sector_id)?;
-            current_sector_id = file_data.next();
-        }
-
-        Ok(())
-    }
-    pub fn opendir(&mut self, ino: u64) -> Result<u64> {
-        let (_sector_id, _sector) = self.find_ino_sector(ino)?;
-        Ok(1)
-    }
-    pub fn readdir(
+    fn find_entry(
         &mut self,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-    ) -> Result<Vec<(u64, FileType, String)>> {
-        let mut index = 0;
-
-        let (_sector_id, sector) = self.find_ino_sector(ino)?;
-        let Sector::DirMetadata(dir_metadata) = sector else {
-            bail!("Inode {ino} is not a directory.");
-        };
+        dir_metadata: &FileMetadata,
+        name: &OsStr,
+    ) -> Result<Option<(u64, usize, DirEntry)>> {
         let mut next_sector = dir_metadata.first_sector();
-        let mut entry_list = Vec::new();
-
-        entry_list.push((ino, FileType::Directory, ".".to_string()));
-        entry_list.push((ino, FileType::Directory, "..".to_string()));
 
         //Iterate through all sector of directory
         while let Some(sector_id) = next_sector {
             let base_sector = self.read_sector(sector_id)?;
-            let Sector::DirData(sector) = base_sector else {
-                bail!("Directory sector is not DirData (inode {ino}, sector {sector_id})");
+            let Sector::DirData(sector) = &base_sector else {
+                bail!(
+                    "Directory sector is not DirData (inode {}, sector {sector_id})",
+                    dir_metadata.ino()
+                );
             };
-            for entry in sector.entries() {
-                if entry.empty {
-                    continue;
-                }
-                if index >= offset {
-                    let filetype = match entry.filetype {
-                        sector::FileType::Regular => FileType::RegularFile,
-                        sector::FileType::Directory => FileType::Directory,
-                    };
-                    entry_list.push((entry.ino, filetype, entry.name.to_string()));
+            //Look for used entry with the right name
+            for (i, entry) in sector.entries().iter().enumerate() {
+                if !entry.empty && OsString::from(entry.name.to_string()) == *name {
+                    return Ok(Some((sector_id, i, entry.clone())));
                 }
-                index += 1;
             }
             next_sector = sector.next_sector();
         }
-
-        Ok(entry_list)
+        Ok(None)
     }
-    pub fn create(&mut self, parent: u64, name: &OsStr, filetype: sector::FileType) -> Result<u64> {
+    fn entry_name(&self, name: &OsStr) -> Result<String> {
         let Some(name) = name.to_str() else {
-            bail!("Invalid name");
+            bail!(FsError::InvalidArgument);
         };
-        if name.len() >= FILE_NAME_SIZE {
-            bail!("Name is too long (max {FILE_NAME_SIZE})");
+        if name.len() >= self.superblock.geometry().file_name_size {
+            bail!(FsError::NameTooLong);
         }
+        Ok(name.to_string())
+    }
+    fn insert_entry(&mut self, parent: u64, new_entry: DirEntry) -> Result<()> {
         let (metadata_sector_id, mut metadata_sector) = self.find_ino_sector(parent)?;
         let Sector::DirMetadata(dir_metadata) = &mut metadata_sector else {
-            bail!("Inode {parent} is not a directory.");
+            bail!(FsError::NotADirectory);
         };
-        let new_inode = self.new_inode()?;
-        let empty_sector_id_file_metadata = self.get_empty_sector()?;
-        //TODO check if name already exist
         let findings = self.get_empty_entry(dir_metadata)?;
 
         let (sector_id, idx) = if let Some(a) = findings {
@@ -350,9 +917,15 @@ impl Container {
             //append_empty_sector
             let empty_sector_id = self.get_empty_sector()?;
             //set the sector data
-            let mut sector = DirData::new();
-            if let Some(previous_id) = dir_metadata.first_sector() {
-                sector.set_previous(previous_id);
+            let mut sector = DirData::new(self.superblock.geometry().dir_sector_size);
+            if let Some(next_id) = dir_metadata.first_sector() {
+                sector.set_next(next_id);
+                let mut base_next_sector = self.read_sector(next_id)?;
+                let Sector::DirData(next_sector) = &mut base_next_sector else {
+                    bail!("Directory sector is not DirData (inode {parent}, sector {next_id})");
+                };
+                next_sector.set_previous(empty_sector_id);
+                self.write_sector(next_id, &base_next_sector)?;
             }
             self.write_sector(empty_sector_id, &Sector::DirData(sector))?;
             //modify metadata to emplace it at the front of the sector list
@@ -376,125 +949,1241 @@ impl Container {
         };
 
         //Write the entry
-        entry.ino = new_inode;
-        entry.empty = false;
-        let std::result::Result::Ok(heapless_name) =
-            heapless::String::<FILE_NAME_SIZE>::from_str(name)
-        else {
-            bail!("Error heapless::String::from_str for the filename.");
-        };
-        entry.name = heapless_name;
-        entry.filetype = filetype;
+        *entry = new_entry;
 
         //Write to container
         self.write_sector(sector_id, &base_sector)?;
-
-        // write metadata of new file
+        Ok(())
+    }
+    fn set_entry_target(
+        &mut self,
+        sector_id: u64,
+        idx: usize,
+        ino: u64,
+        filetype: sector::FileType,
+    ) -> Result<()> {
+        let mut base_sector = self.read_sector(sector_id)?;
+        let Sector::DirData(sector) = &mut base_sector else {
+            bail!("Directory sector {sector_id} is not DirData");
+        };
+        let Some(entry) = sector.entries_mut().get_mut(idx) else {
+            bail!("Error when accessing directory entry {idx}, sector={sector_id}");
+        };
+        entry.ino = ino;
+        entry.filetype = filetype;
+        self.write_sector(sector_id, &base_sector)?;
+        Ok(())
+    }
+    fn set_parent(&mut self, ino: u64, parent: u64) -> Result<()> {
+        let (sector_id, mut sector) = self.find_ino_sector(ino)?;
+        let Some(metadata) = sector.metadata_mut() else {
+            bail!("Sector is not a metadata sector.");
+        };
+        metadata.set_parent(parent);
+        self.write_sector(sector_id, &sector)?;
+        Ok(())
+    }
+    /// Update the ctime of `ino`, and its mtime too if its content was `modified`.
+    fn touch(&mut self, ino: u64, modified: bool) -> Result<()> {
+        let (sector_id, mut sector) = self.find_ino_sector(ino)?;
+        let Some(metadata) = sector.metadata_mut() else {
+            bail!("Sector is not a metadata sector.");
+        };
+        let now = SystemTime::now();
+        if modified {
+            metadata.set_mtime(now);
+        }
+        metadata.set_ctime(now);
+        self.write_sector(sector_id, &sector)?;
+        Ok(())
+    }
+    /// Check whether `ino` is `ancestor` or lives somewhere below it.
+    fn is_in_subtree(&mut self, ancestor: u64, ino: u64) -> Result<bool> {
+        let mut current = Some(ino);
+        while let Some(current_ino) = current {
+            if current_ino == ancestor {
+                return Ok(true);
+            }
+            let (_sector_id, sector) = self.find_ino_sector(current_ino)?;
+            let Some(metadata) = sector.metadata() else {
+                bail!("Sector is not a metadata sector.");
+            };
+            current = metadata.parent();
+        }
+        Ok(false)
+    }
+    fn clear_entry(&mut self, sector_id: u64, idx: usize) -> Result<()> {
+        let mut base_sector = self.read_sector(sector_id)?;
+        let Sector::DirData(sector) = &mut base_sector else {
+            bail!("Directory sector {sector_id} is not DirData");
+        };
+        let Some(entry) = sector.entries_mut().get_mut(idx) else {
+            bail!("Error when accessing directory entry {idx}, sector={sector_id}");
+        };
+        *entry = DirEntry::empty();
+        self.write_sector(sector_id, &base_sector)?;
+        Ok(())
+    }
+    fn is_dir_empty(&mut self, dir_metadata: &FileMetadata) -> Result<bool> {
+        let mut next_sector = dir_metadata.first_sector();
+
+        //Iterate through all sector of directory
+        while let Some(sector_id) = next_sector {
+            let base_sector = self.read_sector(sector_id)?;
+            let Sector::DirData(sector) = &base_sector else {
+                bail!(
+                    "Directory sector is not DirData (inode {}, sector {sector_id})",
+                    dir_metadata.ino()
+                );
+            };
+            if sector.entries().iter().any(|entry| !entry.empty) {
+                return Ok(false);
+            }
+            next_sector = sector.next_sector();
+        }
+        Ok(true)
+    }
+    fn dir_entries(&mut self, dir_metadata: &FileMetadata) -> Result<Vec<DirEntry>> {
+        let mut next_sector = dir_metadata.first_sector();
+        let mut entries = Vec::new();
+
+        //Iterate through all sector of directory
+        while let Some(sector_id) = next_sector {
+            let base_sector = self.read_sector(sector_id)?;
+            let Sector::DirData(sector) = base_sector else {
+                bail!(
+                    "Directory sector is not DirData (inode {}, sector {sector_id})",
+                    dir_metadata.ino()
+                );
+            };
+            entries.extend(
+                sector
+                    .entries()
+                    .iter()
+                    .filter(|entry| !entry.empty)
+                    .cloned(),
+            );
+            next_sector = sector.next_sector();
+        }
+        Ok(entries)
+    }
+    /// Link count of an inode. Inodes written before it was stored have a single name,
+    /// and directories get theirs counted from their entries.
+    fn nlink(&mut self, sector: &Sector) -> Result<u32> {
+        let Some(metadata) = sector.metadata() else {
+            bail!("Sector is not a metadata sector.");
+        };
+        if let Some(nlink) = metadata.nlink() {
+            return Ok(nlink);
+        }
+        let Sector::DirMetadata(dir_metadata) = sector else {
+            return Ok(1);
+        };
+        let subdirectories = self
+            .dir_entries(dir_metadata)?
+            .iter()
+            .filter(|entry| entry.filetype == sector::FileType::Directory)
+            .count();
+        Ok(2 + u32::try_from(subdirectories)?)
+    }
+    /// Add `delta` to the link count of `ino` and return the new count. For directories it
+    /// must be called before their entries change, in case the count is not stored yet.
+    fn add_links(&mut self, ino: u64, delta: i32) -> Result<u32> {
+        let (sector_id, mut sector) = self.find_ino_sector(ino)?;
+        let Some(nlink) = self.nlink(&sector)?.checked_add_signed(delta) else {
+            bail!(FsError::TooManyLinks);
+        };
+        let Some(metadata) = sector.metadata_mut() else {
+            bail!("Sector is not a metadata sector.");
+        };
+        metadata.set_nlink(nlink);
+        metadata.set_ctime(SystemTime::now());
+        self.write_sector(sector_id, &sector)?;
+        Ok(nlink)
+    }
+    /// Remove the name of `ino` that lived in `dir`. The file is deleted with its last name,
+    /// otherwise its parent moves to a directory still holding one of its names.
+    fn drop_link(&mut self, ino: u64, dir: u64) -> Result<()> {
+        if self.add_links(ino, -1)? == 0 {
+            return self.delete_file(ino);
+        }
+        let (_sector_id, sector) = self.find_ino_sector(ino)?;
+        let Some(metadata) = sector.metadata() else {
+            bail!("Sector is not a metadata sector.");
+        };
+        if metadata.parent() != Some(dir) {
+            return Ok(());
+        }
+        let (_sector_id, dir_sector) = self.find_ino_sector(dir)?;
+        let Sector::DirMetadata(dir_metadata) = &dir_sector else {
+            bail!(FsError::NotADirectory);
+        };
+        if self
+            .dir_entries(dir_metadata)?
+            .iter()
+            .any(|entry| entry.ino == ino)
+        {
+            return Ok(());
+        }
+        if let Some(parent) = self.find_dir_containing(ino)? {
+            self.set_parent(ino, parent)?;
+        }
+        Ok(())
+    }
+    /// Walk the whole tree looking for a directory holding a name of `ino`.
+    fn find_dir_containing(&mut self, ino: u64) -> Result<Option<u64>> {
+        let mut dirs = vec![FUSE_ROOT_ID];
+        while let Some(dir) = dirs.pop() {
+            let (_sector_id, sector) = self.find_ino_sector(dir)?;
+            let Sector::DirMetadata(dir_metadata) = &sector else {
+                bail!(FsError::NotADirectory);
+            };
+            for entry in self.dir_entries(dir_metadata)? {
+                if entry.ino == ino {
+                    return Ok(Some(dir));
+                }
+                if entry.filetype == sector::FileType::Directory {
+                    dirs.push(entry.ino);
+                }
+            }
+        }
+        Ok(None)
+    }
+    fn find_ino_sector(&mut self, ino: u64) -> Result<(u64, Sector)> {
+        let sector_id = if ino == FUSE_ROOT_ID {
+            Some(self.metadata.root_dir_sector)
+        } else {
+            self.inodes.get(ino as usize).copied().flatten()
+        };
+        let Some(sector_id) = sector_id else {
+            bail!("Inode {ino} not found");
+        };
+        let sector = self.read_sector(sector_id)?;
+        match sector.metadata() {
+            Some(metadata) if metadata.ino() == ino => Ok((sector_id, sector)),
+            _ => {
+                bail!("Inode table maps inode {ino} to sector {sector_id} which does not hold it.")
+            }
+        }
+    }
+    fn load_inode_table(&mut self) -> Result<()> {
+        let Some(first_sector) = self.metadata.inode_table else {
+            return self.build_inode_table();
+        };
+        let mut next_sector = Some(first_sector);
+        while let Some(sector_id) = next_sector {
+            let Sector::InodeTable(table) = self.read_sector(sector_id)? else {
+                bail!("Inode table sector {sector_id} is not InodeTable");
+            };
+            self.inode_table.push(sector_id);
+            self.inodes.extend(table.sectors().iter().copied());
+            next_sector = table.next_sector();
+        }
+        Ok(())
+    }
+    /// Build the inode table of a container created before it existed by scanning every sector once.
+    fn build_inode_table(&mut self) -> Result<()> {
+        let mut found = Vec::new();
+        for i in 0..self.metadata.sector_count {
+            if let Some(metadata) = self.read_sector(i)?.metadata() {
+                if metadata.ino() != FUSE_ROOT_ID {
+                    found.push((metadata.ino(), i));
+                }
+            }
+        }
+        for (ino, sector_id) in found {
+            self.set_inode_sector(ino, Some(sector_id))?;
+        }
+        Ok(())
+    }
+    fn append_inode_table_sector(&mut self) -> Result<()> {
+        let sector_id = self.get_empty_sector()?;
+        self.write_sector(sector_id, &Sector::InodeTable(InodeTable::new()))?;
+        if let Some(&last_sector_id) = self.inode_table.last() {
+            let mut base_last_sector = self.read_sector(last_sector_id)?;
+            let Sector::InodeTable(last_sector) = &mut base_last_sector else {
+                bail!("Inode table sector {last_sector_id} is not InodeTable");
+            };
+            last_sector.set_next(sector_id);
+            self.write_sector(last_sector_id, &base_last_sector)?;
+        } else {
+            self.metadata.inode_table = Some(sector_id);
+            self.write_metadata()?;
+        }
+        self.inode_table.push(sector_id);
+        self.inodes
+            .resize(self.inode_table.len() * INODE_TABLE_SIZE, None);
+        Ok(())
+    }
+    fn set_inode_sector(&mut self, ino: u64, sector_id: Option<u64>) -> Result<()> {
+        let ino_index = ino as usize;
+        let table_index = ino_index / INODE_TABLE_SIZE;
+        while self.inode_table.len() <= table_index {
+            self.append_inode_table_sector()?;
+        }
+        let table_sector_id = self.inode_table[table_index];
+        let mut base_sector = self.read_sector(table_sector_id)?;
+        let Sector::InodeTable(table) = &mut base_sector else {
+            bail!("Inode table sector {table_sector_id} is not InodeTable");
+        };
+        table.set_sector(ino_index % INODE_TABLE_SIZE, sector_id);
+        self.write_sector(table_sector_id, &base_sector)?;
+        self.inodes[ino_index] = sector_id;
+        Ok(())
+    }
+    fn new_inode(&mut self) -> Result<u64> {
+        if self.metadata.next_ino == u64::MAX {
+            bail!("No more inode");
+        }
+        self.metadata.next_ino += 1;
+        self.write_metadata()?;
+        Ok(self.metadata.next_ino - 1)
+    }
+    fn free_sector(&mut self, sector_id: u64) -> Result<()> {
+        if let Some(group_size) = self.superblock.group_size() {
+            let group_start = sector_id / group_size * group_size;
+            let Sector::Bitmap(mut bitmap) = self.read_sector(group_start)? else {
+                bail!("Sector {group_start} is not a bitmap.");
+            };
+            let idx = (sector_id - group_start) as usize;
+            if idx == 0 {
+                bail!("Sector {sector_id} is a bitmap.");
+            }
+            if !bitmap.is_used(idx) {
+                //Sector is already free
+                return Ok(());
+            }
+            bitmap.set_used(idx, false);
+            self.write_sector(group_start, &Sector::Bitmap(bitmap))?;
+            //Stale metadata must not be found by fsck
+            self.write_sector(sector_id, &Sector::Empty(Empty::default()))?;
+            self.group_free[(sector_id / group_size) as usize] += 1;
+            self.free_sectors += 1;
+            return Ok(());
+        }
+        let mut empty_sector = Empty::default();
+        if let Sector::Empty(_) = self.read_sector(sector_id)? {
+            //Sector is already empty
+            return Ok(());
+        }
+        if let Some(first_empty_sector_id) = self.metadata.first_empty_sector {
+            let mut base_first_empty_sector = self.read_sector(first_empty_sector_id)?;
+            let Sector::Empty(first_empty_sector) = &mut base_first_empty_sector else {
+                bail!("First empty sector ({first_empty_sector_id}) is not an empty sector.");
+            };
+            first_empty_sector.set_previous(sector_id);
+            self.write_sector(first_empty_sector_id, &base_first_empty_sector)?;
+            empty_sector.set_next(first_empty_sector_id);
+        }
+        self.write_sector(sector_id, &Sector::Empty(empty_sector))?;
+        self.metadata.first_empty_sector = Some(sector_id);
+        if self.metadata.last_empty_sector.is_none() {
+            self.metadata.last_empty_sector = Some(sector_id);
+        }
+        self.free_sectors += 1;
+        self.write_metadata()?;
+        Ok(())
+    }
+    fn count_free_sectors(&mut self) -> Result<()> {
+        self.free_sectors = 0;
+        if let Some(group_size) = self.superblock.group_size() {
+            self.group_free.clear();
+            let sector_count = self.metadata.sector_count;
+            for group_start in (0..sector_count).step_by(group_size as usize) {
+                let Sector::Bitmap(bitmap) = self.read_sector(group_start)? else {
+                    bail!("Sector {group_start} is not a bitmap.");
+                };
+                let free = bitmap.free_count((sector_count - group_start).min(group_size) as usize);
+                self.group_free.push(free);
+                self.free_sectors += free;
+            }
+            return Ok(());
+        }
+        let mut next_sector = self.metadata.first_empty_sector;
+        while let Some(sector_id) = next_sector {
+            let Sector::Empty(empty_sector) = self.read_sector(sector_id)? else {
+                bail!("Sector {sector_id} of the free list is not empty.");
+            };
+            self.free_sectors += 1;
+            if Some(sector_id) == self.metadata.last_empty_sector {
+                break;
+            }
+            next_sector = empty_sector.next();
+        }
+        Ok(())
+    }
+    /// Replace the free list with `free`, linked in this order.
+    fn link_free_list(&mut self, free: &[u64]) -> Result<()> {
+        for (idx, &sector_id) in free.iter().enumerate() {
+            let mut empty = Empty::default();
+            if let Some(&previous_id) = idx.checked_sub(1).and_then(|idx| free.get(idx)) {
+                empty.set_previous(previous_id);
+            }
+            if let Some(&next_id) = free.get(idx + 1) {
+                empty.set_next(next_id);
+            }
+            self.write_sector(sector_id, &Sector::Empty(empty))?;
+        }
+        self.metadata.first_empty_sector = free.first().copied();
+        self.metadata.last_empty_sector = free.last().copied();
+        self.free_sectors = free.len() as u64;
+        self.write_metadata()
+    }
+    /// Take the free sector `sector_id` out of the free list, wherever it is.
+    fn unlink_free_sector(&mut self, sector_id: u64) -> Result<()> {
+        let Sector::Empty(empty_sector) = self.read_sector(sector_id)? else {
+            bail!("Sector {sector_id} of the free list is not empty.");
+        };
+        //The head keeps a stale previous sector once the sector before it is allocated
+        let previous = if self.metadata.first_empty_sector == Some(sector_id) {
+            None
+        } else {
+            empty_sector.previous()
+        };
+        let next = if self.metadata.last_empty_sector == Some(sector_id) {
+            None
+        } else {
+            empty_sector.next()
+        };
+        match previous {
+            Some(previous_id) => {
+                let Sector::Empty(previous_sector) = self.read_sector(previous_id)? else {
+                    bail!("Sector {previous_id} of the free list is not empty.");
+                };
+                let mut relinked = Empty::default();
+                if let Some(before_id) = previous_sector.previous() {
+                    relinked.set_previous(before_id);
+                }
+                if let Some(next_id) = next {
+                    relinked.set_next(next_id);
+                }
+                self.write_sector(previous_id, &Sector::Empty(relinked))?;
+            }
+            None => self.metadata.first_empty_sector = next,
+        }
+        match next {
+            Some(next_id) => {
+                let Sector::Empty(next_sector) = self.read_sector(next_id)? else {
+                    bail!("Sector {next_id} of the free list is not empty.");
+                };
+                let mut relinked = Empty::default();
+                if let Some(previous_id) = previous {
+                    relinked.set_previous(previous_id);
+                }
+                if let Some(after_id) = next_sector.next() {
+                    relinked.set_next(after_id);
+                }
+                self.write_sector(next_id, &Sector::Empty(relinked))?;
+            }
+            None => self.metadata.last_empty_sector = previous,
+        }
+        self.free_sectors = self.free_sectors.saturating_sub(1);
+        self.write_metadata()
+    }
+    /// Free the data, entries and extended attributes of `ino` ahead of the removal of its
+    /// last name. The checkpoints along the way leave it whole, only emptier, so that the
+    /// inode and its entry go last in a step that fits in the journal.
+    fn empty_inode(&mut self, ino: u64) -> Result<()> {
+        let (metadata_sector_id, mut metadata_sector) = self.find_ino_sector(ino)?;
+        self.migrate_to_block_map(metadata_sector_id, &mut metadata_sector)?;
+        match &mut metadata_sector {
+            Sector::FileMetadata(file_metadata) => {
+                file_metadata.set_length_byte(0);
+                self.free_blocks(metadata_sector_id, file_metadata, 0..u64::MAX)?;
+                self.write_sector(metadata_sector_id, &metadata_sector)?;
+            }
+            Sector::DirMetadata(_) => self.free_chain_from_end(metadata_sector_id, false)?,
+            //The target of a symlink takes a few sectors at most, freed with the inode
+            _ => {}
+        }
+        self.free_chain_from_end(metadata_sector_id, true)
+    }
+    /// Free the entries of a directory, or the extended attributes of an inode with `xattrs`,
+    /// from the end of the chain, which is cut before every checkpoint.
+    fn free_chain_from_end(&mut self, metadata_sector_id: u64, xattrs: bool) -> Result<()> {
+        let mut metadata_sector = self.read_sector(metadata_sector_id)?;
+        let Some(metadata) = metadata_sector.metadata() else {
+            bail!("Sector {metadata_sector_id} is not a metadata sector.");
+        };
+        let mut chain = Vec::new();
+        let mut next_sector = if xattrs {
+            metadata.xattr_sector()
+        } else {
+            metadata.first_sector()
+        };
+        while let Some(sector_id) = next_sector {
+            chain.push(sector_id);
+            next_sector = match self.read_sector(sector_id)? {
+                Sector::DirData(dir_data) if !xattrs => dir_data.next_sector(),
+                Sector::XattrData(xattr_data) if xattrs => xattr_data.next_sector(),
+                _ => bail!("Sector {sector_id} does not belong to the chain it is in."),
+            };
+        }
+        for (idx, &sector_id) in chain.iter().enumerate().rev() {
+            self.free_sector(sector_id)?;
+            if idx == 0 || !self.checkpoint_due() {
+                continue;
+            }
+            let last_id = chain[idx - 1];
+            let mut last_sector = self.read_sector(last_id)?;
+            match &mut last_sector {
+                Sector::DirData(dir_data) => dir_data.clear_next(),
+                Sector::XattrData(xattr_data) => xattr_data.set_next(None),
+                _ => bail!("Sector {last_id} does not belong to the chain it is in."),
+            }
+            self.write_sector(last_id, &last_sector)?;
+            self.checkpoint()?;
+        }
+        if chain.is_empty() {
+            return Ok(());
+        }
+        let Some(metadata) = metadata_sector.metadata_mut() else {
+            bail!("Sector {metadata_sector_id} is not a metadata sector.");
+        };
+        if xattrs {
+            metadata.set_xattr_sector(None);
+        } else {
+            metadata.clear_first_sector();
+        }
+        self.write_sector(metadata_sector_id, &metadata_sector)?;
+        Ok(())
+    }
+    fn delete_file(&mut self, ino: u64) -> Result<()> {
+        self.empty_inode(ino)?;
+        let (metadata_sector_id, metadata_sector) = self.find_ino_sector(ino)?;
+        let mut current_sector_id = match &metadata_sector {
+            Sector::FileMetadata(_) => None,
+            Sector::SymlinkMetadata(symlink_metadata) => symlink_metadata.metadata().first_sector(),
+            _ => bail!("Inode {ino} is not a file."),
+        };
+        while let Some(sector_id) = current_sector_id {
+            let Sector::FileData(file_data) = self.read_sector(sector_id)? else {
+                bail!("Sector is not of type FileData.");
+            };
+            self.free_sector(sector_id)?;
+            current_sector_id = file_data.next();
+        }
+        self.set_inode_sector(ino, None)?;
+        self.free_sector(metadata_sector_id)
+    }
+    fn delete_dir(&mut self, ino: u64) -> Result<()> {
+        let (_metadata_sector_id, metadata_sector) = self.find_ino_sector(ino)?;
+        if !matches!(metadata_sector, Sector::DirMetadata(_)) {
+            bail!(FsError::NotADirectory);
+        }
+        self.empty_inode(ino)?;
+        let (metadata_sector_id, _metadata_sector) = self.find_ino_sector(ino)?;
+        self.set_inode_sector(ino, None)?;
+        self.free_sector(metadata_sector_id)
+    }
+    /// Extended attributes of `ino`, in insertion order.
+    fn xattrs(&mut self, ino: u64) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let (_sector_id, sector) = self.find_ino_sector(ino)?;
+        let Some(metadata) = sector.metadata() else {
+            bail!("Sector is not a metadata sector.");
+        };
+        let mut buff = Vec::new();
+        let mut next_sector = metadata.xattr_sector();
+        while let Some(sector_id) = next_sector {
+            let Sector::XattrData(xattr_data) = self.read_sector(sector_id)? else {
+                bail!("Sector is not of type XattrData (inode {ino}, sector {sector_id}).");
+            };
+            buff.extend_from_slice(xattr_data.data());
+            next_sector = xattr_data.next_sector();
+        }
+        if buff.is_empty() {
+            return Ok(Vec::new());
+        }
+        encoding::decode(&buff)
+    }
+    /// Replace the extended attributes of `ino`. The new chain is written before the old
+    /// one is freed.
+    fn write_xattrs(&mut self, ino: u64, xattrs: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
+        let chunk_size = self.chunk_size();
+        let buff = if xattrs.is_empty() {
+            Vec::new()
+        } else {
+            encoding::encode_unsized(&xattrs)?
+        };
+        self.reserve_sectors(buff.len().div_ceil(chunk_size) as u64)?;
+        //Build the chain from its end so each sector is written once
+        let mut next_sector = None;
+        for chunk in buff.chunks(chunk_size).rev() {
+            let sector_id = self.get_empty_sector()?;
+            let mut xattr_data = XattrData::new(chunk_size);
+            xattr_data.set_data(chunk);
+            xattr_data.set_next(next_sector);
+            self.write_sector(sector_id, &Sector::XattrData(xattr_data))?;
+            self.checkpoint()?;
+            next_sector = Some(sector_id);
+        }
+        let (sector_id, mut sector) = self.find_ino_sector(ino)?;
+        let Some(metadata) = sector.metadata_mut() else {
+            bail!("Sector is not a metadata sector.");
+        };
+        let old_chain = metadata.xattr_sector();
+        metadata.set_xattr_sector(next_sector);
+        metadata.set_ctime(SystemTime::now());
+        self.write_sector(sector_id, &sector)?;
+        self.free_xattr_chain(old_chain)
+    }
+    fn free_xattr_chain(&mut self, first_sector: Option<u64>) -> Result<()> {
+        let mut current_sector_id = first_sector;
+        while let Some(sector_id) = current_sector_id {
+            let Sector::XattrData(xattr_data) = self.read_sector(sector_id)? else {
+                bail!("Sector is not of type XattrData.");
+            };
+            self.free_sector(sector_id)?;
+            self.checkpoint()?;
+            current_sector_id = xattr_data.next_sector();
+        }
+        Ok(())
+    }
+    fn xattr_name(name: &OsStr) -> Result<&[u8]> {
+        let name = name.as_bytes();
+        if name.is_empty() || name.len() > XATTR_NAME_MAX {
+            bail!(FsError::OutOfRange);
+        }
+        if !XATTR_NAMESPACES
+            .iter()
+            .any(|namespace| name.starts_with(namespace.as_bytes()))
+        {
+            bail!(FsError::NotSupported);
+        }
+        Ok(name)
+    }
+    pub fn opendir(&mut self, ino: u64) -> Result<u64> {
+        let (_sector_id, _sector) = self.find_ino_sector(ino)?;
+        Ok(1)
+    }
+    pub fn readdir(
+        &mut self,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+    ) -> Result<Vec<(u64, FileType, String)>> {
+        let mut index = 0;
+
+        let (_sector_id, sector) = self.find_ino_sector(ino)?;
+        let Sector::DirMetadata(dir_metadata) = sector else {
+            bail!("Inode {ino} is not a directory.");
+        };
+        let mut next_sector = dir_metadata.first_sector();
+        let mut entry_list = Vec::new();
+
+        entry_list.push((ino, FileType::Directory, ".".to_string()));
+        entry_list.push((ino, FileType::Directory, "..".to_string()));
+
+        //Iterate through all sector of directory
+        while let Some(sector_id) = next_sector {
+            let base_sector = self.read_sector(sector_id)?;
+            let Sector::DirData(sector) = base_sector else {
+                bail!("Directory sector is not DirData (inode {ino}, sector {sector_id})");
+            };
+            for entry in sector.entries() {
+                if entry.empty {
+                    continue;
+                }
+                if index >= offset {
+                    let filetype = to_fuser_filetype(entry.filetype);
+                    entry_list.push((entry.ino, filetype, entry.name.to_string()));
+                }
+                index += 1;
+            }
+            next_sector = sector.next_sector();
+        }
+
+        Ok(entry_list)
+    }
+    pub fn create(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        filetype: sector::FileType,
+        permissions: Permissions,
+    ) -> Result<u64> {
+        self.transaction(|container| container.create_inner(parent, name, filetype, permissions))
+    }
+    fn create_inner(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        filetype: sector::FileType,
+        mut permissions: Permissions,
+    ) -> Result<u64> {
+        let name = self.entry_name(name)?;
+        let (_metadata_sector_id, metadata_sector) = self.find_ino_sector(parent)?;
+        let Sector::DirMetadata(dir_metadata) = &metadata_sector else {
+            bail!("Inode {parent} is not a directory.");
+        };
+        //Files created in a setgid directory belong to its group, subdirectories stay setgid
+        let parent_permissions = dir_metadata.permissions();
+        if parent_permissions.mode & S_ISGID as u16 != 0 {
+            permissions.gid = parent_permissions.gid;
+            if filetype == sector::FileType::Directory {
+                permissions.mode |= S_ISGID as u16;
+            }
+        }
+        //The metadata, and maybe a directory sector and an inode table sector
+        self.reserve_sectors(3)?;
+        let new_inode = self.new_inode()?;
+        let empty_sector_id_file_metadata = self.get_empty_sector()?;
+        if filetype == sector::FileType::Directory {
+            self.add_links(parent, 1)?;
+        }
+        //TODO check if name already exist
+        self.insert_entry(
+            parent,
+            DirEntry {
+                ino: new_inode,
+                name,
+                filetype,
+                empty: false,
+            },
+        )?;
+
+        // write metadata of new file
         let empty_sector_id = empty_sector_id_file_metadata;
-        let sector = FileMetadata::new(new_inode, Some(parent));
+        let mut sector = FileMetadata::new(new_inode, Some(parent));
+        sector.set_permissions(permissions);
+        sector.set_created(SystemTime::now());
+        sector.set_nlink(if filetype == sector::FileType::Directory {
+            2
+        } else {
+            1
+        });
         let sector = match filetype {
             sector::FileType::Regular => Sector::FileMetadata(sector),
             sector::FileType::Directory => Sector::DirMetadata(sector),
+            sector::FileType::Symlink => Sector::SymlinkMetadata(SymlinkMetadata::new(sector)),
         };
         self.write_sector(empty_sector_id, &sector)?;
+        self.set_inode_sector(new_inode, Some(empty_sector_id))?;
+        self.touch(parent, true)?;
 
         Ok(new_inode)
     }
+    /// Capacity of the container. Every free sector can hold a new inode.
+    ///
+    /// Without a maximum size the container grows on demand and only reports its free list.
+    pub fn statfs(&self) -> StatFs {
+        //The root is not in the inode table
+        let used_inodes = self.inodes.iter().flatten().count() as u64 + 1;
+        let available = match self.metadata.max_sectors {
+            Some(_) => self.available_sectors(),
+            None => self.free_sectors,
+        };
+        StatFs {
+            block_size: self.sector_size() as u32,
+            blocks: self.metadata.sector_count + available - self.free_sectors,
+            free_blocks: available,
+            files: used_inodes + available,
+            free_files: available,
+            name_max: self.superblock.geometry().file_name_size as u32 - 1,
+        }
+    }
     pub fn getattr(&mut self, ino: u64) -> Result<Option<Attr>> {
         let (_sector_id, sector) = self.find_ino_sector(ino)?;
-        if let Sector::DirMetadata(_) = sector {
-            let attr = Attr {
-                ino,
-                filetype: FileType::Directory,
-                size: 0,
+        let (filetype, size, metadata) = match &sector {
+            Sector::DirMetadata(dir_metadata) => (FileType::Directory, 0, dir_metadata),
+            Sector::FileMetadata(file_metadata) => (
+                FileType::RegularFile,
+                file_metadata.length_byte(),
+                file_metadata,
+            ),
+            Sector::SymlinkMetadata(symlink_metadata) => (
+                FileType::Symlink,
+                symlink_metadata.metadata().length_byte(),
+                symlink_metadata.metadata(),
+            ),
+            _ => return Ok(None),
+        };
+        let permissions = metadata.permissions();
+        let (atime, mtime, ctime, crtime) = (
+            metadata.atime(),
+            metadata.mtime(),
+            metadata.ctime(),
+            metadata.crtime(),
+        );
+        let nlink = self.nlink(&sector)?;
+        //Holes take no sector
+        let blocks = (metadata.length_sector() * self.sector_size() as u64).div_ceil(512);
+        Ok(Some(Attr {
+            ino,
+            filetype,
+            size,
+            blocks,
+            nlink,
+            perm: permissions.mode,
+            uid: permissions.uid,
+            gid: permissions.gid,
+            atime,
+            mtime,
+            ctime,
+            crtime,
+        }))
+    }
+    pub fn set_permissions(
+        &mut self,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> Result<()> {
+        let (sector_id, mut sector) = self.find_ino_sector(ino)?;
+        let Some(metadata) = sector.metadata_mut() else {
+            bail!("Sector is not a metadata sector.");
+        };
+        let mut permissions = metadata.permissions();
+        if let Some(mode) = mode {
+            permissions.mode = (mode & 0o7777) as u16;
+        }
+        if let Some(uid) = uid {
+            permissions.uid = uid;
+        }
+        if let Some(gid) = gid {
+            permissions.gid = gid;
+        }
+        metadata.set_permissions(permissions);
+        metadata.set_ctime(SystemTime::now());
+        self.write_sector(sector_id, &sector)?;
+        Ok(())
+    }
+    pub fn set_times(
+        &mut self,
+        ino: u64,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<()> {
+        let (sector_id, mut sector) = self.find_ino_sector(ino)?;
+        let Some(metadata) = sector.metadata_mut() else {
+            bail!("Sector is not a metadata sector.");
+        };
+        if let Some(atime) = atime {
+            metadata.set_atime(atime);
+        }
+        if let Some(mtime) = mtime {
+            metadata.set_mtime(mtime);
+        }
+        metadata.set_ctime(SystemTime::now());
+        self.write_sector(sector_id, &sector)?;
+        Ok(())
+    }
+    pub fn lookup(&mut self, parent: u64, name: &OsStr) -> Result<Option<(u64, FileType)>> {
+        let (_metadata_sector_id, mut metadata_sector) = self.find_ino_sector(parent)?;
+        let Sector::DirMetadata(dir_metadata) = &mut metadata_sector else {
+            bail!("Inode {parent} is not a directory.");
+        };
+        let mut next_sector = dir_metadata.first_sector();
+
+        //Iterate through all sector of directory
+        while let Some(sector_id) = next_sector {
+            let base_sector = self.read_sector(sector_id)?;
+            let Sector::DirData(sector) = &base_sector else {
+                bail!("Directory sector is not DirData (inode {parent}, sector {sector_id})");
             };
-            return Ok(Some(attr));
-        } else if let Sector::FileMetadata(file_metadata) = sector {
-            let attr = Attr {
+            //Look for used entry
+            for entry in sector.entries() {
+                if !entry.empty {
+                    let entry_name = OsString::from(entry.name.to_string());
+                    if entry_name == *name {
+                        return Ok(Some((entry.ino, to_fuser_filetype(entry.filetype))));
+                    }
+                }
+            }
+            next_sector = sector.next_sector();
+        }
+        Ok(None)
+    }
+    pub fn unlink(&mut self, parent: u64, name: &OsStr) -> Result<()> {
+        self.transaction(|container| container.unlink_inner(parent, name))
+    }
+    fn unlink_inner(&mut self, parent: u64, name: &OsStr) -> Result<()> {
+        let (_metadata_sector_id, metadata_sector) = self.find_ino_sector(parent)?;
+        let Sector::DirMetadata(dir_metadata) = &metadata_sector else {
+            bail!(FsError::NotADirectory);
+        };
+        //If the name has not been found, return Ok. No problem encountered
+        let Some((sector_id, idx, entry)) = self.find_entry(dir_metadata, name)? else {
+            return Ok(());
+        };
+        if entry.filetype == sector::FileType::Directory {
+            bail!(FsError::IsADirectory);
+        }
+        let (_sector_id, sector) = self.find_ino_sector(entry.ino)?;
+        if self.nlink(&sector)? == 1 {
+            //Emptied while it still has its name, the inode and the entry going last
+            self.empty_inode(entry.ino)?;
+        }
+        self.clear_entry(sector_id, idx)?;
+        self.drop_link(entry.ino, parent)?;
+        self.touch(parent, true)?;
+        Ok(())
+    }
+    pub fn getxattr(&mut self, ino: u64, name: &OsStr) -> Result<Vec<u8>> {
+        let name = Self::xattr_name(name)?;
+        let xattrs = self.xattrs(ino)?;
+        let Some((_name, value)) = xattrs.into_iter().find(|(key, _value)| key == name) else {
+            bail!(FsError::NoData);
+        };
+        Ok(value)
+    }
+    /// Names of the extended attributes of `ino`, each followed by a NUL byte.
+    pub fn listxattr(&mut self, ino: u64) -> Result<Vec<u8>> {
+        let mut names = Vec::new();
+        for (name, _value) in self.xattrs(ino)? {
+            names.extend_from_slice(&name);
+            names.push(0);
+        }
+        Ok(names)
+    }
+    pub fn setxattr(&mut self, ino: u64, name: &OsStr, value: &[u8], flags: i32) -> Result<()> {
+        self.transaction(|container| container.setxattr_inner(ino, name, value, flags))
+    }
+    fn setxattr_inner(&mut self, ino: u64, name: &OsStr, value: &[u8], flags: i32) -> Result<()> {
+        let name = Self::xattr_name(name)?;
+        if value.len() > XATTR_SIZE_MAX {
+            bail!(FsError::TooBig);
+        }
+        //Like on Linux, user attributes are only for regular files and directories
+        let (_sector_id, sector) = self.find_ino_sector(ino)?;
+        if matches!(sector, Sector::SymlinkMetadata(_)) && name.starts_with(b"user.") {
+            bail!(FsError::NotPermitted);
+        }
+        let mut xattrs = self.xattrs(ino)?;
+        match xattrs.iter_mut().find(|(key, _value)| key == name) {
+            Some(_) if flags & XATTR_CREATE != 0 => bail!(FsError::AlreadyExists),
+            None if flags & XATTR_REPLACE != 0 => bail!(FsError::NoData),
+            Some((_key, old_value)) => *old_value = value.to_vec(),
+            None => xattrs.push((name.to_vec(), value.to_vec())),
+        }
+        let list_size: usize = xattrs.iter().map(|(key, _value)| key.len() + 1).sum();
+        if list_size > XATTR_LIST_MAX {
+            bail!(FsError::TooBig);
+        }
+        self.write_xattrs(ino, &xattrs)
+    }
+    pub fn removexattr(&mut self, ino: u64, name: &OsStr) -> Result<()> {
+        self.transaction(|container| container.removexattr_inner(ino, name))
+    }
+    fn removexattr_inner(&mut self, ino: u64, name: &OsStr) -> Result<()> {
+        let name = Self::xattr_name(name)?;
+        let mut xattrs = self.xattrs(ino)?;
+        let Some(idx) = xattrs.iter().position(|(key, _value)| key == name) else {
+            bail!(FsError::NoData);
+        };
+        xattrs.remove(idx);
+        self.write_xattrs(ino, &xattrs)
+    }
+    pub fn link(&mut self, ino: u64, newparent: u64, newname: &OsStr) -> Result<()> {
+        self.transaction(|container| container.link_inner(ino, newparent, newname))
+    }
+    fn link_inner(&mut self, ino: u64, newparent: u64, newname: &OsStr) -> Result<()> {
+        let name = self.entry_name(newname)?;
+        let (_sector_id, sector) = self.find_ino_sector(ino)?;
+        let filetype = match sector {
+            Sector::FileMetadata(_) => sector::FileType::Regular,
+            Sector::SymlinkMetadata(_) => sector::FileType::Symlink,
+            _ => bail!(FsError::NotPermitted),
+        };
+        let (_metadata_sector_id, metadata_sector) = self.find_ino_sector(newparent)?;
+        let Sector::DirMetadata(dir_metadata) = &metadata_sector else {
+            bail!(FsError::NotADirectory);
+        };
+        if self.find_entry(dir_metadata, newname)?.is_some() {
+            bail!(FsError::AlreadyExists);
+        }
+        self.reserve_sectors(1)?;
+        self.add_links(ino, 1)?;
+        self.insert_entry(
+            newparent,
+            DirEntry {
                 ino,
-                filetype: FileType::RegularFile,
-                size: file_metadata.length_byte(),
-            };
-            return Ok(Some(attr));
+                name,
+                filetype,
+                empty: false,
+            },
+        )?;
+        self.touch(newparent, true)?;
+        Ok(())
+    }
+    pub fn rmdir(&mut self, parent: u64, name: &OsStr) -> Result<()> {
+        self.transaction(|container| container.rmdir_inner(parent, name))
+    }
+    fn rmdir_inner(&mut self, parent: u64, name: &OsStr) -> Result<()> {
+        let (_metadata_sector_id, metadata_sector) = self.find_ino_sector(parent)?;
+        let Sector::DirMetadata(dir_metadata) = &metadata_sector else {
+            bail!(FsError::NotADirectory);
+        };
+        let Some((sector_id, idx, entry)) = self.find_entry(dir_metadata, name)? else {
+            bail!(FsError::NotFound);
+        };
+        if entry.filetype != sector::FileType::Directory {
+            bail!(FsError::NotADirectory);
+        }
+        let (_sector_id, sector) = self.find_ino_sector(entry.ino)?;
+        let Sector::DirMetadata(target_metadata) = &sector else {
+            bail!(FsError::NotADirectory);
+        };
+        if !self.is_dir_empty(target_metadata)? {
+            bail!(FsError::NotEmpty);
         }
-        Ok(None)
+        //Emptied while it still has its name, the inode and the entry going last
+        self.empty_inode(entry.ino)?;
+        self.add_links(parent, -1)?;
+        self.clear_entry(sector_id, idx)?;
+        self.delete_dir(entry.ino)?;
+        self.touch(parent, true)?;
+        Ok(())
     }
-    pub fn lookup(&mut self, parent: u64, name: &OsStr) -> Result<Option<(u64, FileType)>> {
-        let (_metadata_sector_id, mut metadata_sector) = self.find_ino_sector(parent)?;
-        let Sector::DirMetadata(dir_metadata) = &mut metadata_sector else {
-            bail!("Inode {parent} is not a directory.");
+    pub fn symlink(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        target: &OsStr,
+        uid: u32,
+        gid: u32,
+    ) -> Result<u64> {
+        self.transaction(|container| container.symlink_inner(parent, name, target, uid, gid))
+    }
+    fn symlink_inner(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        target: &OsStr,
+        uid: u32,
+        gid: u32,
+    ) -> Result<u64> {
+        let target = target.as_bytes();
+        let chunk_size = self.chunk_size();
+        if target.is_empty() {
+            bail!(FsError::NotFound);
+        }
+        if target.len() >= PATH_MAX as usize {
+            bail!(FsError::NameTooLong);
+        }
+        //Like on Linux, the permissions of a symbolic link are always 0777
+        let permissions = Permissions {
+            mode: 0o777,
+            uid,
+            gid,
         };
-        let mut next_sector = dir_metadata.first_sector();
-
-        //Iterate through all sector of directory
-        while let Some(sector_id) = next_sector {
-            let base_sector = self.read_sector(sector_id)?;
-            let Sector::DirData(sector) = &base_sector else {
-                bail!("Directory sector is not DirData (inode {parent}, sector {sector_id})");
-            };
-            //Look for used entry
-            for entry in sector.entries() {
-                if !entry.empty {
-                    let entry_name = OsString::from(entry.name.to_string());
-                    if entry_name == *name {
-                        let filetype = match entry.filetype {
-                            sector::FileType::Directory => FileType::Directory,
-                            sector::FileType::Regular => FileType::RegularFile,
-                        };
-                        return Ok(Some((entry.ino, filetype)));
-                    }
+        if target.len() > SYMLINK_INLINE_SIZE {
+            self.reserve_sectors(3 + target.len().div_ceil(chunk_size) as u64)?;
+        }
+        let ino = self.create(parent, name, sector::FileType::Symlink, permissions)?;
+        let (metadata_sector_id, mut metadata_sector) = self.find_ino_sector(ino)?;
+        let Sector::SymlinkMetadata(symlink_metadata) = &mut metadata_sector else {
+            bail!("Inode {ino} is not a symbolic link.");
+        };
+        if !symlink_metadata.set_inline_target(target) {
+            //Long targets are stored in a FileData chain
+            let mut previous_sector: Option<(u64, FileData)> = None;
+            for chunk in target.chunks(chunk_size) {
+                let sector_id = self.get_empty_sector()?;
+                symlink_metadata.metadata_mut().increase_length_sector();
+                let mut file_data = FileData::new(chunk_size);
+                file_data.write(chunk, 0, chunk.len());
+                file_data.set_data_length(chunk.len() as u64);
+                if let Some((previous_id, mut previous_data)) = previous_sector.take() {
+                    previous_data.set_next(sector_id);
+                    file_data.set_previous(previous_id);
+                    self.write_sector(previous_id, &Sector::FileData(previous_data))?;
+                } else {
+                    symlink_metadata.metadata_mut().set_first_sector(sector_id);
                 }
+                previous_sector = Some((sector_id, file_data));
+            }
+            if let Some((sector_id, file_data)) = previous_sector {
+                self.write_sector(sector_id, &Sector::FileData(file_data))?;
             }
-            next_sector = sector.next_sector();
         }
-        Ok(None)
+        symlink_metadata
+            .metadata_mut()
+            .set_length_byte(target.len() as u64);
+        self.write_sector(metadata_sector_id, &metadata_sector)?;
+        Ok(ino)
     }
-    pub fn unlink(&mut self, parent: u64, name: &OsStr) -> Result<()> {
-        let (_metadata_sector_id, mut metadata_sector) = self.find_ino_sector(parent)?;
-        let Sector::DirMetadata(dir_metadata) = &mut metadata_sector else {
-            bail!("Inode {parent} is not a directory.");
+    pub fn readlink(&mut self, ino: u64) -> Result<Vec<u8>> {
+        let (_metadata_sector_id, metadata_sector) = self.find_ino_sector(ino)?;
+        let Sector::SymlinkMetadata(symlink_metadata) = &metadata_sector else {
+            bail!(FsError::InvalidArgument);
         };
-        let mut next_sector = dir_metadata.first_sector();
+        let Some(first_sector) = symlink_metadata.metadata().first_sector() else {
+            return Ok(symlink_metadata.inline_target().to_vec());
+        };
+        let mut target = Vec::new();
+        let mut current_sector_id = Some(first_sector);
+        while let Some(sector_id) = current_sector_id {
+            let Sector::FileData(file_data) = self.read_sector(sector_id)? else {
+                bail!("Sector {sector_id} (ino {ino}) is not a FileData");
+            };
+            target.extend_from_slice(&file_data.data()[..file_data.data_length() as usize]);
+            current_sector_id = file_data.next();
+        }
+        Ok(target)
+    }
+    pub fn rename(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<()> {
+        self.transaction(|container| {
+            container.rename_inner(parent, name, newparent, newname, flags)
+        })
+    }
+    fn rename_inner(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<()> {
+        let noreplace = flags & RENAME_NOREPLACE != 0;
+        let exchange = flags & RENAME_EXCHANGE != 0;
+        if (noreplace && exchange) || flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE) != 0 {
+            bail!(FsError::InvalidArgument);
+        }
+        let new_name = self.entry_name(newname)?;
+        let (_metadata_sector_id, metadata_sector) = self.find_ino_sector(parent)?;
+        let Sector::DirMetadata(dir_metadata) = &metadata_sector else {
+            bail!(FsError::NotADirectory);
+        };
+        let Some((sector_id, idx, entry)) = self.find_entry(dir_metadata, name)? else {
+            bail!(FsError::NotFound);
+        };
+        let (_metadata_sector_id, metadata_sector) = self.find_ino_sector(newparent)?;
+        let Sector::DirMetadata(new_dir_metadata) = &metadata_sector else {
+            bail!(FsError::NotADirectory);
+        };
+        let target = self.find_entry(new_dir_metadata, newname)?;
 
-        let mut ino = None;
-        //Iterate through all sector of directory
-        while let Some(sector_id) = next_sector {
-            let mut base_sector = self.read_sector(sector_id)?;
-            let Sector::DirData(sector) = &mut base_sector else {
-                bail!("Directory sector is not DirData (inode {parent}, sector {sector_id})");
+        //A directory cannot be moved into its own subtree
+        if entry.filetype == sector::FileType::Directory
+            && self.is_in_subtree(entry.ino, newparent)?
+        {
+            bail!(FsError::InvalidArgument);
+        }
+
+        if exchange {
+            let Some((target_sector_id, target_idx, target_entry)) = target else {
+                bail!(FsError::NotFound);
             };
-            //Look for entry with the right name
-            for entry in sector.entries_mut() {
-                if !entry.empty {
-                    let entry_name = OsString::from(entry.name.to_string());
-                    if entry_name == *name {
-                        if entry.filetype == sector::FileType::Directory {
-                            bail!("{name:?} is a directory.");
-                        }
-                        //Free entry and set ino
-                        ino = Some(entry.ino);
-                        entry.empty = true;
-                        entry.ino = 0;
-                        entry.name = heapless::String::new();
-                        break;
-                    }
-                }
+            if target_entry.ino == entry.ino {
+                return Ok(());
             }
-            next_sector = sector.next_sector();
-            if ino.is_some() {
-                //The file has been found so we write it back
-                self.write_sector(sector_id, &base_sector)?;
-                break;
+            if target_entry.filetype == sector::FileType::Directory
+                && self.is_in_subtree(target_entry.ino, parent)?
+            {
+                bail!(FsError::InvalidArgument);
             }
+            if parent != newparent {
+                let is_dir = |filetype| i32::from(filetype == sector::FileType::Directory);
+                let moved_dirs = is_dir(entry.filetype) - is_dir(target_entry.filetype);
+                self.add_links(parent, -moved_dirs)?;
+                self.add_links(newparent, moved_dirs)?;
+            }
+            //Both names keep their place, only the inodes they point to are swapped
+            self.set_entry_target(target_sector_id, target_idx, entry.ino, entry.filetype)?;
+            self.set_entry_target(sector_id, idx, target_entry.ino, target_entry.filetype)?;
+            self.set_parent(entry.ino, newparent)?;
+            self.set_parent(target_entry.ino, parent)?;
+            self.touch(target_entry.ino, false)?;
+            self.touch_renamed(entry.ino, parent, newparent)?;
+            return Ok(());
         }
-        //If the name has not been found, return Ok. No problem encountered
-        let Some(ino) = ino else {
+
+        let Some((target_sector_id, target_idx, target_entry)) = target else {
+            self.reserve_sectors(1)?;
+            self.move_dir_links(entry.filetype, parent, newparent)?;
+            //Add the new name before removing the old one so the file is never unreachable
+            self.insert_entry(
+                newparent,
+                DirEntry {
+                    ino: entry.ino,
+                    name: new_name,
+                    filetype: entry.filetype,
+                    empty: false,
+                },
+            )?;
+            self.clear_entry(sector_id, idx)?;
+            self.set_parent(entry.ino, newparent)?;
+            self.touch_renamed(entry.ino, parent, newparent)?;
             return Ok(());
         };
-        self.delete_file(ino)?;
+        if noreplace {
+            bail!(FsError::AlreadyExists);
+        }
+        if target_entry.ino == entry.ino {
+            //Renaming a file onto itself does nothing
+            return Ok(());
+        }
+        match (entry.filetype, target_entry.filetype) {
+            (sector::FileType::Directory, sector::FileType::Directory) => {
+                let (_sector_id, sector) = self.find_ino_sector(target_entry.ino)?;
+                let Sector::DirMetadata(target_metadata) = &sector else {
+                    bail!(FsError::NotADirectory);
+                };
+                if !self.is_dir_empty(target_metadata)? {
+                    bail!(FsError::NotEmpty);
+                }
+            }
+            (sector::FileType::Directory, _) => bail!(FsError::NotADirectory),
+            (_, sector::FileType::Directory) => bail!(FsError::IsADirectory),
+            _ => {}
+        }
+        let (_sector_id, sector) = self.find_ino_sector(target_entry.ino)?;
+        if target_entry.filetype == sector::FileType::Directory || self.nlink(&sector)? == 1 {
+            //Emptied while it still has its name, the inode and the entry going last
+            self.empty_inode(target_entry.ino)?;
+        }
+        if target_entry.filetype == sector::FileType::Directory {
+            self.add_links(newparent, -1)?;
+        }
+        self.move_dir_links(entry.filetype, parent, newparent)?;
+        //The target entry switches to the moved inode in a single sector write
+        self.set_entry_target(target_sector_id, target_idx, entry.ino, entry.filetype)?;
+        self.clear_entry(sector_id, idx)?;
+        self.set_parent(entry.ino, newparent)?;
+        match target_entry.filetype {
+            sector::FileType::Regular | sector::FileType::Symlink => {
+                self.drop_link(target_entry.ino, newparent)?
+            }
+            sector::FileType::Directory => self.delete_dir(target_entry.ino)?,
+        }
+        self.touch_renamed(entry.ino, parent, newparent)?;
+        Ok(())
+    }
+    /// A directory moving to another parent takes its `..` link with it.
+    fn move_dir_links(
+        &mut self,
+        filetype: sector::FileType,
+        parent: u64,
+        newparent: u64,
+    ) -> Result<()> {
+        if filetype == sector::FileType::Directory && parent != newparent {
+            self.add_links(parent, -1)?;
+            self.add_links(newparent, 1)?;
+        }
+        Ok(())
+    }
+    fn touch_renamed(&mut self, ino: u64, parent: u64, newparent: u64) -> Result<()> {
+        self.touch(ino, false)?;
+        self.touch(parent, true)?;
+        if newparent != parent {
+            self.touch(newparent, true)?;
+        }
         Ok(())
     }
     pub fn write(&mut self, ino: u64, offset: i64, data: &[u8]) -> Result<u64> {
+        self.transaction(|container| container.write_inner(ino, offset, data))
+    }
+    fn write_inner(&mut self, ino: u64, offset: i64, data: &[u8]) -> Result<u64> {
+        let chunk_size = self.chunk_size();
         //TODO What is offset? The offset base on the beginning of a file or the hyphothetical
         //cursor?
         if offset < 0 {
@@ -502,126 +2191,117 @@ impl Container {
         }
         let offset = offset as u64;
         let (metadata_sector_id, mut metadata_sector) = self.find_ino_sector(ino)?;
+        self.migrate_to_block_map(metadata_sector_id, &mut metadata_sector)?;
         let Sector::FileMetadata(file_metadata) = &mut metadata_sector else {
             bail!("Inode {ino} is not a directory.");
         };
-        let offset = if offset > file_metadata.length_byte() {
-            file_metadata.length_byte()
-        } else {
-            offset
-        };
+        let end = offset + data.len() as u64;
+        //Blocks skipped past the end are left unmapped as a hole
+        self.extend_tail(file_metadata, end)?;
+        let first_block = offset / chunk_size as u64;
+        let blocks = first_block..end.div_ceil(chunk_size as u64);
+        let mapped = self.mapped_sectors(file_metadata.first_sector(), blocks.clone())?;
+        self.reserve_sectors(mapped.iter().filter(|sector| sector.is_none()).count() as u64)?;
 
-        let mut current_sector_id = file_metadata.first_sector();
-        let mut file_index = 0;
+        //New sectors follow the block before them, or the metadata
+        let mut goal = match first_block.checked_sub(1) {
+            Some(previous_block) => self
+                .mapped_sectors(file_metadata.first_sector(), previous_block..first_block)?[0]
+                .unwrap_or(metadata_sector_id),
+            None => metadata_sector_id,
+        } + 1;
         let mut data_index = 0;
-        let mut sector_index = 0;
-
-        let mut previous_sector_id = None;
-
-        let mut starting_sector = None;
-        //find offset
-        while let Some(sector_id) = current_sector_id {
-            let mut sector = self.read_sector(sector_id)?;
-            let Sector::FileData(sector_data) = &mut sector else {
-                bail!("Sector {sector_id} (ino {ino} is not a FileData {sector:?}");
-            };
-            if offset >= file_index + DATA_CHUNK_SIZE as u64 {
-                current_sector_id = sector_data.next();
-                file_index += DATA_CHUNK_SIZE as u64;
-                continue;
-            }
-
-            //Check if offset starts at this sector
-            if offset >= file_index && offset < file_index + DATA_CHUNK_SIZE as u64 {
-                starting_sector = Some(sector_id);
-                sector_index = (offset - file_index) as usize;
-                previous_sector_id = Some(sector_id);
-                break;
-            }
-        }
-
-        let mut current_sector_id = if let Some(a) = starting_sector {
-            a
-        } else {
-            //Get an empty sector if needed
-            let empty_sector_id = self.get_empty_sector()?;
-            file_metadata.increase_length_sector();
-            let mut next_sector = FileData::new();
-            //Setup the sector
-            if let Some(previous_sector_id) = previous_sector_id {
-                next_sector.set_previous(previous_sector_id);
-            } else {
-                file_metadata.set_first_sector(empty_sector_id);
-            }
-            //Write it
-            self.write_sector(empty_sector_id, &Sector::FileData(next_sector))?;
-            empty_sector_id
-        };
-        let mut total_data_diff = 0;
-
-        //Loop through the sectors to write the data
-        loop {
-            let mut sector = self.read_sector(current_sector_id)?;
-            let Sector::FileData(sector_data) = &mut sector else {
-                bail!("Sector {current_sector_id} (ino {ino} is not a FileData {sector:?}");
+        for (block, sector_id) in blocks.zip(mapped) {
+            let block_start = block * chunk_size as u64;
+            let sector_index = (offset.max(block_start) - block_start) as usize;
+            let write_qty = (data.len() - data_index).min(chunk_size - sector_index);
+            let (sector_id, mut sector_data) = match sector_id {
+                Some(sector_id) => {
+                    let root = file_metadata.first_sector();
+                    let sector_id = self.unshare_block(root, block, sector_id)?;
+                    let Sector::FileData(sector_data) = self.read_sector(sector_id)? else {
+                        bail!("Sector {sector_id} (ino {ino}) is not a FileData");
+                    };
+                    (sector_id, sector_data)
+                }
+                None => {
+                    let root = file_metadata.first_sector();
+                    let (root, sector_id) = self.map_new_block(root, block, goal)?;
+                    file_metadata.set_first_sector(root);
+                    file_metadata.increase_length_sector();
+                    (sector_id, FileData::new(chunk_size))
+                }
             };
-            //Remaining qty to write from data
-            let data_qty = data.len() - data_index;
-            //Maximum qty writable in that sector
-            let write_qty = data_qty.min(DATA_CHUNK_SIZE - sector_index);
-
-            //Write the data into the FileData struct
             let slice = &data[data_index..data_index + write_qty];
             sector_data.write(slice, sector_index, sector_index + write_qty);
-            let prev_data_length = sector_data.data_length() as usize;
-
-            let length_diff = if sector_index + write_qty < prev_data_length {
-                0
-            } else {
-                sector_index + write_qty - prev_data_length
-            };
-            total_data_diff += length_diff;
-            sector_data.set_data_length((prev_data_length + length_diff) as u64);
-
-            //Update index
+            //Blocks filled in a hole hold data up to the end of the file
+            let data_length = sector_data
+                .data_length()
+                .max((sector_index + write_qty) as u64)
+                .max(file_metadata.length_byte().saturating_sub(block_start));
+            sector_data.set_data_length(data_length);
+            self.write_sector(sector_id, &Sector::FileData(sector_data))?;
             data_index += write_qty;
-            if data_index == data.len() {
-                //We are done writing
-                self.write_sector(current_sector_id, &sector)?;
-                break;
+            goal = sector_id + 1;
+            if self.checkpoint_due() {
+                //Make the data written so far part of the file first
+                let written = block_start + sector_index as u64 + write_qty as u64;
+                if written > file_metadata.length_byte() {
+                    file_metadata.set_length_byte(written);
+                }
+                let metadata_copy = Sector::FileMetadata(file_metadata.clone());
+                self.write_sector(metadata_sector_id, &metadata_copy)?;
+                self.checkpoint()?;
             }
-            sector_index = 0;
-            file_index += DATA_CHUNK_SIZE as u64;
-
-            //Append a new sector if needed
-            let next_sector_id = if let Some(a) = sector_data.next() {
-                a
-            } else {
-                let empty_sector_id = self.get_empty_sector()?;
-                file_metadata.increase_length_sector();
-                sector_data.set_next(empty_sector_id);
-                let mut next_sector = FileData::new();
-                next_sector.set_previous(current_sector_id);
-                self.write_sector(empty_sector_id, &Sector::FileData(next_sector))?;
-                empty_sector_id
-            };
-            self.write_sector(current_sector_id, &sector)?;
-            current_sector_id = next_sector_id;
         }
 
-        file_metadata.increase_length_byte(total_data_diff as u64);
+        if end > file_metadata.length_byte() {
+            file_metadata.set_length_byte(end);
+        }
+        let now = SystemTime::now();
+        file_metadata.set_mtime(now);
+        file_metadata.set_ctime(now);
         self.write_sector(metadata_sector_id, &metadata_sector)?;
 
         Ok(data.len().try_into()?)
     }
+    /// Before a file grows to `length`, extend the data of its blocks up to `length`,
+    /// zeroing the bytes they kept past the current end from before a truncation.
+    fn extend_tail(&mut self, file_metadata: &FileMetadata, length: u64) -> Result<()> {
+        let chunk_size = self.chunk_size() as u64;
+        let length_byte = file_metadata.length_byte();
+        if length <= length_byte {
+            return Ok(());
+        }
+        //The block holding the end, and those preallocated past it
+        let blocks = length_byte / chunk_size..length.div_ceil(chunk_size);
+        let root = file_metadata.first_sector();
+        for (block, sector_id) in self.mapped_blocks(root, blocks)? {
+            let sector_id = self.unshare_block(root, block, sector_id)?;
+            let Sector::FileData(mut sector_data) = self.read_sector(sector_id)? else {
+                bail!("Sector {sector_id} is not a FileData");
+            };
+            let block_start = block * chunk_size;
+            let start = length_byte.saturating_sub(block_start) as usize;
+            let zeros = vec![0; chunk_size as usize - start];
+            sector_data.write(&zeros, start, chunk_size as usize);
+            sector_data.set_data_length((length - block_start).min(chunk_size));
+            self.write_sector(sector_id, &Sector::FileData(sector_data))?;
+        }
+        Ok(())
+    }
     pub fn read(&mut self, ino: u64, offset: i64, size: u64, data: &mut Vec<u8>) -> Result<u64> {
+        let chunk_size = self.chunk_size();
         //TODO What is offset? The offset base on the beginning of a file or the hyphothetical
         //cursor?
         if offset < 0 {
             bail!("Reading at a negative offset (offset={offset})");
         }
         let offset = offset as u64;
-        let (_metadata_sector_id, mut metadata_sector) = self.find_ino_sector(ino)?;
+        let (metadata_sector_id, mut metadata_sector) = self.find_ino_sector(ino)?;
+        self.transaction(|container| {
+            container.migrate_to_block_map(metadata_sector_id, &mut metadata_sector)
+        })?;
         let Sector::FileMetadata(file_metadata) = &mut metadata_sector else {
             bail!("Inode {ino} is not a directory.");
         };
@@ -629,78 +2309,91 @@ impl Container {
             return Ok(0);
         }
 
-        let mut current_sector_id = file_metadata.first_sector();
-        let mut file_index = 0;
-        let mut sector_index = 0;
-
-        let mut starting_sector = None;
-        //find offset
-        while let Some(sector_id) = current_sector_id {
-            let mut sector = self.read_sector(sector_id)?;
-            let Sector::FileData(sector_data) = &mut sector else {
-                bail!("Sector {sector_id} (ino {ino} is not a FileData {sector:?}");
-            };
-            if offset >= file_index + DATA_CHUNK_SIZE as u64 {
-                current_sector_id = sector_data.next();
-                file_index += DATA_CHUNK_SIZE as u64;
-                continue;
+        let end = offset.saturating_add(size).min(file_metadata.length_byte());
+        let blocks = offset / chunk_size as u64..end.div_ceil(chunk_size as u64);
+        let mapped = self.mapped_sectors(file_metadata.first_sector(), blocks.clone())?;
+        for (block, sector_id) in blocks.zip(mapped) {
+            let block_start = block * chunk_size as u64;
+            let start = (offset.max(block_start) - block_start) as usize;
+            let stop = (end - block_start).min(chunk_size as u64) as usize;
+            match sector_id {
+                Some(sector_id) => {
+                    let Sector::FileData(sector_data) = self.read_sector(sector_id)? else {
+                        bail!("Sector {sector_id} (ino {ino}) is not a FileData");
+                    };
+                    data.extend_from_slice(&sector_data.data()[start..stop]);
+                }
+                //Unmapped blocks read as zeros
+                None => data.resize(data.len() + stop - start, 0),
             }
+        }
 
-            //Check if offset starts at this sector
-            if offset >= file_index && offset < file_index + DATA_CHUNK_SIZE as u64 {
-                starting_sector = Some(sector_id);
-                sector_index = (offset - file_index) as usize;
-                break;
-            }
+        //Same policy as relatime: only refresh atime if it is older than mtime/ctime or stale
+        let now = SystemTime::now();
+        let atime = file_metadata.atime();
+        if atime <= file_metadata.mtime()
+            || atime <= file_metadata.ctime()
+            || now
+                .duration_since(atime)
+                .is_ok_and(|age| age >= RELATIME_PERIOD)
+        {
+            file_metadata.set_atime(now);
+            self.write_sector(metadata_sector_id, &metadata_sector)?;
         }
 
-        let Some(mut current_sector_id) = starting_sector else {
-            bail!("Couldn't find the offset");
+        Ok(data.len().try_into()?)
+    }
+    /// Offset of the first data of a regular file from `offset` on, like SEEK_DATA.
+    ///
+    /// Data and holes are found block by block: a mapped block is data even where it holds zeros.
+    pub fn seek_data(&mut self, ino: u64, offset: u64) -> Result<u64> {
+        let chunk_size = self.chunk_size() as u64;
+        let file_metadata = self.seekable_file(ino, offset)?;
+        let blocks = offset / chunk_size..file_metadata.length_byte().div_ceil(chunk_size);
+        let mapped = self.mapped_blocks(file_metadata.first_sector(), blocks)?;
+        let Some(&(block, _sector_id)) = mapped.first() else {
+            bail!(FsError::NoSuchAddress);
         };
-
-        //Loop through the sectors to read the data
-        loop {
-            let mut sector = self.read_sector(current_sector_id)?;
-            let Sector::FileData(sector_data) = &mut sector else {
-                bail!("Sector {current_sector_id} (ino {ino} is not a FileData {sector:?}");
-            };
-            //Remaining qty to read from data
-            let data_qty = size as usize - data.len();
-            //Maximum qty readable in that sector
-            let read_qty = data_qty.min(sector_data.data_length() as usize - sector_index);
-
-            //Read the data from FileData struct
-            let slice = &sector_data.data()[sector_index..sector_index + read_qty];
-            data.extend_from_slice(slice);
-
-            //Update index
-            if size == data.len() as u64 {
-                //We are done reading
-                break;
-            }
-            sector_index = 0;
-            file_index += DATA_CHUNK_SIZE as u64;
-
-            //Append a new sector if needed
-            if let Some(next_sector_id) = sector_data.next() {
-                current_sector_id = next_sector_id;
-            } else {
-                //EOF
+        Ok(offset.max(block * chunk_size))
+    }
+    /// Offset of the first hole of a regular file from `offset` on, like SEEK_HOLE. The end
+    /// of the file counts as a hole.
+    pub fn seek_hole(&mut self, ino: u64, offset: u64) -> Result<u64> {
+        let chunk_size = self.chunk_size() as u64;
+        let file_metadata = self.seekable_file(ino, offset)?;
+        let length_byte = file_metadata.length_byte();
+        let first_block = offset / chunk_size;
+        let blocks = first_block..length_byte.div_ceil(chunk_size);
+        let mut hole = first_block;
+        for (block, _sector_id) in self.mapped_blocks(file_metadata.first_sector(), blocks)? {
+            if block != hole {
                 break;
             }
+            hole += 1;
         }
-
-        Ok(data.len().try_into()?)
+        Ok(offset.max(hole * chunk_size).min(length_byte))
+    }
+    /// Metadata of the regular file `ino` once its data is a block map, failing with ENXIO
+    /// if `offset` is past its end.
+    fn seekable_file(&mut self, ino: u64, offset: u64) -> Result<FileMetadata> {
+        let (metadata_sector_id, mut metadata_sector) = self.find_ino_sector(ino)?;
+        self.transaction(|container| {
+            container.migrate_to_block_map(metadata_sector_id, &mut metadata_sector)
+        })?;
+        let Sector::FileMetadata(file_metadata) = metadata_sector else {
+            bail!(FsError::InvalidArgument);
+        };
+        if offset >= file_metadata.length_byte() {
+            bail!(FsError::NoSuchAddress);
+        }
+        Ok(file_metadata)
     }
     pub fn lookup_name(&mut self, ino: u64) -> Result<OsString> {
         let (_sector_id, sector) = self.find_ino_sector(ino)?;
-        let parent_ino = if let Sector::FileMetadata(file_metadata) = sector {
-            file_metadata.parent()
-        } else if let Sector::DirMetadata(dir_metadata) = sector {
-            dir_metadata.parent()
-        } else {
+        let Some(metadata) = sector.metadata() else {
             bail!("Sector is not a metadata sector.");
         };
+        let parent_ino = metadata.parent();
         let Some(parent_ino) = parent_ino else {
             //We are checking the root
             return Ok(OsString::from_str("/")?);
@@ -711,64 +2404,383 @@ impl Container {
             bail!("Parent of inode {ino} is not a directory");
         };
 
-        let mut next_sector = dir_metadata.first_sector();
-        //Iterate through all sector of directory
-        while let Some(sector_id) = next_sector {
-            let base_sector = self.read_sector(sector_id)?;
-            let Sector::DirData(sector) = base_sector else {
-                bail!("Directory sector is not DirData (inode {ino}, sector {sector_id})");
-            };
-            for entry in sector.entries() {
-                if !entry.empty && entry.ino == ino {
-                    let ename = OsString::from(entry.name.to_string());
-                    return Ok(ename);
-                }
-            }
-            next_sector = sector.next_sector();
-        }
-        bail!("Inode {ino} not found in parent directory");
+        //With several names, any of those held by the parent will do
+        let entries = self.dir_entries(&dir_metadata)?;
+        let Some(entry) = entries.iter().find(|entry| entry.ino == ino) else {
+            bail!("Inode {ino} not found in parent directory");
+        };
+        Ok(OsString::from(entry.name.to_string()))
     }
     pub fn truncate(&mut self, ino: u64, offset: u64) -> Result<()> {
+        self.transaction(|container| container.truncate_inner(ino, offset))
+    }
+    fn truncate_inner(&mut self, ino: u64, offset: u64) -> Result<()> {
+        let chunk_size = self.chunk_size() as u64;
         let (metadata_sector_id, mut metadata_sector) = self.find_ino_sector(ino)?;
+        self.migrate_to_block_map(metadata_sector_id, &mut metadata_sector)?;
         let Sector::FileMetadata(file_metadata) = &mut metadata_sector else {
             bail!("Inode {ino} is not a directory.");
         };
-        match offset.cmp(&file_metadata.length_byte()) {
-            Ordering::Greater => bail!(
-                "Offset is too large for truncating (offset={offset}, file size={}.",
-                file_metadata.length_byte()
-            ),
-            Ordering::Equal => return Ok(()),
-            Ordering::Less => {}
-        }
+        let length_byte = file_metadata.length_byte();
+        //A file extended ends with a hole, nothing is allocated
+        self.extend_tail(file_metadata, offset)?;
         file_metadata.set_length_byte(offset);
-        let mut current_sector_id = file_metadata.first_sector();
-        self.write_sector(metadata_sector_id, &metadata_sector)?;
-        let mut file_index = 0;
+        //Even when the size does not change, as for `open` with `O_TRUNC` on an empty file
+        let now = SystemTime::now();
+        file_metadata.set_mtime(now);
+        file_metadata.set_ctime(now);
+        if offset >= length_byte {
+            self.write_sector(metadata_sector_id, &metadata_sector)?;
+            return Ok(());
+        }
 
-        //find offset
-        while let Some(sector_id) = current_sector_id {
-            let mut sector = self.read_sector(sector_id)?;
-            let Sector::FileData(sector_data) = &mut sector else {
-                bail!("Sector {sector_id} (ino {ino} is not a FileData {sector:?}");
-            };
-            current_sector_id = sector_data.next();
-            if offset >= file_index + DATA_CHUNK_SIZE as u64 {
-                current_sector_id = sector_data.next();
-                file_index += DATA_CHUNK_SIZE as u64;
+        let first_freed = offset.div_ceil(chunk_size);
+        self.free_blocks(metadata_sector_id, file_metadata, first_freed..u64::MAX)?;
+        //The block holding the new end keeps its first bytes
+        if offset % chunk_size != 0 {
+            let block = offset / chunk_size;
+            let root = file_metadata.first_sector();
+            if let Some(sector_id) = self.mapped_sectors(root, block..block + 1)?[0] {
+                let sector_id = self.unshare_block(root, block, sector_id)?;
+                let Sector::FileData(mut sector_data) = self.read_sector(sector_id)? else {
+                    bail!("Sector {sector_id} (ino {ino}) is not a FileData");
+                };
+                sector_data.set_data_length(offset % chunk_size);
+                self.write_sector(sector_id, &Sector::FileData(sector_data))?;
+            }
+        }
+        self.write_sector(metadata_sector_id, &metadata_sector)?;
+        Ok(())
+    }
+    /// Allocate or free the sectors of `offset..offset + length` of a regular file, `mode`
+    /// taking the flags of fallocate(2).
+    ///
+    /// Without flags, unmapped blocks get zeroed sectors and the file grows to the end of the
+    /// range, unless `FALLOC_FL_KEEP_SIZE` is set. `FALLOC_FL_PUNCH_HOLE` frees the blocks in
+    /// the range and zeroes the rest of it, and `FALLOC_FL_ZERO_RANGE` zeroes the range with
+    /// its sectors allocated.
+    pub fn fallocate(&mut self, ino: u64, offset: u64, length: u64, mode: i32) -> Result<()> {
+        self.transaction(|container| container.fallocate_inner(ino, offset, length, mode))
+    }
+    fn fallocate_inner(&mut self, ino: u64, offset: u64, length: u64, mode: i32) -> Result<()> {
+        if mode & !(FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE) != 0 {
+            bail!(FsError::NotSupported);
+        }
+        let punch_hole = mode & FALLOC_FL_PUNCH_HOLE != 0;
+        let keep_size = mode & FALLOC_FL_KEEP_SIZE != 0;
+        if punch_hole && (!keep_size || mode & FALLOC_FL_ZERO_RANGE != 0) {
+            bail!(FsError::NotSupported);
+        }
+        let Some(end) = offset.checked_add(length).filter(|_| length > 0) else {
+            bail!(FsError::InvalidArgument);
+        };
+        if end > MAX_FILE_SIZE {
+            bail!(FsError::FileTooBig);
+        }
+        let (metadata_sector_id, mut metadata_sector) = self.find_ino_sector(ino)?;
+        self.migrate_to_block_map(metadata_sector_id, &mut metadata_sector)?;
+        let Sector::FileMetadata(file_metadata) = &mut metadata_sector else {
+            bail!(FsError::InvalidArgument);
+        };
+        let chunk_size = self.chunk_size() as u64;
+        let length_byte = file_metadata.length_byte();
+        let now = SystemTime::now();
+        if punch_hole {
+            //Blocks entirely in the range, past the end of the file blocks hold no data
+            let first_freed = offset.div_ceil(chunk_size);
+            let end_freed = if end >= length_byte {
+                (end / chunk_size).max(length_byte.div_ceil(chunk_size))
+            } else {
+                end / chunk_size
+            };
+            let zero_end = end.min(length_byte);
+            if first_freed >= end_freed {
+                self.zero_mapped(file_metadata, offset..zero_end)?;
+            } else {
+                self.zero_mapped(file_metadata, offset..first_freed * chunk_size)?;
+                self.zero_mapped(file_metadata, end_freed * chunk_size..zero_end)?;
+            }
+            self.free_blocks(metadata_sector_id, file_metadata, first_freed..end_freed)?;
+            file_metadata.set_mtime(now);
+            file_metadata.set_ctime(now);
+        } else {
+            if mode & FALLOC_FL_ZERO_RANGE != 0 {
+                self.zero_mapped(file_metadata, offset..end.min(length_byte))?;
+                file_metadata.set_mtime(now);
+                file_metadata.set_ctime(now);
+            }
+            if !keep_size && end > length_byte {
+                self.extend_tail(file_metadata, end)?;
+                file_metadata.set_length_byte(end);
+                file_metadata.set_ctime(now);
+            }
+            let blocks = offset / chunk_size..end.div_ceil(chunk_size);
+            self.allocate_blocks(metadata_sector_id, file_metadata, blocks)?;
+        }
+        self.write_sector(metadata_sector_id, &metadata_sector)?;
+        Ok(())
+    }
+    /// Map a zeroed sector to each unmapped block of `blocks`.
+    ///
+    /// The range can be far larger than the container, so only its mapped blocks are listed
+    /// and the space needed is checked before anything is allocated.
+    fn allocate_blocks(
+        &mut self,
+        metadata_sector_id: u64,
+        file_metadata: &mut FileMetadata,
+        blocks: Range<u64>,
+    ) -> Result<()> {
+        let chunk_size = self.chunk_size();
+        let mapped = self.mapped_blocks(file_metadata.first_sector(), blocks.clone())?;
+        self.reserve_sectors(blocks.end - blocks.start - mapped.len() as u64)?;
+        let mut goal = match blocks.start.checked_sub(1) {
+            Some(previous_block) => self
+                .mapped_sectors(file_metadata.first_sector(), previous_block..blocks.start)?[0]
+                .unwrap_or(metadata_sector_id),
+            None => metadata_sector_id,
+        } + 1;
+        let mut mapped = mapped.into_iter().peekable();
+        for block in blocks {
+            if let Some((_block, sector_id)) = mapped.next_if(|(mapped, _)| *mapped == block) {
+                goal = sector_id + 1;
+                continue;
+            }
+            let root = file_metadata.first_sector();
+            let (root, sector_id) = self.map_new_block(root, block, goal)?;
+            file_metadata.set_first_sector(root);
+            file_metadata.increase_length_sector();
+            //Blocks past the end of the file hold no data yet
+            let mut sector_data = FileData::new(chunk_size);
+            let block_start = block * chunk_size as u64;
+            sector_data.set_data_length(file_metadata.length_byte().saturating_sub(block_start));
+            self.write_sector(sector_id, &Sector::FileData(sector_data))?;
+            goal = sector_id + 1;
+            if self.checkpoint_due() {
+                let metadata_copy = Sector::FileMetadata(file_metadata.clone());
+                self.write_sector(metadata_sector_id, &metadata_copy)?;
+                self.checkpoint()?;
+            }
+        }
+        Ok(())
+    }
+    /// Free the mapped blocks of `blocks` with the nodes left empty, one at a time. Sectors
+    /// shared with other files only lose a reference.
+    fn free_blocks(
+        &mut self,
+        metadata_sector_id: u64,
+        file_metadata: &mut FileMetadata,
+        blocks: Range<u64>,
+    ) -> Result<()> {
+        let freed = self.mapped_blocks(file_metadata.first_sector(), blocks)?;
+        for (block, sector_id) in freed.into_iter().rev() {
+            match self.unmap_block(file_metadata.first_sector().unwrap_or_default(), block)? {
+                Some(root) => file_metadata.set_first_sector(root),
+                None => file_metadata.clear_first_sector(),
+            }
+            file_metadata.set_length_sector(file_metadata.length_sector().saturating_sub(1));
+            self.release_sector(sector_id)?;
+            if self.checkpoint_due() {
+                let metadata_copy = Sector::FileMetadata(file_metadata.clone());
+                self.write_sector(metadata_sector_id, &metadata_copy)?;
+                self.checkpoint()?;
+            }
+        }
+        Ok(())
+    }
+    /// Zero the bytes of `range` held by the mapped blocks of a file.
+    fn zero_mapped(&mut self, file_metadata: &FileMetadata, range: Range<u64>) -> Result<()> {
+        let chunk_size = self.chunk_size() as u64;
+        if range.is_empty() {
+            return Ok(());
+        }
+        let blocks = range.start / chunk_size..range.end.div_ceil(chunk_size);
+        let root = file_metadata.first_sector();
+        for (block, sector_id) in self.mapped_blocks(root, blocks)? {
+            let sector_id = self.unshare_block(root, block, sector_id)?;
+            let Sector::FileData(mut sector_data) = self.read_sector(sector_id)? else {
+                bail!("Sector {sector_id} is not a FileData");
+            };
+            let block_start = block * chunk_size;
+            let start = (range.start.max(block_start) - block_start) as usize;
+            let stop = (range.end - block_start).min(chunk_size) as usize;
+            sector_data.write(&vec![0; stop - start], start, stop);
+            self.write_sector(sector_id, &Sector::FileData(sector_data))?;
+        }
+        Ok(())
+    }
+    /// Copy `length` bytes of the regular file `ino_in` from `offset_in` to the regular file
+    /// `ino_out` at `offset_out`, like copy_file_range(2), and return the bytes copied.
+    ///
+    /// The copy stops at the end of `ino_in`. Blocks at the same place in a sector of both
+    /// files are copied sector by sector, holes staying holes, and with `clone` set their
+    /// sectors are shared by both files until either writes them.
+    pub fn copy_range(
+        &mut self,
+        ino_in: u64,
+        offset_in: u64,
+        ino_out: u64,
+        offset_out: u64,
+        length: u64,
+        clone: bool,
+    ) -> Result<u64> {
+        let clone = clone && self.superblock.can_share();
+        if clone {
+            self.enable_refcounts()?;
+        }
+        self.transaction(|container| {
+            container.copy_range_inner(ino_in, offset_in, ino_out, offset_out, length, clone)
+        })
+    }
+    fn copy_range_inner(
+        &mut self,
+        ino_in: u64,
+        offset_in: u64,
+        ino_out: u64,
+        offset_out: u64,
+        length: u64,
+        clone: bool,
+    ) -> Result<u64> {
+        let chunk_size = self.chunk_size() as u64;
+        let mut files = Vec::new();
+        for ino in [ino_in, ino_out] {
+            let (metadata_sector_id, mut metadata_sector) = self.find_ino_sector(ino)?;
+            self.migrate_to_block_map(metadata_sector_id, &mut metadata_sector)?;
+            let Sector::FileMetadata(file_metadata) = metadata_sector else {
+                bail!(FsError::InvalidArgument);
+            };
+            files.push(file_metadata);
+        }
+        let length = length.min(files[0].length_byte().saturating_sub(offset_in));
+        let Some(end_out) = offset_out.checked_add(length) else {
+            bail!(FsError::InvalidArgument);
+        };
+        if ino_in == ino_out && offset_in < end_out && offset_out < offset_in + length {
+            bail!(FsError::InvalidArgument);
+        }
+        if length == 0 {
+            return Ok(0);
+        }
+        //The blocks copied are then inside both files
+        if end_out > files[1].length_byte() {
+            self.truncate_inner(ino_out, end_out)?;
+        }
+        let mut copied = 0;
+        while copied < length {
+            let (position_in, position_out) = (offset_in + copied, offset_out + copied);
+            if position_in % chunk_size == 0
+                && position_out % chunk_size == 0
+                && length - copied >= chunk_size
+            {
+                let (block_in, block_out) = (position_in / chunk_size, position_out / chunk_size);
+                self.copy_block(ino_in, block_in, ino_out, block_out, clone)?;
+                copied += chunk_size;
+                self.checkpoint()?;
                 continue;
             }
-            //Check if offset starts at this sector
-            else if offset >= file_index && offset < file_index + DATA_CHUNK_SIZE as u64 {
-                sector_data.set_data_length(offset - file_index);
-                self.write_sector(sector_id, &sector)?;
-            } else if offset < file_index {
-                sector_data.set_data_length(0);
-                self.write_sector(sector_id, &sector)?;
+            //Bytes not aligned with the sectors go through a buffer
+            let size = (chunk_size - position_in % chunk_size)
+                .min(chunk_size - position_out % chunk_size)
+                .min(length - copied);
+            let mut data = Vec::new();
+            self.read(ino_in, position_in.try_into()?, size, &mut data)?;
+            self.write_inner(ino_out, position_out.try_into()?, &data)?;
+            copied += size;
+        }
+        Ok(length)
+    }
+    /// Copy the block `block_in` of `ino_in` to the block `block_out` of `ino_out`, both
+    /// entirely inside their file, sharing its sector if `clone` is set.
+    fn copy_block(
+        &mut self,
+        ino_in: u64,
+        block_in: u64,
+        ino_out: u64,
+        block_out: u64,
+        clone: bool,
+    ) -> Result<()> {
+        let chunk_size = self.chunk_size();
+        let (_sector_id, sector) = self.find_ino_sector(ino_in)?;
+        let Some(root_in) = sector.metadata().map(FileMetadata::first_sector) else {
+            bail!("Inode {ino_in} has no metadata.");
+        };
+        let source = self.mapped_sectors(root_in, block_in..block_in + 1)?[0];
+        let (metadata_sector_id, mut metadata_sector) = self.find_ino_sector(ino_out)?;
+        let Sector::FileMetadata(file_metadata) = &mut metadata_sector else {
+            bail!(FsError::InvalidArgument);
+        };
+        let root = file_metadata.first_sector();
+        let target = self.mapped_sectors(root, block_out..block_out + 1)?[0];
+        match (source, target) {
+            //Already the same sector, or both holes
+            (source, target) if source == target => return Ok(()),
+            (None, _) => {
+                self.free_blocks(metadata_sector_id, file_metadata, block_out..block_out + 1)?;
+            }
+            (Some(source), target) if clone => {
+                self.reserve_refcount(source)?;
+                if target.is_some() {
+                    let blocks = block_out..block_out + 1;
+                    self.free_blocks(metadata_sector_id, file_metadata, blocks)?;
+                }
+                let root = self.map_block(file_metadata.first_sector(), block_out, source)?;
+                file_metadata.set_first_sector(root);
+                file_metadata.increase_length_sector();
+                self.share_sector(source)?;
+            }
+            (Some(source), target) => {
+                let Sector::FileData(source_data) = self.read_sector(source)? else {
+                    bail!("Sector {source} (ino {ino_in}) is not a FileData");
+                };
+                //Only the data is copied, not the links of a sector from a chain
+                let mut sector_data = FileData::new(chunk_size);
+                sector_data.write(source_data.data(), 0, chunk_size);
+                sector_data.set_data_length(chunk_size as u64);
+                let sector_id = match target {
+                    Some(target) => self.unshare_block(root, block_out, target)?,
+                    None => {
+                        self.reserve_sectors(1)?;
+                        let (root, sector_id) =
+                            self.map_new_block(root, block_out, metadata_sector_id + 1)?;
+                        file_metadata.set_first_sector(root);
+                        file_metadata.increase_length_sector();
+                        sector_id
+                    }
+                };
+                self.write_sector(sector_id, &Sector::FileData(sector_data))?;
             }
         }
+        let now = SystemTime::now();
+        file_metadata.set_mtime(now);
+        file_metadata.set_ctime(now);
+        self.write_sector(metadata_sector_id, &metadata_sector)?;
         Ok(())
     }
 }
 
+impl Drop for Container {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            eprintln!("Failed to write back the container: {err:?}");
+        }
+    }
+}
+
+const fn to_fuser_filetype(filetype: sector::FileType) -> FileType {
+    match filetype {
+        sector::FileType::Regular => FileType::RegularFile,
+        sector::FileType::Directory => FileType::Directory,
+        sector::FileType::Symlink => FileType::Symlink,
+    }
+}
+
+mod block_map;
+mod cache;
+mod defrag;
+mod fsck;
+mod journal;
+mod refcount;
+mod superblock;
 mod test;
+
+pub use defrag::DefragReport;
+pub use fsck::FsckReport;
@@ -0,0 +1,246 @@
+use anyhow::{bail, Result};
+use std::ops::Range;
+
+use super::Container;
+use crate::encoding;
+use crate::sector::{BlockMap, Sector};
+
+impl Container {
+    /// Entries of a block map node
+    fn fan_out(&self) -> u64 {
+        encoding::block_map_fan_out(self.sector_size()) as u64
+    }
+    /// Blocks mapped by each entry of a node of `depth`.
+    pub(super) fn entry_span(&self, depth: u8) -> u64 {
+        self.fan_out().saturating_pow(depth.into())
+    }
+    pub(super) fn read_block_map(&mut self, sector_id: u64) -> Result<BlockMap> {
+        let Sector::BlockMap(node) = self.read_sector(sector_id)? else {
+            bail!("Sector {sector_id} is not a BlockMap.");
+        };
+        Ok(node)
+    }
+    /// Whether `sector_id` is the root of a block map rather than the start of a chain.
+    pub(super) fn is_block_map(&mut self, sector_id: u64) -> Result<bool> {
+        Ok(matches!(self.read_sector(sector_id)?, Sector::BlockMap(_)))
+    }
+    /// Data sector of each of `blocks` in the map rooted at `root`, None where unmapped.
+    pub(super) fn mapped_sectors(
+        &mut self,
+        root: Option<u64>,
+        blocks: Range<u64>,
+    ) -> Result<Vec<Option<u64>>> {
+        let mut sectors = vec![None; (blocks.end.saturating_sub(blocks.start)) as usize];
+        if let Some(root) = root.filter(|_| !sectors.is_empty()) {
+            let node = self.read_block_map(root)?;
+            self.collect_mapped(&node, 0, &blocks, &mut sectors)?;
+        }
+        Ok(sectors)
+    }
+    /// Fill `sectors` with the blocks of `node`, whose range starts at block `base`.
+    fn collect_mapped(
+        &mut self,
+        node: &BlockMap,
+        base: u64,
+        blocks: &Range<u64>,
+        sectors: &mut [Option<u64>],
+    ) -> Result<()> {
+        let span = self.entry_span(node.depth());
+        for (idx, entry) in node.entries().iter().enumerate() {
+            let start = base.saturating_add(span.saturating_mul(idx as u64));
+            let end = start.saturating_add(span);
+            let Some(sector_id) = *entry else {
+                continue;
+            };
+            if end <= blocks.start || start >= blocks.end {
+                continue;
+            }
+            if node.depth() == 0 {
+                sectors[(start - blocks.start) as usize] = Some(sector_id);
+            } else {
+                let child = self.read_block_map(sector_id)?;
+                self.collect_mapped(&child, start, blocks, sectors)?;
+            }
+        }
+        Ok(())
+    }
+    /// Mapped blocks of `blocks` in the map rooted at `root`, with their data sector, in
+    /// block order.
+    pub(super) fn mapped_blocks(
+        &mut self,
+        root: Option<u64>,
+        blocks: Range<u64>,
+    ) -> Result<Vec<(u64, u64)>> {
+        let mut mapped = Vec::new();
+        let Some(root) = root else {
+            return Ok(mapped);
+        };
+        //Nodes still to walk with the first block of their range, the first one on top
+        let mut nodes: Vec<(u64, u64)> = vec![(root, 0)];
+        while let Some((node_id, base)) = nodes.pop() {
+            let node = self.read_block_map(node_id)?;
+            let span = self.entry_span(node.depth());
+            let mut children = Vec::new();
+            for (idx, entry) in node.entries().iter().enumerate() {
+                let start = base.saturating_add(span.saturating_mul(idx as u64));
+                let Some(sector_id) = *entry else {
+                    continue;
+                };
+                if start.saturating_add(span) <= blocks.start || start >= blocks.end {
+                    continue;
+                }
+                if node.depth() == 0 {
+                    mapped.push((start, sector_id));
+                } else {
+                    children.push((sector_id, start));
+                }
+            }
+            nodes.extend(children.into_iter().rev());
+        }
+        Ok(mapped)
+    }
+    /// Map `block` to the data sector `sector_id` in the map rooted at `root`, adding the
+    /// nodes it needs, and return the root of the map.
+    pub(super) fn map_block(
+        &mut self,
+        root: Option<u64>,
+        block: u64,
+        sector_id: u64,
+    ) -> Result<u64> {
+        let (root_id, leaf_id, mut leaf) = self.map_path(root, block, sector_id)?;
+        leaf.set_entry((block % self.fan_out()) as usize, Some(sector_id));
+        self.write_sector(leaf_id, &Sector::BlockMap(leaf))?;
+        Ok(root_id)
+    }
+    /// Allocate a data sector for `block`, preferably from `goal` on, and map it in the map
+    /// rooted at `root`. Return the root of the map and the data sector.
+    pub(super) fn map_new_block(
+        &mut self,
+        root: Option<u64>,
+        block: u64,
+        goal: u64,
+    ) -> Result<(u64, u64)> {
+        //Nodes come first so that consecutive data sectors stay contiguous
+        let (root_id, leaf_id, mut leaf) = self.map_path(root, block, goal)?;
+        let sector_id = self.get_empty_sector_after(goal)?;
+        leaf.set_entry((block % self.fan_out()) as usize, Some(sector_id));
+        self.write_sector(leaf_id, &Sector::BlockMap(leaf))?;
+        Ok((root_id, sector_id))
+    }
+    /// Root of the map rooted at `root` once grown to cover `block`, with the leaf mapping
+    /// `block`. Missing nodes are allocated from `goal` on.
+    fn map_path(
+        &mut self,
+        root: Option<u64>,
+        block: u64,
+        goal: u64,
+    ) -> Result<(u64, u64, BlockMap)> {
+        let fan_out = self.fan_out();
+        let (mut root_id, mut root) = match root {
+            Some(root_id) => (root_id, self.read_block_map(root_id)?),
+            None => {
+                let mut depth = 0;
+                while block / self.entry_span(depth) >= fan_out {
+                    depth += 1;
+                }
+                let root_id = self.get_empty_sector_after(goal)?;
+                (root_id, BlockMap::new(depth, fan_out as usize))
+            }
+        };
+        //Grow the map from the root until it covers the block
+        while block / self.entry_span(root.depth()) >= fan_out {
+            let mut new_root = BlockMap::new(root.depth() + 1, fan_out as usize);
+            new_root.set_entry(0, Some(root_id));
+            root_id = self.get_empty_sector_after(goal)?;
+            self.write_sector(root_id, &Sector::BlockMap(new_root.clone()))?;
+            root = new_root;
+        }
+        let (mut node_id, mut node) = (root_id, root);
+        while node.depth() > 0 {
+            let idx = (block / self.entry_span(node.depth()) % fan_out) as usize;
+            match node.entry(idx) {
+                Some(child_id) => {
+                    node = self.read_block_map(child_id)?;
+                    node_id = child_id;
+                }
+                None => {
+                    let child_id = self.get_empty_sector_after(goal)?;
+                    node.set_entry(idx, Some(child_id));
+                    self.write_sector(node_id, &Sector::BlockMap(node.clone()))?;
+                    node = BlockMap::new(node.depth() - 1, fan_out as usize);
+                    node_id = child_id;
+                }
+            }
+        }
+        Ok((root_id, node_id, node))
+    }
+    /// Unmap `block` from the map rooted at `root` and free the nodes left empty, but not its
+    /// data sector. Return the root of the map, None once it maps nothing.
+    pub(super) fn unmap_block(&mut self, root: u64, block: u64) -> Result<Option<u64>> {
+        let fan_out = self.fan_out();
+        //Nodes from the root to the leaf, with the index of the entry leading to the block
+        let mut path = Vec::new();
+        let mut node_id = root;
+        loop {
+            let node = self.read_block_map(node_id)?;
+            let idx = block / self.entry_span(node.depth());
+            if path.is_empty() && idx >= fan_out {
+                //The block is past the range of the map
+                return Ok(Some(root));
+            }
+            let idx = (idx % fan_out) as usize;
+            let (depth, entry) = (node.depth(), node.entry(idx));
+            path.push((node_id, node, idx));
+            match entry {
+                None => return Ok(Some(root)),
+                Some(child_id) if depth > 0 => node_id = child_id,
+                Some(_) => break,
+            }
+        }
+        while let Some((node_id, mut node, idx)) = path.pop() {
+            node.set_entry(idx, None);
+            if !node.is_empty() {
+                self.write_sector(node_id, &Sector::BlockMap(node))?;
+                return Ok(Some(root));
+            }
+            self.free_sector(node_id)?;
+        }
+        Ok(None)
+    }
+    /// Move a regular file whose data is still a chain of `FileData` to a block map.
+    ///
+    /// The map is built over the sectors of the chain before the metadata points to it, so
+    /// that a crash in between only leaks the nodes.
+    pub(super) fn migrate_to_block_map(
+        &mut self,
+        metadata_sector_id: u64,
+        metadata_sector: &mut Sector,
+    ) -> Result<()> {
+        let Sector::FileMetadata(file_metadata) = metadata_sector else {
+            return Ok(());
+        };
+        let Some(first_sector) = file_metadata.first_sector() else {
+            return Ok(());
+        };
+        if self.is_block_map(first_sector)? {
+            return Ok(());
+        }
+        let mut root = None;
+        let mut next_sector = Some(first_sector);
+        let mut block = 0;
+        while let Some(sector_id) = next_sector {
+            let Sector::FileData(file_data) = self.read_sector(sector_id)? else {
+                bail!("Sector {sector_id} is not a FileData.");
+            };
+            root = Some(self.map_block(root, block, sector_id)?);
+            self.checkpoint()?;
+            next_sector = file_data.next();
+            block += 1;
+        }
+        if let Some(root) = root {
+            file_metadata.set_first_sector(root);
+        }
+        self.write_sector(metadata_sector_id, metadata_sector)?;
+        Ok(())
+    }
+}
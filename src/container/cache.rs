@@ -0,0 +1,82 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::sector::Sector;
+
+/// Memory used by the sector cache unless another size is chosen
+pub const DEFAULT_CACHE_SIZE: usize = 4 << 20;
+
+/// Decoded sectors of the container, the least recently used being evicted first.
+///
+/// Sectors are cached once read and verified, so that reading them again needs neither I/O
+/// nor decoding. Only the state written back or waiting to be is cached: sectors changed by
+/// the current transaction are not.
+pub struct SectorCache {
+    /// Maximum number of sectors kept
+    capacity: usize,
+    /// Cached sectors, with the tick of their last use
+    sectors: HashMap<u64, (Sector, u64)>,
+    /// Cached sectors by tick of last use
+    uses: BTreeMap<u64, u64>,
+    tick: u64,
+}
+impl SectorCache {
+    /// Cache of about `size` bytes of sectors of `sector_size` bytes.
+    pub fn new(size: usize, sector_size: usize) -> Self {
+        Self {
+            capacity: size / sector_size,
+            sectors: HashMap::new(),
+            uses: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+    /// Maximum number of sectors kept
+    pub const fn capacity(&self) -> usize {
+        self.capacity
+    }
+    /// Change the memory used to about `size` bytes, evicting sectors if needed.
+    pub fn resize(&mut self, size: usize, sector_size: usize) {
+        self.capacity = size / sector_size;
+        self.evict();
+    }
+    pub fn get(&mut self, sector_id: u64) -> Option<Sector> {
+        self.tick += 1;
+        let (sector, last_use) = self.sectors.get_mut(&sector_id)?;
+        self.uses.remove(last_use);
+        self.uses.insert(self.tick, sector_id);
+        *last_use = self.tick;
+        Some(sector.clone())
+    }
+    pub fn insert(&mut self, sector_id: u64, sector: Sector) {
+        self.tick += 1;
+        if let Some((_sector, last_use)) = self.sectors.insert(sector_id, (sector, self.tick)) {
+            self.uses.remove(&last_use);
+        }
+        self.uses.insert(self.tick, sector_id);
+        self.evict();
+    }
+    pub fn remove(&mut self, sector_id: u64) {
+        if let Some((_sector, last_use)) = self.sectors.remove(&sector_id) {
+            self.uses.remove(&last_use);
+        }
+    }
+    /// Forget the sectors from `sector_count` on, past the end of a shrunk container.
+    pub fn truncate(&mut self, sector_count: u64) {
+        let stale: Vec<u64> = self
+            .sectors
+            .keys()
+            .copied()
+            .filter(|sector_id| *sector_id >= sector_count)
+            .collect();
+        for sector_id in stale {
+            self.remove(sector_id);
+        }
+    }
+    fn evict(&mut self) {
+        while self.sectors.len() > self.capacity {
+            let Some((_tick, sector_id)) = self.uses.pop_first() else {
+                break;
+            };
+            self.sectors.remove(&sector_id);
+        }
+    }
+}
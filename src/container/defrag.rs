@@ -0,0 +1,406 @@
+use anyhow::{bail, Result};
+use fuser::FUSE_ROOT_ID;
+use std::collections::{BTreeSet, HashMap};
+
+use super::Container;
+use crate::error::FsError;
+use crate::sector::Sector;
+
+/// Outcome of `Container::defrag`
+#[derive(Debug, Default)]
+pub struct DefragReport {
+    /// Sectors moved, some of them twice to make room
+    pub moved: u64,
+    /// Size of the container file before and after
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+/// What points to a sector, the sectors holding the pointer being given by their slot
+#[derive(Debug, Clone, Copy)]
+enum Pointer {
+    /// The root directory in the container metadata
+    Root,
+    /// The first sector of the inode table in the container metadata
+    InodeTable,
+    /// The first sector of the refcount table in the superblock
+    RefcountTable,
+    /// The entry of an inode in the inode table
+    Inode(u64),
+    /// The next sector of the previous sector of a chain
+    Next(usize),
+    /// The data, entries or block map of a metadata sector
+    First(usize),
+    /// The extended attributes of a metadata sector
+    Xattrs(usize),
+    /// An entry of a block map node
+    Entry(usize, usize),
+}
+
+/// Sectors in use, in the order they are laid out once defragmented
+#[derive(Default)]
+struct Layout {
+    /// Current place of each sector
+    sectors: Vec<u64>,
+    /// What points to each sector
+    pointers: Vec<Pointer>,
+    /// Sector of a chain whose previous sector is this one
+    followers: Vec<Option<usize>>,
+    /// Slot of each sector in use
+    slots: HashMap<u64, usize>,
+}
+impl Layout {
+    fn push(&mut self, sector_id: u64, pointer: Pointer) -> Result<usize> {
+        let slot = self.sectors.len();
+        if self.slots.insert(sector_id, slot).is_some() {
+            bail!("Sector {sector_id} is used twice, the container needs to be checked.");
+        }
+        self.sectors.push(sector_id);
+        self.pointers.push(pointer);
+        self.followers.push(None);
+        Ok(slot)
+    }
+    fn relocated(&mut self, slot: usize, target: u64) {
+        self.slots.remove(&self.sectors[slot]);
+        self.slots.insert(target, slot);
+        self.sectors[slot] = target;
+    }
+}
+
+impl Container {
+    /// Move the sectors so that the inode table, then every inode with its data, entries and
+    /// extended attributes, is contiguous, and shrink the container to the sectors in use.
+    ///
+    /// Each move is a transaction, a crash in between leaves a consistent container: without
+    /// bitmaps, the sectors taken are unlinked from the free list and the sectors released are
+    /// pushed back onto it. Sectors used by nobody are left in place until `fsck --repair` frees
+    /// them, and so are the sectors shared by several files.
+    pub fn defrag(&mut self) -> Result<DefragReport> {
+        self.flush()?;
+        let size_before = self.file.metadata()?.len();
+        let mut layout = self.defrag_layout()?;
+        let mut free = self.free_sector_set()?;
+        let mut moved = 0;
+        let mut position = 0;
+        for slot in 0..layout.sectors.len() {
+            while self.is_defrag_reserved(position, &layout, &free) {
+                position += 1;
+            }
+            if layout.sectors[slot] != position {
+                if let Some(&other) = layout.slots.get(&position) {
+                    //Make room for the sector
+                    let target = self.transaction(|container| {
+                        let target = container.take_sector_after(position, &mut free)?;
+                        container.relocate(&layout, other, target, &mut free)?;
+                        Ok(target)
+                    })?;
+                    layout.relocated(other, target);
+                    moved += 1;
+                }
+                self.transaction(|container| {
+                    container.take_sector(position, &mut free)?;
+                    container.relocate(&layout, slot, position, &mut free)
+                })?;
+                layout.relocated(slot, position);
+                moved += 1;
+            }
+            position += 1;
+        }
+        self.shrink(&free)?;
+        Ok(DefragReport {
+            moved,
+            size_before,
+            size_after: self.file.metadata()?.len(),
+        })
+    }
+    /// Sectors in use and what points to them, in the order they are laid out.
+    fn defrag_layout(&mut self) -> Result<Layout> {
+        let mut layout = Layout::default();
+        let mut inodes = vec![(FUSE_ROOT_ID, self.metadata.root_dir_sector, Pointer::Root)];
+        inodes.extend(
+            self.inodes
+                .iter()
+                .enumerate()
+                .filter_map(|(ino, sector)| sector.map(|sector| (ino as u64, sector)))
+                .map(|(ino, sector_id)| (ino, sector_id, Pointer::Inode(ino))),
+        );
+        self.layout_chain(
+            &mut layout,
+            self.metadata.inode_table,
+            Pointer::InodeTable,
+            false,
+        )?;
+        self.layout_chain(
+            &mut layout,
+            self.superblock.refcount_table(),
+            Pointer::RefcountTable,
+            false,
+        )?;
+        for (ino, sector_id, pointer) in inodes {
+            let sector = self.read_sector(sector_id)?;
+            let Some(metadata) = sector.metadata() else {
+                bail!("Inode {ino} has no metadata in sector {sector_id}.");
+            };
+            let slot = layout.push(sector_id, pointer)?;
+            match metadata.first_sector() {
+                Some(root)
+                    if matches!(sector, Sector::FileMetadata(_)) && self.is_block_map(root)? =>
+                {
+                    self.layout_block_map(&mut layout, root, slot)?;
+                }
+                first_sector => {
+                    self.layout_chain(&mut layout, first_sector, Pointer::First(slot), true)?;
+                }
+            }
+            self.layout_chain(
+                &mut layout,
+                metadata.xattr_sector(),
+                Pointer::Xattrs(slot),
+                false,
+            )?;
+        }
+        Ok(layout)
+    }
+    /// Add the chain starting at `first_sector`, whose sectors point back to the previous one
+    /// if `linked` is set.
+    fn layout_chain(
+        &mut self,
+        layout: &mut Layout,
+        first_sector: Option<u64>,
+        pointer: Pointer,
+        linked: bool,
+    ) -> Result<()> {
+        let mut next_sector = first_sector;
+        let mut pointer = pointer;
+        let mut previous = None;
+        while let Some(sector_id) = next_sector {
+            let slot = layout.push(sector_id, pointer)?;
+            if let Some(previous) = previous.filter(|_| linked) {
+                layout.followers[previous] = Some(slot);
+            }
+            next_sector = match self.read_sector(sector_id)? {
+                Sector::FileData(file_data) => file_data.next(),
+                Sector::DirData(dir_data) => dir_data.next_sector(),
+                Sector::XattrData(xattr_data) => xattr_data.next_sector(),
+                Sector::InodeTable(table) => table.next_sector(),
+                Sector::RefcountTable(table) => table.next_sector(),
+                _ => bail!("Sector {sector_id} is not part of a chain."),
+            };
+            pointer = Pointer::Next(slot);
+            previous = Some(slot);
+        }
+        Ok(())
+    }
+    /// Add the nodes of the block map rooted at `root`, then its data in block order, except
+    /// the shared sectors.
+    fn layout_block_map(&mut self, layout: &mut Layout, root: u64, metadata: usize) -> Result<()> {
+        let mut data = Vec::new();
+        let mut nodes = vec![(root, Pointer::First(metadata))];
+        while let Some((node_id, pointer)) = nodes.pop() {
+            let slot = layout.push(node_id, pointer)?;
+            let node = self.read_block_map(node_id)?;
+            let mut children = Vec::new();
+            for (idx, entry) in node.entries().iter().enumerate() {
+                let Some(sector_id) = *entry else {
+                    continue;
+                };
+                if node.depth() == 0 {
+                    if self.refcount(sector_id) == 0 {
+                        data.push((sector_id, Pointer::Entry(slot, idx)));
+                    }
+                } else {
+                    children.push((sector_id, Pointer::Entry(slot, idx)));
+                }
+            }
+            nodes.extend(children.into_iter().rev());
+        }
+        for (sector_id, pointer) in data {
+            layout.push(sector_id, pointer)?;
+        }
+        Ok(())
+    }
+    /// Free sectors, from the bitmaps or the free list.
+    fn free_sector_set(&mut self) -> Result<BTreeSet<u64>> {
+        let mut free = BTreeSet::new();
+        let sector_count = self.metadata.sector_count;
+        if let Some(group_size) = self.superblock.group_size() {
+            for group_start in (0..sector_count).step_by(group_size as usize) {
+                let Sector::Bitmap(bitmap) = self.read_sector(group_start)? else {
+                    bail!("Sector {group_start} is not a bitmap.");
+                };
+                let end = (sector_count - group_start).min(group_size);
+                free.extend(
+                    (1..end)
+                        .filter(|idx| !bitmap.is_used(*idx as usize))
+                        .map(|idx| group_start + idx),
+                );
+            }
+            return Ok(free);
+        }
+        let mut next_sector = self.metadata.first_empty_sector;
+        while let Some(sector_id) = next_sector {
+            let Sector::Empty(empty_sector) = self.read_sector(sector_id)? else {
+                bail!("Sector {sector_id} of the free list is not empty.");
+            };
+            free.insert(sector_id);
+            if Some(sector_id) == self.metadata.last_empty_sector {
+                break;
+            }
+            next_sector = empty_sector.next();
+        }
+        Ok(free)
+    }
+    /// Whether `position` keeps its sector: bitmaps and sectors used by nobody.
+    fn is_defrag_reserved(&self, position: u64, layout: &Layout, free: &BTreeSet<u64>) -> bool {
+        if position >= self.metadata.sector_count {
+            return false;
+        }
+        let bitmap = self
+            .superblock
+            .group_size()
+            .is_some_and(|group_size| position % group_size == 0);
+        bitmap || !(free.contains(&position) || layout.slots.contains_key(&position))
+    }
+    /// Allocate the free sector `sector_id`.
+    fn take_sector(&mut self, sector_id: u64, free: &mut BTreeSet<u64>) -> Result<()> {
+        if !free.remove(&sector_id) {
+            bail!("Sector {sector_id} is not free.");
+        }
+        let Some(group_size) = self.superblock.group_size() else {
+            return self.unlink_free_sector(sector_id);
+        };
+        let group_start = sector_id / group_size * group_size;
+        let Sector::Bitmap(mut bitmap) = self.read_sector(group_start)? else {
+            bail!("Sector {group_start} is not a bitmap.");
+        };
+        bitmap.set_used((sector_id - group_start) as usize, true);
+        self.write_sector(group_start, &Sector::Bitmap(bitmap))?;
+        self.group_free[(sector_id / group_size) as usize] -= 1;
+        self.free_sectors = self.free_sectors.saturating_sub(1);
+        Ok(())
+    }
+    /// Allocate the first free sector after `position`, growing the container if there is none.
+    fn take_sector_after(&mut self, position: u64, free: &mut BTreeSet<u64>) -> Result<u64> {
+        if let Some(&sector_id) = free.range(position + 1..).next() {
+            self.take_sector(sector_id, free)?;
+            return Ok(sector_id);
+        }
+        if let Some(group_size) = self.superblock.group_size() {
+            return self.grow_sector(group_size);
+        }
+        if self.available_sectors() == 0 {
+            bail!(FsError::NoSpace);
+        }
+        self.metadata.sector_count += 1;
+        self.write_metadata()?;
+        Ok(self.metadata.sector_count - 1)
+    }
+    /// Copy the sector of `slot` to the allocated sector `target`, point everything that
+    /// referenced it to `target` and free it.
+    fn relocate(
+        &mut self,
+        layout: &Layout,
+        slot: usize,
+        target: u64,
+        free: &mut BTreeSet<u64>,
+    ) -> Result<()> {
+        let sector_id = layout.sectors[slot];
+        let sector = self.read_sector(sector_id)?;
+        self.write_sector(target, &sector)?;
+        if let Some(follower) = layout.followers[slot] {
+            let follower_id = layout.sectors[follower];
+            let mut follower_sector = self.read_sector(follower_id)?;
+            match &mut follower_sector {
+                Sector::FileData(file_data) => file_data.set_previous(target),
+                Sector::DirData(dir_data) => dir_data.set_previous(target),
+                _ => bail!("Sector {follower_id} has no previous sector."),
+            }
+            self.write_sector(follower_id, &follower_sector)?;
+        }
+        if let Some(table_sector) = self.inode_table.iter_mut().find(|id| **id == sector_id) {
+            *table_sector = target;
+        }
+        if let Some(table_sector) = self.refcount_table.iter_mut().find(|id| **id == sector_id) {
+            *table_sector = target;
+        }
+        let pointer = layout.pointers[slot];
+        match pointer {
+            Pointer::Root => {
+                self.metadata.root_dir_sector = target;
+                self.write_metadata()?;
+            }
+            Pointer::InodeTable => {
+                self.metadata.inode_table = Some(target);
+                self.write_metadata()?;
+            }
+            Pointer::RefcountTable => {
+                self.superblock.set_refcount_table(target);
+                self.write_superblock()?;
+            }
+            Pointer::Inode(ino) => self.set_inode_sector(ino, Some(target))?,
+            Pointer::Next(holder) => {
+                let holder_id = layout.sectors[holder];
+                let mut holder_sector = self.read_sector(holder_id)?;
+                match &mut holder_sector {
+                    Sector::FileData(file_data) => file_data.set_next(target),
+                    Sector::DirData(dir_data) => dir_data.set_next(target),
+                    Sector::XattrData(xattr_data) => xattr_data.set_next(Some(target)),
+                    Sector::InodeTable(table) => table.set_next(target),
+                    Sector::RefcountTable(table) => table.set_next(target),
+                    _ => bail!("Sector {holder_id} is not part of a chain."),
+                }
+                self.write_sector(holder_id, &holder_sector)?;
+            }
+            Pointer::First(holder) | Pointer::Xattrs(holder) => {
+                let holder_id = layout.sectors[holder];
+                let mut holder_sector = self.read_sector(holder_id)?;
+                let Some(metadata) = holder_sector.metadata_mut() else {
+                    bail!("Sector {holder_id} is not a metadata sector.");
+                };
+                if matches!(pointer, Pointer::First(_)) {
+                    metadata.set_first_sector(target);
+                } else {
+                    metadata.set_xattr_sector(Some(target));
+                }
+                self.write_sector(holder_id, &holder_sector)?;
+            }
+            Pointer::Entry(holder, idx) => {
+                let holder_id = layout.sectors[holder];
+                let mut node = self.read_block_map(holder_id)?;
+                node.set_entry(idx, Some(target));
+                self.write_sector(holder_id, &Sector::BlockMap(node))?;
+            }
+        }
+        free.insert(sector_id);
+        self.free_sector(sector_id)
+    }
+    /// Drop the free sectors at the end of the container and truncate its file.
+    fn shrink(&mut self, free: &BTreeSet<u64>) -> Result<()> {
+        let group_size = self.superblock.group_size();
+        let is_bitmap = |sector_id: u64| group_size.is_some_and(|size| sector_id % size == 0);
+        let sector_count = (0..self.metadata.sector_count)
+            .rev()
+            .find(|sector_id| !free.contains(sector_id) && !is_bitmap(*sector_id))
+            .map_or(0, |sector_id| sector_id + 1);
+        if group_size.is_none() {
+            //From the end, so that the sectors left past the count are never on the free list
+            for &sector_id in free.range(sector_count..).rev() {
+                self.transaction(|container| {
+                    container.unlink_free_sector(sector_id)?;
+                    container.metadata.sector_count = sector_id;
+                    container.write_metadata()
+                })?;
+            }
+        }
+        self.transaction(|container| {
+            container.metadata.sector_count = sector_count;
+            container.write_metadata()
+        })?;
+        self.flush()?;
+        let size = self.superblock.sectors_offset() + sector_count * self.sector_size() as u64;
+        self.file.set_len(size)?;
+        self.file.sync_data()?;
+        self.cache.truncate(sector_count);
+        self.count_free_sectors()
+    }
+}
@@ -0,0 +1,892 @@
+use anyhow::{bail, Result};
+use fuser::FUSE_ROOT_ID;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::Path;
+
+use super::Container;
+use crate::sector::{self, DirEntry, Empty, Permissions, Sector};
+
+/// Directory of the root receiving the orphaned inodes
+const LOST_FOUND: &str = "lost+found";
+
+/// Outcome of `Container::fsck`
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// Problems found, in the order they were found
+    pub problems: Vec<String>,
+    /// Whether the problems were repaired
+    pub repaired: bool,
+}
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// What references a sector
+#[derive(Debug, Clone, Copy)]
+enum Owner {
+    Inode(u64),
+    InodeTable,
+    RefcountTable,
+    Bitmap,
+    FreeList,
+}
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Inode(ino) => write!(f, "inode {ino}"),
+            Self::InodeTable => write!(f, "the inode table"),
+            Self::RefcountTable => write!(f, "the refcount table"),
+            Self::Bitmap => write!(f, "the free space bitmap"),
+            Self::FreeList => write!(f, "the free list"),
+        }
+    }
+}
+
+/// Chains of sectors hanging from an inode
+#[derive(Debug, Clone, Copy)]
+enum Chain {
+    Data,
+    Entries,
+    Xattrs,
+}
+impl Chain {
+    /// Previous and next sectors of `sector`, None if it does not belong to this kind of chain.
+    /// Extended attributes chains have no previous sectors.
+    const fn links(self, sector: &Sector) -> Option<(Option<u64>, Option<u64>)> {
+        match (self, sector) {
+            (Self::Data, Sector::FileData(file_data)) => {
+                Some((file_data.previous(), file_data.next()))
+            }
+            (Self::Entries, Sector::DirData(dir_data)) => {
+                Some((dir_data.previous_sector(), dir_data.next_sector()))
+            }
+            (Self::Xattrs, Sector::XattrData(xattr_data)) => Some((None, xattr_data.next_sector())),
+            _ => None,
+        }
+    }
+}
+impl fmt::Display for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::Data => "data",
+            Self::Entries => "directory",
+            Self::Xattrs => "extended attributes",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// State of a check, filled while walking the container
+struct Check {
+    repair: bool,
+    problems: Vec<String>,
+    /// Owner of each sector reached so far
+    owners: Vec<Option<Owner>>,
+    /// Sectors failing their checksum
+    unreadable: BTreeSet<u64>,
+    /// Metadata sector of each inode, found by scanning every sector
+    metadata_sectors: BTreeMap<u64, u64>,
+    /// Inodes reached from the root or linked in lost+found
+    reached: BTreeSet<u64>,
+    /// Number of entries naming each inode
+    names: BTreeMap<u64, u32>,
+    /// Number of subdirectories of each directory
+    subdirs: BTreeMap<u64, u32>,
+    /// Number of block map entries found for each shared sector
+    references: BTreeMap<u64, u32>,
+}
+impl Check {
+    fn problem(&mut self, problem: String) {
+        self.problems.push(problem);
+    }
+}
+
+impl Container {
+    /// Check the consistency of an existing container, and repair it if `repair` is set.
+    ///
+    /// Every chain and block map hanging from the root is walked, then the free list or the bitmaps.
+    /// Sectors referenced more often than counted or by nobody are reported. Repairing cuts
+    /// broken chains, fixes lengths, link counts and reference counts, rebuilds the free space
+    /// and the inode table, and moves orphaned inodes into `lost+found`.
+    pub fn fsck(container_name: &str, repair: bool) -> Result<FsckReport> {
+        if !Path::new(container_name).exists() {
+            bail!("The file {container_name} does not exist.");
+        }
+        let mut container = Self::open(container_name.to_string())?;
+        let mut check = Check {
+            repair,
+            problems: Vec::new(),
+            owners: vec![None; container.metadata.sector_count as usize],
+            unreadable: BTreeSet::new(),
+            metadata_sectors: BTreeMap::new(),
+            reached: BTreeSet::new(),
+            names: BTreeMap::new(),
+            subdirs: BTreeMap::new(),
+            references: BTreeMap::new(),
+        };
+        container.fsck_load_inode_table(&mut check);
+        container.fsck_scan(&mut check)?;
+        container.fsck_inode_table(&mut check)?;
+        container.fsck_refcount_table(&mut check)?;
+        container.fsck_tree(&mut check)?;
+        let lost = container.fsck_orphans(&mut check)?;
+        container.fsck_refcounts(&mut check)?;
+        let group_size = container.superblock.group_size();
+        let free_space_ok = match group_size {
+            Some(group_size) => container.fsck_bitmaps(&mut check, group_size)?,
+            None => container.fsck_free_list(&mut check)?,
+        };
+        if repair {
+            match group_size {
+                Some(group_size) if !free_space_ok => {
+                    container.rebuild_bitmaps(&check, group_size)?;
+                }
+                None if !free_space_ok => container.rebuild_free_list(&check)?,
+                _ => {}
+            }
+            container.repair_inode_table(&mut check)?;
+            container.link_lost(&mut check, &lost)?;
+        }
+        container.fsck_links(&mut check)?;
+        Ok(FsckReport {
+            repaired: repair && !check.problems.is_empty(),
+            problems: check.problems,
+        })
+    }
+    /// Read every sector once, noting corrupted ones and where each inode lives.
+    fn fsck_scan(&mut self, check: &mut Check) -> Result<()> {
+        for sector_id in 0..self.metadata.sector_count {
+            let sector = match self.read_sector(sector_id) {
+                Ok(sector) => sector,
+                Err(err) => {
+                    check.problem(format!("Sector {sector_id} is unreadable: {err}"));
+                    check.unreadable.insert(sector_id);
+                    continue;
+                }
+            };
+            let Some(metadata) = sector.metadata() else {
+                continue;
+            };
+            let ino = metadata.ino();
+            if let Some(first_sector_id) = check.metadata_sectors.get(&ino) {
+                check.problem(format!(
+                    "Inode {ino} is stored in sectors {first_sector_id} and {sector_id}"
+                ));
+                continue;
+            }
+            check.metadata_sectors.insert(ino, sector_id);
+        }
+        match check.metadata_sectors.get(&FUSE_ROOT_ID) {
+            Some(&sector_id) if sector_id == self.metadata.root_dir_sector => Ok(()),
+            _ => bail!("The root directory is lost, the container cannot be checked."),
+        }
+    }
+    /// Read the inode table before the scan, which reports the inode of corrupted sectors.
+    fn fsck_load_inode_table(&mut self, check: &mut Check) {
+        //Containers from before the inode table get one built when opened
+        if self.metadata.inode_table.is_none() {
+            return;
+        }
+        if let Err(err) = self.load_inode_table() {
+            check.problem(format!("The inode table is unreadable: {err}"));
+            //Rebuilt from scratch when repairing
+            self.inode_table.clear();
+            self.inodes.clear();
+            self.metadata.inode_table = None;
+        }
+    }
+    /// Mark the sectors of the inode table and compare it with the inodes found.
+    fn fsck_inode_table(&mut self, check: &mut Check) -> Result<()> {
+        if self.metadata.inode_table.is_none() {
+            return Ok(());
+        }
+        for sector_id in self.inode_table.clone() {
+            if let Some(owner) = check.owners[sector_id as usize] {
+                check.problem(format!(
+                    "The inode table shares sector {sector_id} with {owner}"
+                ));
+            }
+            check.owners[sector_id as usize] = Some(Owner::InodeTable);
+        }
+        let inode_count = self.inodes.len().max(
+            check
+                .metadata_sectors
+                .keys()
+                .last()
+                .map_or(0, |ino| *ino as usize + 1),
+        );
+        for ino in 0..inode_count as u64 {
+            if ino == FUSE_ROOT_ID {
+                continue;
+            }
+            let mapped = self.inodes.get(ino as usize).copied().flatten();
+            let found = check.metadata_sectors.get(&ino).copied();
+            match (mapped, found) {
+                (Some(mapped), Some(found)) if mapped != found => check.problem(format!(
+                    "The inode table maps inode {ino} to sector {mapped} instead of {found}"
+                )),
+                (Some(mapped), None) => check.problem(format!(
+                    "The inode table maps inode {ino} to sector {mapped} which does not hold it"
+                )),
+                (None, Some(found)) => check.problem(format!(
+                    "The inode table misses inode {ino} stored in sector {found}"
+                )),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+    /// Mark the sectors of the refcount table.
+    fn fsck_refcount_table(&mut self, check: &mut Check) -> Result<()> {
+        if let Err(err) = self.load_refcounts() {
+            check.problem(format!("The refcount table is unreadable: {err}"));
+            //Shared sectors are then reported as used twice
+            self.refcounts.clear();
+        }
+        //The superblock still points to the sectors read
+        for sector_id in self.refcount_table.clone() {
+            if let Some(owner) = check.owners[sector_id as usize] {
+                check.problem(format!(
+                    "The refcount table shares sector {sector_id} with {owner}"
+                ));
+            }
+            check.owners[sector_id as usize] = Some(Owner::RefcountTable);
+        }
+        Ok(())
+    }
+    /// Compare the references counted for every shared sector with the block map entries
+    /// found.
+    fn fsck_refcounts(&mut self, check: &mut Check) -> Result<()> {
+        for (sector_id, count) in self.refcounts.clone() {
+            let found = check.references.get(&sector_id).copied().unwrap_or(0);
+            if count + 1 != found {
+                check.problem(format!(
+                    "Sector {sector_id} has {} references, {found} found",
+                    count + 1
+                ));
+                if check.repair {
+                    self.set_refcount(sector_id, found.saturating_sub(1))?;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Walk every inode reachable from the root.
+    fn fsck_tree(&mut self, check: &mut Check) -> Result<()> {
+        check.reached.insert(FUSE_ROOT_ID);
+        self.fsck_walk(check, FUSE_ROOT_ID)
+    }
+    /// Check `ino` and everything reachable from it, breadth first.
+    fn fsck_walk(&mut self, check: &mut Check, ino: u64) -> Result<()> {
+        let mut queue = VecDeque::from([ino]);
+        while let Some(ino) = queue.pop_front() {
+            for (sector_id, idx, entry) in self.fsck_inode(check, ino)? {
+                let Some(&entry_sector_id) = check.metadata_sectors.get(&entry.ino) else {
+                    check.problem(format!(
+                        "Directory {ino} has an entry {} for the missing inode {}",
+                        entry.name, entry.ino
+                    ));
+                    if check.repair {
+                        self.clear_entry(sector_id, idx)?;
+                    }
+                    continue;
+                };
+                let filetype = match self.read_sector(entry_sector_id) {
+                    Ok(sector) => filetype(&sector),
+                    Err(err) => {
+                        check.problem(format!(
+                            "Directory {ino} has an entry {} for inode {} in the unreadable sector {entry_sector_id}: {err}",
+                            entry.name, entry.ino
+                        ));
+                        //Forgotten like the inodes of the sectors found unreadable by the scan
+                        check.metadata_sectors.remove(&entry.ino);
+                        check.unreadable.insert(entry_sector_id);
+                        if check.repair {
+                            self.clear_entry(sector_id, idx)?;
+                        }
+                        continue;
+                    }
+                };
+                if entry.filetype != filetype {
+                    check.problem(format!(
+                        "Directory {ino} has an entry {} of type {:?} for inode {} of type {filetype:?}",
+                        entry.name, entry.filetype, entry.ino
+                    ));
+                    if check.repair {
+                        self.set_entry_target(sector_id, idx, entry.ino, filetype)?;
+                    }
+                }
+                if filetype == sector::FileType::Directory {
+                    if check.reached.contains(&entry.ino) {
+                        check.problem(format!(
+                            "Directory {} has another name {} in directory {ino}",
+                            entry.ino, entry.name
+                        ));
+                        if check.repair {
+                            self.clear_entry(sector_id, idx)?;
+                        }
+                        continue;
+                    }
+                    *check.subdirs.entry(ino).or_default() += 1;
+                }
+                *check.names.entry(entry.ino).or_default() += 1;
+                if check.reached.insert(entry.ino) {
+                    queue.push_back(entry.ino);
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Check the chains of `ino` and return its directory entries with their location.
+    fn fsck_inode(&mut self, check: &mut Check, ino: u64) -> Result<Vec<(u64, usize, DirEntry)>> {
+        let sector_id = check.metadata_sectors[&ino];
+        if let Some(owner) = check.owners[sector_id as usize] {
+            check.problem(format!(
+                "Inode {ino} shares sector {sector_id} with {owner}"
+            ));
+        }
+        check.owners[sector_id as usize] = Some(Owner::Inode(ino));
+        let mut sector = self.read_sector(sector_id)?;
+        let inline_symlink = matches!(
+            &sector,
+            Sector::SymlinkMetadata(symlink_metadata) if !symlink_metadata.inline_target().is_empty()
+        );
+        let Some(metadata) = sector.metadata() else {
+            bail!("Sector {sector_id} is not a metadata sector.");
+        };
+        let (first_sector, xattr_sector) = (metadata.first_sector(), metadata.xattr_sector());
+        let mut changed = false;
+
+        let (xattr_chain, cut) = self.fsck_chain(check, ino, Chain::Xattrs, xattr_sector)?;
+        if cut && xattr_chain.is_empty() {
+            if let Some(metadata) = sector.metadata_mut() {
+                metadata.set_xattr_sector(None);
+                changed = true;
+            }
+        }
+
+        let mut entries = Vec::new();
+        if let Sector::DirMetadata(_) = &sector {
+            let (chain, cut) = self.fsck_chain(check, ino, Chain::Entries, first_sector)?;
+            if cut && chain.is_empty() {
+                if let Some(metadata) = sector.metadata_mut() {
+                    metadata.clear_first_sector();
+                    changed = true;
+                }
+            }
+            for sector_id in chain {
+                let Sector::DirData(dir_data) = self.read_sector(sector_id)? else {
+                    bail!("Sector {sector_id} is not DirData");
+                };
+                for (idx, entry) in dir_data.entries().iter().enumerate() {
+                    if !entry.empty {
+                        entries.push((sector_id, idx, entry.clone()));
+                    }
+                }
+            }
+        } else if !inline_symlink {
+            let root = first_sector.filter(|root| {
+                matches!(sector, Sector::FileMetadata(_))
+                    && self.unusable_sector(check, *root).is_none()
+                    && matches!(self.read_sector(*root), Ok(Sector::BlockMap(_)))
+            });
+            let (blocks, cut) = match root {
+                Some(root) => (self.fsck_block_map(check, ino, root)?, false),
+                None => {
+                    let (chain, cut) = self.fsck_chain(check, ino, Chain::Data, first_sector)?;
+                    (
+                        chain
+                            .into_iter()
+                            .zip(0..)
+                            .map(|(id, block)| (block, id))
+                            .collect(),
+                        cut,
+                    )
+                }
+            };
+            //A regular file without data sectors is a hole, a symlink always has its chain
+            let is_chain = root.is_none()
+                && (first_sector.is_some() || !matches!(sector, Sector::FileMetadata(_)));
+            let Some(metadata) = sector.metadata_mut() else {
+                bail!("Sector {sector_id} is not a metadata sector.");
+            };
+            if cut && blocks.is_empty() {
+                metadata.clear_first_sector();
+                changed = true;
+            }
+            let chain_length = blocks.len() as u64;
+            if metadata.length_sector() != chain_length {
+                check.problem(format!(
+                    "Inode {ino} has {} data sectors, {chain_length} found",
+                    metadata.length_sector()
+                ));
+                metadata.set_length_sector(chain_length);
+                changed = true;
+            }
+            let chunk_size = self.chunk_size() as u64;
+            //Unmapped blocks of a block map read as zeros
+            let capacity = chain_length * chunk_size;
+            if is_chain && metadata.length_byte() > capacity {
+                check.problem(format!(
+                    "Inode {ino} holds {} bytes but its data sectors only {capacity}",
+                    metadata.length_byte()
+                ));
+                metadata.set_length_byte(capacity);
+                changed = true;
+            }
+            let length_byte = metadata.length_byte();
+            for (block, data_sector_id) in blocks {
+                let expected = length_byte
+                    .saturating_sub(block * chunk_size)
+                    .min(chunk_size);
+                let mut data_sector = self.read_sector(data_sector_id)?;
+                let Sector::FileData(file_data) = &mut data_sector else {
+                    bail!("Sector {data_sector_id} is not FileData");
+                };
+                if file_data.data_length() != expected {
+                    check.problem(format!(
+                        "Sector {data_sector_id} of inode {ino} holds {} bytes instead of {expected}",
+                        file_data.data_length()
+                    ));
+                    if check.repair {
+                        file_data.set_data_length(expected);
+                        self.write_sector(data_sector_id, &data_sector)?;
+                    }
+                }
+            }
+        }
+        if changed && check.repair {
+            self.write_sector(sector_id, &sector)?;
+        }
+        Ok(entries)
+    }
+    /// Walk a chain of `ino` and return its valid sectors, and whether it had to be cut.
+    ///
+    /// The chain ends before the first sector out of bounds, unreadable, of the wrong type or
+    /// already used elsewhere. When repairing, the last valid sector becomes its end.
+    fn fsck_chain(
+        &mut self,
+        check: &mut Check,
+        ino: u64,
+        chain: Chain,
+        first_sector: Option<u64>,
+    ) -> Result<(Vec<u64>, bool)> {
+        let mut sectors: Vec<u64> = Vec::new();
+        let mut next_sector = first_sector;
+        while let Some(sector_id) = next_sector {
+            if let Some(problem) = self.unusable_sector(check, sector_id) {
+                self.broken_chain(check, ino, chain, &problem, sectors.last().copied())?;
+                return Ok((sectors, true));
+            }
+            let mut sector = self.read_sector(sector_id)?;
+            let Some((previous, next)) = chain.links(&sector) else {
+                let problem = format!("goes through sector {sector_id} of the wrong type");
+                self.broken_chain(check, ino, chain, &problem, sectors.last().copied())?;
+                return Ok((sectors, true));
+            };
+            check.owners[sector_id as usize] = Some(Owner::Inode(ino));
+            //Extended attributes chains only go forward
+            let previous_id = sectors.last().copied();
+            if previous_id.is_some() && previous != previous_id && !matches!(chain, Chain::Xattrs) {
+                check.problem(format!(
+                    "Sector {sector_id} of inode {ino} does not point back to sector {}",
+                    previous_id.unwrap_or_default()
+                ));
+                if check.repair {
+                    match &mut sector {
+                        Sector::FileData(file_data) => {
+                            file_data.set_previous(previous_id.unwrap_or_default())
+                        }
+                        Sector::DirData(dir_data) => {
+                            dir_data.set_previous(previous_id.unwrap_or_default())
+                        }
+                        _ => {}
+                    }
+                    self.write_sector(sector_id, &sector)?;
+                }
+            }
+            sectors.push(sector_id);
+            next_sector = next;
+        }
+        Ok((sectors, false))
+    }
+    /// Walk the block map of `ino` rooted at `root` and return its data sectors with their
+    /// block, in block order.
+    ///
+    /// Entries pointing to a sector out of bounds, unreadable, of the wrong type or already
+    /// used elsewhere are reported, and unmapped when repairing. Shared sectors can be
+    /// reached as many times as they are counted.
+    fn fsck_block_map(
+        &mut self,
+        check: &mut Check,
+        ino: u64,
+        root: u64,
+    ) -> Result<Vec<(u64, u64)>> {
+        check.owners[root as usize] = Some(Owner::Inode(ino));
+        let mut blocks = Vec::new();
+        let mut nodes = vec![(root, 0)];
+        while let Some((node_id, base)) = nodes.pop() {
+            let Sector::BlockMap(mut node) = self.read_sector(node_id)? else {
+                bail!("Sector {node_id} is not a BlockMap");
+            };
+            let span = self.entry_span(node.depth());
+            let mut cut = false;
+            for idx in 0..node.entries().len() {
+                let Some(sector_id) = node.entry(idx) else {
+                    continue;
+                };
+                let references = check.references.get(&sector_id).copied().unwrap_or(0);
+                let shared =
+                    node.depth() == 0 && references > 0 && references <= self.refcount(sector_id);
+                let problem = match self.unusable_sector(check, sector_id) {
+                    _ if shared => None,
+                    Some(problem) => Some(problem),
+                    None => match (self.read_sector(sector_id)?, node.depth()) {
+                        (Sector::FileData(_), 0) => None,
+                        (Sector::BlockMap(child), depth) if child.depth() + 1 == depth => None,
+                        _ => Some(format!("goes through sector {sector_id} of the wrong type")),
+                    },
+                };
+                if let Some(problem) = problem {
+                    check.problem(format!("The block map of inode {ino} {problem}"));
+                    node.set_entry(idx, None);
+                    cut = true;
+                    continue;
+                }
+                check.owners[sector_id as usize] = Some(Owner::Inode(ino));
+                if node.depth() == 0 && self.refcount(sector_id) > 0 {
+                    *check.references.entry(sector_id).or_default() += 1;
+                }
+                let start = base + span * idx as u64;
+                if node.depth() == 0 {
+                    blocks.push((start, sector_id));
+                } else {
+                    nodes.push((sector_id, start));
+                }
+            }
+            if cut && check.repair {
+                self.write_sector(node_id, &Sector::BlockMap(node))?;
+            }
+        }
+        blocks.sort_unstable();
+        Ok(blocks)
+    }
+    /// Why a chain cannot go through `sector_id`, if it cannot.
+    fn unusable_sector(&self, check: &Check, sector_id: u64) -> Option<String> {
+        if sector_id >= self.metadata.sector_count {
+            Some(format!(
+                "points past the end of the container ({sector_id})"
+            ))
+        } else if check.unreadable.contains(&sector_id) {
+            Some(format!("goes through the unreadable sector {sector_id}"))
+        } else {
+            check.owners[sector_id as usize]
+                .map(|owner| format!("shares sector {sector_id} with {owner}"))
+        }
+    }
+    /// Report a broken chain and, when repairing, make `last_sector` its end.
+    fn broken_chain(
+        &mut self,
+        check: &mut Check,
+        ino: u64,
+        chain: Chain,
+        problem: &str,
+        last_sector: Option<u64>,
+    ) -> Result<()> {
+        check.problem(format!("The {chain} chain of inode {ino} {problem}"));
+        let Some(sector_id) = last_sector.filter(|_| check.repair) else {
+            return Ok(());
+        };
+        let mut sector = self.read_sector(sector_id)?;
+        match &mut sector {
+            Sector::FileData(file_data) => file_data.clear_next(),
+            Sector::DirData(dir_data) => dir_data.clear_next(),
+            Sector::XattrData(xattr_data) => xattr_data.set_next(None),
+            _ => bail!("Sector {sector_id} is not part of a chain."),
+        }
+        self.write_sector(sector_id, &sector)?;
+        Ok(())
+    }
+    /// Walk the inodes no directory reaches and return those to move into lost+found.
+    fn fsck_orphans(&mut self, check: &mut Check) -> Result<Vec<u64>> {
+        let orphans: Vec<u64> = check
+            .metadata_sectors
+            .keys()
+            .copied()
+            .filter(|ino| !check.reached.contains(ino))
+            .collect();
+        //Orphans named by an orphaned directory come back with it
+        let mut named = BTreeSet::new();
+        for &ino in &orphans {
+            if let Sector::DirMetadata(dir_metadata) =
+                self.read_sector(check.metadata_sectors[&ino])?
+            {
+                if let Ok(entries) = self.dir_entries(&dir_metadata) {
+                    named.extend(entries.iter().map(|entry| entry.ino));
+                }
+            }
+        }
+        let (top, rest): (Vec<u64>, Vec<u64>) =
+            orphans.into_iter().partition(|ino| !named.contains(ino));
+        let mut lost = Vec::new();
+        //Cycles of orphaned directories are broken at their first inode
+        for ino in top.into_iter().chain(rest) {
+            if check.reached.insert(ino) {
+                check.problem(format!("Inode {ino} is not reachable from the root"));
+                lost.push(ino);
+                self.fsck_walk(check, ino)?;
+            }
+        }
+        Ok(lost)
+    }
+    /// Walk the free list and report the sectors used by nobody. Return whether both are fine.
+    fn fsck_free_list(&mut self, check: &mut Check) -> Result<bool> {
+        let mut ok = true;
+        let mut previous_id = None;
+        let mut next_sector = self.metadata.first_empty_sector;
+        while let Some(sector_id) = next_sector {
+            let problem = self.unusable_sector(check, sector_id);
+            let empty = match problem {
+                Some(_) => None,
+                None => match self.read_sector(sector_id)? {
+                    Sector::Empty(empty) => Some(empty),
+                    _ => None,
+                },
+            };
+            let Some(empty) = empty else {
+                let problem =
+                    problem.unwrap_or_else(|| format!("goes through the used sector {sector_id}"));
+                check.problem(format!("The free list {problem}"));
+                ok = false;
+                break;
+            };
+            check.owners[sector_id as usize] = Some(Owner::FreeList);
+            if empty.previous() != previous_id && previous_id.is_some() {
+                check.problem(format!(
+                    "Free sector {sector_id} does not point back to sector {}",
+                    previous_id.unwrap_or_default()
+                ));
+                ok = false;
+            }
+            if Some(sector_id) == self.metadata.last_empty_sector {
+                previous_id = Some(sector_id);
+                break;
+            }
+            previous_id = Some(sector_id);
+            next_sector = empty.next();
+        }
+        if ok && previous_id != self.metadata.last_empty_sector {
+            check.problem(format!(
+                "The free list ends at sector {previous_id:?} instead of {:?}",
+                self.metadata.last_empty_sector
+            ));
+            ok = false;
+        }
+        Ok(ok && self.fsck_leaked(check))
+    }
+    /// Report the sectors used by nobody and not free. Return whether there are none.
+    fn fsck_leaked(&self, check: &mut Check) -> bool {
+        let leaked: Vec<u64> = (0..self.metadata.sector_count)
+            .filter(|sector_id| check.owners[*sector_id as usize].is_none())
+            .collect();
+        if leaked.is_empty() {
+            return true;
+        }
+        check.problem(format!(
+            "{} sectors are neither used nor free: {leaked:?}",
+            leaked.len()
+        ));
+        false
+    }
+    /// Compare the bitmap of every group with the sectors used. Return whether they match.
+    fn fsck_bitmaps(&mut self, check: &mut Check, group_size: u64) -> Result<bool> {
+        let mut ok = true;
+        let sector_count = self.metadata.sector_count;
+        for group_start in (0..sector_count).step_by(group_size as usize) {
+            let bitmap = match self.unusable_sector(check, group_start) {
+                Some(_) => None,
+                None => match self.read_sector(group_start)? {
+                    Sector::Bitmap(bitmap) => Some(bitmap),
+                    _ => None,
+                },
+            };
+            let Some(bitmap) = bitmap else {
+                check.problem(format!(
+                    "Sector {group_start} is not the bitmap of its group"
+                ));
+                ok = false;
+                continue;
+            };
+            check.owners[group_start as usize] = Some(Owner::Bitmap);
+            if !bitmap.is_used(0) {
+                check.problem(format!("The bitmap in sector {group_start} is marked free"));
+                ok = false;
+            }
+            let end = (group_start + group_size).min(sector_count);
+            for sector_id in group_start + 1..end {
+                if bitmap.is_used((sector_id - group_start) as usize) {
+                    continue;
+                }
+                match check.owners[sector_id as usize] {
+                    Some(owner) => {
+                        check.problem(format!(
+                            "Sector {sector_id} is used by {owner} but marked free"
+                        ));
+                        ok = false;
+                    }
+                    None => check.owners[sector_id as usize] = Some(Owner::FreeList),
+                }
+            }
+        }
+        Ok(ok && self.fsck_leaked(check))
+    }
+    /// Write the bitmap of every group from the sectors used, emptying the free ones.
+    fn rebuild_bitmaps(&mut self, check: &Check, group_size: u64) -> Result<()> {
+        let sector_count = self.metadata.sector_count;
+        for group_start in (0..sector_count).step_by(group_size as usize) {
+            let mut bitmap = Self::group_bitmap(&self.superblock);
+            let end = (group_start + group_size).min(sector_count);
+            for sector_id in group_start + 1..end {
+                match check.owners[sector_id as usize] {
+                    None | Some(Owner::FreeList | Owner::Bitmap) => {
+                        self.write_sector(sector_id, &Sector::Empty(Empty::default()))?;
+                    }
+                    Some(Owner::Inode(_) | Owner::InodeTable | Owner::RefcountTable) => {
+                        bitmap.set_used((sector_id - group_start) as usize, true);
+                    }
+                }
+            }
+            self.write_sector(group_start, &Sector::Bitmap(bitmap))?;
+        }
+        self.count_free_sectors()
+    }
+    /// Chain every sector not used by an inode or the inode table into a new free list.
+    fn rebuild_free_list(&mut self, check: &Check) -> Result<()> {
+        let free: Vec<u64> = (0..self.metadata.sector_count)
+            .filter(|sector_id| {
+                matches!(
+                    check.owners[*sector_id as usize],
+                    None | Some(Owner::FreeList)
+                )
+            })
+            .collect();
+        self.link_free_list(&free)
+    }
+    /// Map every inode found to its metadata sector and forget the others.
+    fn repair_inode_table(&mut self, check: &mut Check) -> Result<()> {
+        self.count_free_sectors()?;
+        let stale: Vec<u64> = (0..self.inodes.len() as u64)
+            .filter(|ino| {
+                self.inodes[*ino as usize].is_some() && !check.metadata_sectors.contains_key(ino)
+            })
+            .collect();
+        for ino in stale {
+            self.set_inode_sector(ino, None)?;
+        }
+        let inodes: Vec<(u64, u64)> = check
+            .metadata_sectors
+            .iter()
+            .map(|(ino, sector_id)| (*ino, *sector_id))
+            .filter(|(ino, _)| *ino != FUSE_ROOT_ID)
+            .collect();
+        for (ino, sector_id) in inodes {
+            if self.inodes.get(ino as usize).copied().flatten() != Some(sector_id) {
+                self.set_inode_sector(ino, Some(sector_id))?;
+            }
+            if ino >= self.metadata.next_ino {
+                self.metadata.next_ino = ino + 1;
+                self.write_metadata()?;
+            }
+        }
+        Ok(())
+    }
+    /// Give the orphaned inodes a name in lost+found, creating it if needed.
+    fn link_lost(&mut self, check: &mut Check, lost: &[u64]) -> Result<()> {
+        if lost.is_empty() {
+            return Ok(());
+        }
+        let (_sector_id, root_sector) = self.find_ino_sector(FUSE_ROOT_ID)?;
+        let Sector::DirMetadata(root_metadata) = &root_sector else {
+            bail!("The root is not a directory.");
+        };
+        let lost_found = match self.find_entry(root_metadata, OsStr::new(LOST_FOUND))? {
+            Some((_, _, entry)) if entry.filetype == sector::FileType::Directory => entry.ino,
+            Some(_) => bail!("{LOST_FOUND} is not a directory."),
+            None => {
+                let permissions = Permissions {
+                    mode: 0o700,
+                    ..root_metadata.permissions()
+                };
+                let ino = self.create(
+                    FUSE_ROOT_ID,
+                    OsStr::new(LOST_FOUND),
+                    sector::FileType::Directory,
+                    permissions,
+                )?;
+                //Its link counts are right from the start
+                *check.subdirs.entry(FUSE_ROOT_ID).or_default() += 1;
+                ino
+            }
+        };
+        for &ino in lost {
+            let (_sector_id, sector) = self.find_ino_sector(ino)?;
+            let filetype = filetype(&sector);
+            if filetype == sector::FileType::Directory {
+                self.add_links(lost_found, 1)?;
+                *check.subdirs.entry(lost_found).or_default() += 1;
+            }
+            let name = self.entry_name(OsStr::new(&format!("#{ino}")))?;
+            self.insert_entry(
+                lost_found,
+                DirEntry {
+                    ino,
+                    name,
+                    filetype,
+                    empty: false,
+                },
+            )?;
+            self.set_parent(ino, lost_found)?;
+            *check.names.entry(ino).or_default() += 1;
+        }
+        Ok(())
+    }
+    /// Compare the link count of every inode reached with the names found.
+    fn fsck_links(&mut self, check: &mut Check) -> Result<()> {
+        for ino in check.reached.clone() {
+            let sector_id = check.metadata_sectors[&ino];
+            let mut sector = self.read_sector(sector_id)?;
+            let is_dir = matches!(sector, Sector::DirMetadata(_));
+            let Some(metadata) = sector.metadata_mut() else {
+                continue;
+            };
+            let expected = if is_dir {
+                2 + check.subdirs.get(&ino).copied().unwrap_or(0)
+            } else {
+                check.names.get(&ino).copied().unwrap_or(0)
+            };
+            let Some(nlink) = metadata.nlink() else {
+                continue;
+            };
+            if nlink != expected {
+                check.problem(format!("Inode {ino} has {nlink} links, {expected} found"));
+                if check.repair {
+                    metadata.set_nlink(expected);
+                    self.write_sector(sector_id, &sector)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+const fn filetype(sector: &Sector) -> sector::FileType {
+    match sector {
+        Sector::DirMetadata(_) => sector::FileType::Directory,
+        Sector::SymlinkMetadata(_) => sector::FileType::Symlink,
+        _ => sector::FileType::Regular,
+    }
+}
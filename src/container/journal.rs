@@ -0,0 +1,108 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::encoding;
+
+/// Bytes reserved for the journal header
+const JOURNAL_HEADER_SIZE: usize = 16;
+/// Bytes of a record before its image: its target as a u64
+const JOURNAL_TARGET_SIZE: usize = 8;
+/// Target of the records holding the container `Metadata`
+pub const METADATA_TARGET: u64 = u64::MAX;
+/// Target of the records holding the `Superblock`
+pub const SUPERBLOCK_TARGET: u64 = u64::MAX - 1;
+
+/// A transaction is committed once the header holding its record count and the checksum of
+/// its records is written.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct JournalHeader {
+    record_count: u32,
+    checksum: u32,
+}
+
+/// Redo journal of the container.
+///
+/// The images of the sectors changed by a transaction are written to the journal and
+/// committed before any of them reaches its place in the container. If the container is
+/// not closed properly, committed records are applied again on the next open.
+pub struct Journal {
+    offset: u64,
+    capacity: usize,
+    /// Bytes of a record: its target, then the image of a sector
+    record_size: usize,
+}
+impl Journal {
+    pub const fn new(offset: u64, capacity: usize, sector_size: usize) -> Self {
+        Self {
+            offset,
+            capacity,
+            record_size: JOURNAL_TARGET_SIZE + sector_size,
+        }
+    }
+    /// Bytes taken in the container by a journal of `capacity` records of sectors of
+    /// `sector_size` bytes.
+    pub const fn size(capacity: usize, sector_size: usize) -> u64 {
+        (JOURNAL_HEADER_SIZE + capacity * (JOURNAL_TARGET_SIZE + sector_size)) as u64
+    }
+    /// Maximum number of records in a transaction
+    pub const fn capacity(&self) -> usize {
+        self.capacity
+    }
+    /// Write and commit `records`, which must fit in the journal.
+    pub fn commit(&self, file: &mut File, records: &[(u64, Vec<u8>)]) -> Result<()> {
+        let mut buff = Vec::with_capacity(records.len() * self.record_size);
+        for (target, image) in records {
+            buff.extend_from_slice(&target.to_le_bytes());
+            buff.extend_from_slice(image);
+            buff.resize(buff.len().next_multiple_of(self.record_size), 0);
+        }
+        file.seek(SeekFrom::Start(self.offset + JOURNAL_HEADER_SIZE as u64))?;
+        file.write_all(&buff)?;
+        file.sync_data()?;
+        let header = JournalHeader {
+            record_count: records.len() as u32,
+            checksum: crc32c::crc32c(&buff),
+        };
+        self.write_header(file, &header)?;
+        file.sync_data()?;
+        Ok(())
+    }
+    /// Mark the last transaction as applied.
+    pub fn clear(&self, file: &mut File) -> Result<()> {
+        self.write_header(file, &JournalHeader::default())
+    }
+    /// Records of the last committed transaction, empty if there is none or its commit
+    /// did not complete.
+    pub fn committed(&self, file: &mut File) -> Result<Vec<(u64, Vec<u8>)>> {
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut buff = [0; JOURNAL_HEADER_SIZE];
+        file.read_exact(&mut buff)?;
+        let header: JournalHeader = encoding::decode(&buff)?;
+        let record_count = header.record_count as usize;
+        if record_count == 0 || record_count > self.capacity {
+            return Ok(Vec::new());
+        }
+        let mut buff = vec![0; record_count * self.record_size];
+        file.read_exact(&mut buff)?;
+        if crc32c::crc32c(&buff) != header.checksum {
+            return Ok(Vec::new());
+        }
+        let records = buff
+            .chunks(self.record_size)
+            .map(|record| {
+                let (target, image) = record.split_at(JOURNAL_TARGET_SIZE);
+                let mut target_bytes = [0; 8];
+                target_bytes.copy_from_slice(target);
+                (u64::from_le_bytes(target_bytes), image.to_vec())
+            })
+            .collect();
+        Ok(records)
+    }
+    fn write_header(&self, file: &mut File, header: &JournalHeader) -> Result<()> {
+        file.seek(SeekFrom::Start(self.offset))?;
+        file.write_all(&encoding::encode(header, JOURNAL_HEADER_SIZE)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+use anyhow::{bail, Result};
+
+use super::Container;
+use crate::encoding;
+use crate::error::FsError;
+use crate::sector::{RefcountTable, Sector};
+
+impl Container {
+    /// Counts held by each sector of the refcount table
+    fn refcount_table_size(&self) -> usize {
+        encoding::refcount_table_size(self.sector_size())
+    }
+    /// Read the refcount table, keeping the counts of the shared sectors.
+    pub(super) fn load_refcounts(&mut self) -> Result<()> {
+        self.refcount_table.clear();
+        self.refcounts.clear();
+        let mut next_sector = self.superblock.refcount_table();
+        while let Some(sector_id) = next_sector {
+            let Sector::RefcountTable(table) = self.read_sector(sector_id)? else {
+                bail!("Refcount table sector {sector_id} is not RefcountTable");
+            };
+            let base = (self.refcount_table.len() * self.refcount_table_size()) as u64;
+            for (idx, &count) in table.counts().iter().enumerate() {
+                if count > 0 {
+                    self.refcounts.insert(base + idx as u64, count);
+                }
+            }
+            self.refcount_table.push(sector_id);
+            next_sector = table.next_sector();
+        }
+        Ok(())
+    }
+    /// References to `sector_id` besides the first one, 0 if it is not shared.
+    pub(super) fn refcount(&self, sector_id: u64) -> u32 {
+        self.refcounts.get(&sector_id).copied().unwrap_or(0)
+    }
+    pub(super) fn set_refcount(&mut self, sector_id: u64, count: u32) -> Result<()> {
+        let table_index = sector_id as usize / self.refcount_table_size();
+        if count == 0 && table_index >= self.refcount_table.len() {
+            return Ok(());
+        }
+        while self.refcount_table.len() <= table_index {
+            self.append_refcount_table_sector()?;
+        }
+        let table_sector_id = self.refcount_table[table_index];
+        let mut base_sector = self.read_sector(table_sector_id)?;
+        let Sector::RefcountTable(table) = &mut base_sector else {
+            bail!("Refcount table sector {table_sector_id} is not RefcountTable");
+        };
+        table.set_count(sector_id as usize % self.refcount_table_size(), count);
+        self.write_sector(table_sector_id, &base_sector)?;
+        if count > 0 {
+            self.refcounts.insert(sector_id, count);
+        } else {
+            self.refcounts.remove(&sector_id);
+        }
+        Ok(())
+    }
+    /// Grow the refcount table until it holds the count of `sector_id`, a sector at a time
+    /// between checkpoints, ahead of a step sharing it.
+    pub(super) fn reserve_refcount(&mut self, sector_id: u64) -> Result<()> {
+        let table_index = sector_id as usize / self.refcount_table_size();
+        while self.refcount_table.len() <= table_index {
+            self.append_refcount_table_sector()?;
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+    fn append_refcount_table_sector(&mut self) -> Result<()> {
+        let Some(&last_sector_id) = self.refcount_table.last() else {
+            bail!("The container has no refcount table.");
+        };
+        let sector_id = self.get_empty_sector()?;
+        let table = RefcountTable::new(self.refcount_table_size());
+        self.write_sector(sector_id, &Sector::RefcountTable(table))?;
+        let mut base_last_sector = self.read_sector(last_sector_id)?;
+        let Sector::RefcountTable(last_sector) = &mut base_last_sector else {
+            bail!("Refcount table sector {last_sector_id} is not RefcountTable");
+        };
+        last_sector.set_next(sector_id);
+        self.write_sector(last_sector_id, &base_last_sector)?;
+        self.refcount_table.push(sector_id);
+        Ok(())
+    }
+    /// Create the refcount table of a container that never shared a sector, in the same
+    /// transaction as the superblock pointing to it.
+    pub(super) fn enable_refcounts(&mut self) -> Result<()> {
+        if !self.refcount_table.is_empty() {
+            return Ok(());
+        }
+        if !self.superblock.can_share() {
+            bail!(FsError::NotSupported);
+        }
+        let sector_id = self.transaction(|container| {
+            let sector_id = container.get_empty_sector()?;
+            let table = RefcountTable::new(container.refcount_table_size());
+            container.write_sector(sector_id, &Sector::RefcountTable(table))?;
+            container.superblock.set_refcount_table(sector_id);
+            container.write_superblock()?;
+            Ok(sector_id)
+        })?;
+        self.refcount_table.push(sector_id);
+        Ok(())
+    }
+    /// Add a reference to the data sector `sector_id`.
+    pub(super) fn share_sector(&mut self, sector_id: u64) -> Result<()> {
+        let Some(count) = self.refcount(sector_id).checked_add(1) else {
+            bail!(FsError::TooManyLinks);
+        };
+        self.set_refcount(sector_id, count)
+    }
+    /// Drop a reference to the sector `sector_id`, freeing it with the last one.
+    pub(super) fn release_sector(&mut self, sector_id: u64) -> Result<()> {
+        match self.refcount(sector_id) {
+            0 => self.free_sector(sector_id),
+            count => self.set_refcount(sector_id, count - 1),
+        }
+    }
+    /// Sector to write for `block` of the file whose block map is rooted at `root`, mapped to
+    /// `sector_id`: a copy of it mapped instead if other files share it.
+    pub(super) fn unshare_block(
+        &mut self,
+        root: Option<u64>,
+        block: u64,
+        sector_id: u64,
+    ) -> Result<u64> {
+        let count = self.refcount(sector_id);
+        if count == 0 {
+            return Ok(sector_id);
+        }
+        self.reserve_sectors(1)?;
+        let sector = self.read_sector(sector_id)?;
+        let copy_id = self.get_empty_sector_after(sector_id + 1)?;
+        self.write_sector(copy_id, &sector)?;
+        //The block is already mapped, the root stays the same
+        self.map_block(root, block, copy_id)?;
+        self.set_refcount(sector_id, count - 1)?;
+        Ok(copy_id)
+    }
+}
@@ -0,0 +1,215 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::journal::Journal;
+use super::METADATA_SIZE;
+use crate::encoding;
+use crate::sector::{Geometry, DATA_CHUNK_SIZE, DIR_SECTOR_SIZE, FILE_NAME_SIZE, SECTOR_SIZE};
+
+/// Signature at the beginning of every container
+const MAGIC: [u8; 8] = *b"MINI-FS\0";
+/// Format version written by this build
+const VERSION: u32 = 1;
+/// Sectors end with a checksum verified on every read
+const FEATURE_CHECKSUMS: u64 = 1;
+/// Multi-sector updates go through the journal right after the superblock
+const FEATURE_JOURNAL: u64 = 2;
+/// Free space is tracked by a bitmap at the beginning of each group of sectors
+const FEATURE_BITMAP: u64 = 4;
+/// Data sectors can be shared by several files, which older builds would free too early
+const FEATURE_REFCOUNTS: u64 = 8;
+/// Feature flags this build knows how to handle
+const SUPPORTED_FEATURES: u64 =
+    FEATURE_CHECKSUMS | FEATURE_JOURNAL | FEATURE_BITMAP | FEATURE_REFCOUNTS;
+/// Records in the journal of new containers
+const JOURNAL_RECORDS: u32 = 256;
+/// Bytes reserved for the superblock, leaving room for new fields.
+pub const SUPERBLOCK_SIZE: usize = 128;
+/// Maximum bytes of the volume label
+pub const LABEL_SIZE: usize = 32;
+
+/// First bytes of a container, describing the format it was written with.
+///
+/// Containers written before the superblock existed are handled as version 0: they start
+/// directly with `Metadata` and have no features.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Superblock {
+    magic: [u8; 8],
+    version: u32,
+    /// Features used by the container, a build must know all of them to open it
+    features: u64,
+    sector_size: u32,
+    data_chunk_size: u32,
+    /// Capacity of the journal, in records
+    journal_records: u32,
+    /// Geometry fields recorded after the data chunk size, 0 for the default
+    dir_sector_size: u32,
+    file_name_size: u32,
+    uuid: [u8; 16],
+    label: heapless::String<LABEL_SIZE>,
+    /// First sector of the refcount table, with the refcounts feature
+    refcount_table: Option<u64>,
+}
+impl Superblock {
+    pub const fn new() -> Self {
+        Self {
+            magic: MAGIC,
+            version: VERSION,
+            features: FEATURE_CHECKSUMS | FEATURE_JOURNAL | FEATURE_BITMAP,
+            sector_size: SECTOR_SIZE as u32,
+            data_chunk_size: DATA_CHUNK_SIZE as u32,
+            journal_records: JOURNAL_RECORDS,
+            dir_sector_size: DIR_SECTOR_SIZE as u32,
+            file_name_size: FILE_NAME_SIZE as u32,
+            uuid: [0; 16],
+            label: heapless::String::new(),
+            refcount_table: None,
+        }
+    }
+    pub fn legacy() -> Self {
+        Self {
+            version: 0,
+            features: 0,
+            journal_records: 0,
+            ..Self::new()
+        }
+    }
+    /// Decode the superblock at the beginning of `buff`, None if it has no signature.
+    pub fn decode(buff: &[u8]) -> Result<Option<Self>> {
+        if !buff.starts_with(&MAGIC) {
+            return Ok(None);
+        }
+        Ok(Some(encoding::decode(buff)?))
+    }
+    /// Check that this build can use the container.
+    pub fn check(&self) -> Result<()> {
+        if self.version > VERSION {
+            bail!(
+                "Unsupported container format version {} (this build supports up to version {VERSION}).",
+                self.version
+            );
+        }
+        let unknown_features = self.features & !SUPPORTED_FEATURES;
+        if unknown_features != 0 {
+            bail!("Unsupported container features {unknown_features:#x}.");
+        }
+        let geometry = self.geometry();
+        geometry.check()?;
+        let sector_size = encoding::sector_size(&geometry)?;
+        if (self.sector_size as usize) < sector_size {
+            bail!(
+                "Container sectors of {} bytes are too small for their geometry, which needs {sector_size}.",
+                self.sector_size
+            );
+        }
+        if self.has_journal() && self.journal_records == 0 {
+            bail!("The container has an empty journal.");
+        }
+        if self.has_refcounts() && self.refcount_table.is_none() {
+            bail!("The container shares sectors but has no refcount table.");
+        }
+        Ok(())
+    }
+    /// Record `geometry`, with the sector size it needs.
+    pub fn set_geometry(&mut self, geometry: Geometry) -> Result<()> {
+        geometry.check()?;
+        self.sector_size = encoding::sector_size(&geometry)? as u32;
+        self.data_chunk_size = geometry.data_chunk_size as u32;
+        self.dir_sector_size = geometry.dir_sector_size as u32;
+        self.file_name_size = geometry.file_name_size as u32;
+        Ok(())
+    }
+    pub fn geometry(&self) -> Geometry {
+        let or_default = |size: u32, default: usize| {
+            if size == 0 {
+                default
+            } else {
+                size as usize
+            }
+        };
+        Geometry {
+            data_chunk_size: self.data_chunk_size as usize,
+            dir_sector_size: or_default(self.dir_sector_size, DIR_SECTOR_SIZE),
+            file_name_size: or_default(self.file_name_size, FILE_NAME_SIZE),
+        }
+    }
+    pub const fn sector_size(&self) -> usize {
+        self.sector_size as usize
+    }
+    pub const fn uuid(&self) -> [u8; 16] {
+        self.uuid
+    }
+    pub fn set_uuid(&mut self, uuid: [u8; 16]) {
+        self.uuid = uuid;
+    }
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+    pub fn set_label(&mut self, label: &str) -> Result<()> {
+        let std::result::Result::Ok(label) = heapless::String::try_from(label) else {
+            bail!("The label is limited to {LABEL_SIZE} bytes.");
+        };
+        self.label = label;
+        Ok(())
+    }
+    pub const fn has_checksums(&self) -> bool {
+        self.features & FEATURE_CHECKSUMS != 0
+    }
+    pub const fn has_journal(&self) -> bool {
+        self.features & FEATURE_JOURNAL != 0
+    }
+    pub const fn has_refcounts(&self) -> bool {
+        self.features & FEATURE_REFCOUNTS != 0
+    }
+    /// Whether sectors can be shared: containers from before the superblock cannot record it.
+    pub const fn can_share(&self) -> bool {
+        self.version > 0
+    }
+    /// First sector of the refcount table, None until a sector is first shared.
+    pub const fn refcount_table(&self) -> Option<u64> {
+        if self.has_refcounts() {
+            self.refcount_table
+        } else {
+            None
+        }
+    }
+    /// Record the first sector of the refcount table, enabling the refcounts feature.
+    pub fn set_refcount_table(&mut self, sector_id: u64) {
+        self.features |= FEATURE_REFCOUNTS;
+        self.refcount_table = Some(sector_id);
+    }
+    /// Number of sectors in a group described by a bitmap, None without the bitmap feature.
+    pub const fn group_size(&self) -> Option<u64> {
+        if self.features & FEATURE_BITMAP != 0 {
+            Some(8 * encoding::bitmap_size(self.sector_size as usize) as u64)
+        } else {
+            None
+        }
+    }
+    /// Journal of the container, right after the superblock.
+    pub const fn journal(&self) -> Option<Journal> {
+        if self.has_journal() {
+            Some(Journal::new(
+                SUPERBLOCK_SIZE as u64,
+                self.journal_records as usize,
+                self.sector_size as usize,
+            ))
+        } else {
+            None
+        }
+    }
+    pub const fn metadata_offset(&self) -> u64 {
+        if self.version == 0 {
+            0
+        } else if self.has_journal() {
+            SUPERBLOCK_SIZE as u64
+                + Journal::size(self.journal_records as usize, self.sector_size as usize)
+        } else {
+            SUPERBLOCK_SIZE as u64
+        }
+    }
+    /// Offset of the first sector in the container file.
+    pub const fn sectors_offset(&self) -> u64 {
+        self.metadata_offset() + METADATA_SIZE as u64
+    }
+}
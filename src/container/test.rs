@@ -1,17 +1,61 @@
 #[cfg(test)]
 mod tests {
-    use crate::container::Container;
-    use crate::sector::{self, FileData, FileMetadata, Sector, DATA_CHUNK_SIZE};
+    use crate::container::superblock::{Superblock, SUPERBLOCK_SIZE};
+    use crate::container::{Container, Metadata, MkfsOptions, METADATA_SIZE};
+    use crate::encoding;
+    use crate::error::FsError;
+    use crate::sector::{
+        self, FileData, FileMetadata, Geometry, Permissions, Sector, DATA_CHUNK_SIZE,
+        DIR_SECTOR_SIZE, FILE_NAME_SIZE, INODE_TABLE_SIZE, SECTOR_SIZE,
+    };
     use fuser::FileType;
+    use libc::{
+        FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE, RENAME_EXCHANGE,
+        RENAME_NOREPLACE, XATTR_CREATE, XATTR_REPLACE,
+    };
+    use std::collections::BTreeMap;
     use std::ffi::{OsStr, OsString};
     use std::str::FromStr;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
     use std::{collections::HashSet, fs::remove_file};
 
+    const PERMISSIONS: Permissions = Permissions {
+        mode: 0o644,
+        uid: 1000,
+        gid: 1000,
+    };
+
+    /// Container from before the superblock, whose free sectors form a list starting after
+    /// the root in sector 0.
+    fn legacy_container(container_name: &str) -> Container {
+        let _ = remove_file(container_name);
+        let metadata = Metadata {
+            root_dir_sector: 0,
+            sector_count: 1,
+            first_empty_sector: None,
+            last_empty_sector: None,
+            next_ino: 2,
+            inode_table: None,
+            max_sectors: None,
+        };
+        let mut bytes = encoding::encode(&metadata, METADATA_SIZE).unwrap();
+        let root = Sector::DirMetadata(FileMetadata::new(1, None));
+        bytes.extend(encoding::encode(&root, SECTOR_SIZE).unwrap());
+        std::fs::write(container_name, &bytes).unwrap();
+        Container::new(container_name.to_string()).unwrap()
+    }
+    /// Data sector of `block` of a file.
+    fn data_sector_id(container: &mut Container, file_metadata: &FileMetadata, block: u64) -> u64 {
+        container
+            .mapped_sectors(file_metadata.first_sector(), block..block + 1)
+            .unwrap()[0]
+            .unwrap()
+    }
+
     #[test]
     fn append_empty_sector() {
         let container_name = "/tmp/canard_append_empty";
-        let _ = remove_file(container_name);
-        let mut container = Container::new(container_name.to_string()).unwrap();
+        let mut container = legacy_container(container_name);
         let sector_count = container.metadata.sector_count;
         container.append_empty_sector().unwrap();
         assert_eq!(container.metadata.sector_count, sector_count + 1);
@@ -21,8 +65,7 @@ mod tests {
     #[test]
     fn read_write_sector() {
         let container_name = "/tmp/canard_read_write_sector";
-        let _ = remove_file(container_name);
-        let mut container = Container::new(container_name.to_string()).unwrap();
+        let mut container = legacy_container(container_name);
         container.append_empty_sector().unwrap();
         container.append_empty_sector().unwrap();
         container.append_empty_sector().unwrap();
@@ -47,20 +90,19 @@ mod tests {
     #[test]
     fn free_sector() {
         let container_name = "/tmp/canard_free_sector";
-        let _ = remove_file(container_name);
-        let mut container = Container::new(container_name.to_string()).unwrap();
+        let mut container = legacy_container(container_name);
         container.append_empty_sector().unwrap();
         container.append_empty_sector().unwrap();
         container.append_empty_sector().unwrap();
         container.append_empty_sector().unwrap();
         container
-            .write_sector(1, &Sector::FileData(FileData::new()))
+            .write_sector(1, &Sector::FileData(FileData::new(DATA_CHUNK_SIZE)))
             .unwrap();
         container
             .write_sector(2, &Sector::DirMetadata(FileMetadata::new(2, None)))
             .unwrap();
         container
-            .write_sector(3, &Sector::FileData(FileData::new()))
+            .write_sector(3, &Sector::FileData(FileData::new(DATA_CHUNK_SIZE)))
             .unwrap();
         container
             .write_sector(4, &Sector::DirMetadata(FileMetadata::new(3, None)))
@@ -156,13 +198,12 @@ mod tests {
     #[test]
     fn delete_file() {
         let container_name = "/tmp/canard_delete_file";
-        let _ = remove_file(container_name);
-        let mut container = Container::new(container_name.to_string()).unwrap();
+        let mut container = legacy_container(container_name);
         container.append_empty_sector().unwrap();
         container.append_empty_sector().unwrap();
         container.append_empty_sector().unwrap();
         container.append_empty_sector().unwrap();
-        let mut file_data = FileData::new();
+        let mut file_data = FileData::new(DATA_CHUNK_SIZE);
         file_data.set_next(4);
         container
             .write_sector(1, &Sector::FileData(file_data))
@@ -172,12 +213,12 @@ mod tests {
         container
             .write_sector(2, &Sector::FileMetadata(file_metadata))
             .unwrap();
-        let mut file_data = FileData::new();
+        let mut file_data = FileData::new(DATA_CHUNK_SIZE);
         file_data.set_previous(4);
         container
             .write_sector(3, &Sector::FileData(file_data))
             .unwrap();
-        let mut file_data = FileData::new();
+        let mut file_data = FileData::new(DATA_CHUNK_SIZE);
         file_data.set_next(3);
         file_data.set_previous(1);
         container
@@ -187,6 +228,7 @@ mod tests {
         //write_sector are not doing any checking of what is written
         container.metadata.first_empty_sector = None;
         container.metadata.last_empty_sector = None;
+        container.set_inode_sector(7, Some(2)).unwrap();
 
         assert!(matches!(
             container.read_sector(1).unwrap(),
@@ -229,8 +271,7 @@ mod tests {
     #[test]
     fn get_empty_sector() {
         let container_name = "/tmp/canard_get_empty_sector";
-        let _ = remove_file(container_name);
-        let mut container = Container::new(container_name.to_string()).unwrap();
+        let mut container = legacy_container(container_name);
         container.append_empty_sector().unwrap();
         assert_eq!(container.metadata.sector_count, 2);
         let empty_sector = container.get_empty_sector().unwrap();
@@ -243,10 +284,15 @@ mod tests {
         let _ = remove_file(container_name);
         let mut container = Container::new(container_name.to_string()).unwrap();
         let new_inode = container
-            .create(1, OsStr::new("loutre.txt"), sector::FileType::Regular)
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
             .unwrap();
         let (sector_id, _sector) = container.find_ino_sector(1).unwrap();
-        assert_eq!(sector_id, 0); //Root directory
+        assert_eq!(sector_id, 1); //Root directory, after the bitmap
         let ret = container.find_ino_sector(new_inode);
         assert!(ret.is_ok());
         let ret = container.find_ino_sector(37);
@@ -262,7 +308,12 @@ mod tests {
         container.append_empty_sector().unwrap();
         container.append_empty_sector().unwrap();
         let new_inode = container
-            .create(1, OsStr::new("loutre.txt"), sector::FileType::Regular)
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
             .unwrap();
 
         let attr = container.getattr(new_inode).unwrap().unwrap();
@@ -280,15 +331,25 @@ mod tests {
         let mut container = Container::new(container_name.to_string()).unwrap();
 
         let inode1 = container
-            .create(1, OsStr::new("loutre.txt"), sector::FileType::Regular)
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
             .unwrap();
         let inode2 = container
-            .create(1, OsStr::new("canard.txt"), sector::FileType::Regular)
+            .create(
+                1,
+                OsStr::new("canard.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
             .unwrap();
 
         let entries = container.readdir(1, 1, 0).unwrap();
         let entries_names = entries.iter().map(|e| e.2.clone()).collect::<HashSet<_>>();
-        let entries_inode = entries.iter().map(|e| e.0.clone()).collect::<HashSet<_>>();
+        let entries_inode = entries.iter().map(|e| e.0).collect::<HashSet<_>>();
         assert_eq!(entries.len(), 4); //".", "..", "loutre.txt", "canard.txt"
         assert!(entries_names.contains("."));
         assert!(entries_names.contains(".."));
@@ -298,11 +359,16 @@ mod tests {
         assert!(entries_inode.contains(&inode2));
 
         let inode3 = container
-            .create(1, OsStr::new("baleine.txt"), sector::FileType::Regular)
+            .create(
+                1,
+                OsStr::new("baleine.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
             .unwrap();
         let entries = container.readdir(1, 1, 0).unwrap();
         let entries_names = entries.iter().map(|e| e.2.clone()).collect::<HashSet<_>>();
-        let entries_inode = entries.iter().map(|e| e.0.clone()).collect::<HashSet<_>>();
+        let entries_inode = entries.iter().map(|e| e.0).collect::<HashSet<_>>();
         assert_eq!(entries.len(), 5); //  "baleine.txt"
         assert!(entries_names.contains("."));
         assert!(entries_names.contains(".."));
@@ -322,10 +388,20 @@ mod tests {
         let mut container = Container::new(container_name.to_string()).unwrap();
 
         let inode1 = container
-            .create(1, OsStr::new("loutre.txt"), sector::FileType::Regular)
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
             .unwrap();
         let inode2 = container
-            .create(1, OsStr::new("canard.txt"), sector::FileType::Regular)
+            .create(
+                1,
+                OsStr::new("canard.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
             .unwrap();
 
         let finding = container.lookup(1, OsStr::new("loutre.txt")).unwrap();
@@ -349,7 +425,12 @@ mod tests {
         let mut container = Container::new(container_name.to_string()).unwrap();
 
         let file_inode = container
-            .create(1, OsStr::new("canard.txt"), sector::FileType::Regular)
+            .create(
+                1,
+                OsStr::new("canard.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
             .unwrap();
 
         //Phase 1, First simple write
@@ -362,8 +443,7 @@ mod tests {
             panic!("Sector is not FileMetadata.");
         };
         assert_eq!(file_metadata.length_byte(), 10);
-        assert!(file_metadata.first_sector().is_some());
-        let sector_id = file_metadata.first_sector().unwrap();
+        let sector_id = data_sector_id(&mut container, &file_metadata, 0);
         let Sector::FileData(sector_data) = container.read_sector(sector_id).unwrap() else {
             panic!("Sector is not FileData.");
         };
@@ -379,8 +459,7 @@ mod tests {
             panic!("Sector is not FileMetadata.");
         };
         assert_eq!(file_metadata.length_byte(), DATA_CHUNK_SIZE as u64 + 10);
-        assert!(file_metadata.first_sector().is_some());
-        let sector_id = file_metadata.first_sector().unwrap();
+        let sector_id = data_sector_id(&mut container, &file_metadata, 0);
         let Sector::FileData(sector_data) = container.read_sector(sector_id).unwrap() else {
             panic!("Sector is not FileData.");
         };
@@ -391,12 +470,11 @@ mod tests {
             &sector_data.data()[0..DATA_CHUNK_SIZE]
         );
 
-        assert!(sector_data.next().is_some());
-        let sector_id = sector_data.next().unwrap();
+        let sector_id = data_sector_id(&mut container, &file_metadata, 1);
         let Sector::FileData(sector_data) = container.read_sector(sector_id).unwrap() else {
             panic!("Sector is not FileData.");
         };
-        let sector_2_data = vec![17; 10];
+        let sector_2_data = [17; 10];
         assert_eq!(sector_data.data_length(), 10);
         assert_eq!(&sector_2_data[0..10], &sector_data.data()[0..10]);
 
@@ -412,8 +490,7 @@ mod tests {
             panic!("Sector is not FileMetadata.");
         };
         assert_eq!(file_metadata.length_byte(), DATA_CHUNK_SIZE as u64 + 10);
-        assert!(file_metadata.first_sector().is_some());
-        let sector_id = file_metadata.first_sector().unwrap();
+        let sector_id = data_sector_id(&mut container, &file_metadata, 0);
         let Sector::FileData(sector_data) = container.read_sector(sector_id).unwrap() else {
             panic!("Sector is not FileData.");
         };
@@ -425,8 +502,7 @@ mod tests {
             &sector_data.data()[0..DATA_CHUNK_SIZE]
         );
 
-        assert!(sector_data.next().is_some());
-        let sector_id = sector_data.next().unwrap();
+        let sector_id = data_sector_id(&mut container, &file_metadata, 1);
         let Sector::FileData(sector_data) = container.read_sector(sector_id).unwrap() else {
             panic!("Sector is not FileData.");
         };
@@ -449,8 +525,7 @@ mod tests {
             file_metadata.length_byte(),
             (DATA_CHUNK_SIZE as u64 * 4) - 5
         );
-        assert!(file_metadata.first_sector().is_some());
-        let sector_id = file_metadata.first_sector().unwrap();
+        let sector_id = data_sector_id(&mut container, &file_metadata, 0);
         let Sector::FileData(sector_data) = container.read_sector(sector_id).unwrap() else {
             panic!("Sector is not FileData.");
         };
@@ -461,34 +536,31 @@ mod tests {
             &sector_1_data[0..DATA_CHUNK_SIZE],
             &sector_data.data()[0..DATA_CHUNK_SIZE]
         );
-        assert!(sector_data.next().is_some());
-        let sector_id = sector_data.next().unwrap();
+        let sector_id = data_sector_id(&mut container, &file_metadata, 1);
         let Sector::FileData(sector_data) = container.read_sector(sector_id).unwrap() else {
             panic!("Sector is not FileData.");
         };
-        let sector_2_data = vec![91; DATA_CHUNK_SIZE];
+        let sector_2_data = [91; DATA_CHUNK_SIZE];
         assert_eq!(
             &sector_2_data[0..DATA_CHUNK_SIZE],
             &sector_data.data()[0..DATA_CHUNK_SIZE]
         );
 
-        assert!(sector_data.next().is_some());
-        let sector_id = sector_data.next().unwrap();
+        let sector_id = data_sector_id(&mut container, &file_metadata, 2);
         let Sector::FileData(sector_data) = container.read_sector(sector_id).unwrap() else {
             panic!("Sector is not FileData.");
         };
-        let sector_3_data = vec![91; DATA_CHUNK_SIZE];
+        let sector_3_data = [91; DATA_CHUNK_SIZE];
         assert_eq!(
             &sector_3_data[0..DATA_CHUNK_SIZE],
             &sector_data.data()[0..DATA_CHUNK_SIZE]
         );
 
-        assert!(sector_data.next().is_some());
-        let sector_id = sector_data.next().unwrap();
+        let sector_id = data_sector_id(&mut container, &file_metadata, 3);
         let Sector::FileData(sector_data) = container.read_sector(sector_id).unwrap() else {
             panic!("Sector is not FileData.");
         };
-        let sector_4_data = vec![91; DATA_CHUNK_SIZE - 5];
+        let sector_4_data = [91; DATA_CHUNK_SIZE - 5];
         assert_eq!(sector_data.data_length(), DATA_CHUNK_SIZE as u64 - 5);
         assert_eq!(
             &sector_4_data[0..DATA_CHUNK_SIZE - 5],
@@ -504,7 +576,12 @@ mod tests {
         let mut container = Container::new(container_name.to_string()).unwrap();
 
         let file_inode = container
-            .create(1, OsStr::new("canard.txt"), sector::FileType::Regular)
+            .create(
+                1,
+                OsStr::new("canard.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
             .unwrap();
 
         //Phase 1, First simple write
@@ -538,32 +615,579 @@ mod tests {
                 size
             };
             assert_eq!(read, expected_read);
-            let src_slice = &data[offset as usize..(offset as u64 + read) as usize];
+            let src_slice = &data[offset..(offset as u64 + read) as usize];
             let read_slice = &read_data[0..read as usize];
             assert_eq!(src_slice, read_slice);
         }
         remove_file(container_name).unwrap();
     }
     #[test]
+    fn truncate() {
+        let container_name = "/tmp/canard_truncate";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let inode_file = container
+            .create(
+                1,
+                OsStr::new("canard.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        let data: Vec<u8> = (0..DATA_CHUNK_SIZE * 5).map(|i| i as u8).collect();
+        container.write(inode_file, 0, &data).unwrap();
+        let free_blocks = container.statfs().free_blocks;
+
+        //The sectors past the new end are freed
+        container
+            .truncate(inode_file, DATA_CHUNK_SIZE as u64 + 10)
+            .unwrap();
+        assert_eq!(container.statfs().free_blocks, free_blocks + 3);
+        let (_sector_id, sector) = container.find_ino_sector(inode_file).unwrap();
+        assert_eq!(sector.metadata().unwrap().length_sector(), 2);
+        let mut read = Vec::new();
+        container
+            .read(inode_file, 0, data.len() as u64, &mut read)
+            .unwrap();
+        assert_eq!(read, &data[..DATA_CHUNK_SIZE + 10]);
+
+        //So is the block map once empty
+        container.truncate(inode_file, 0).unwrap();
+        assert_eq!(container.statfs().free_blocks, free_blocks + 6);
+        let (_sector_id, sector) = container.find_ino_sector(inode_file).unwrap();
+        assert_eq!(sector.metadata().unwrap().length_sector(), 0);
+        assert_eq!(sector.metadata().unwrap().first_sector(), None);
+
+        container.write(inode_file, 0, &data[..10]).unwrap();
+        drop(container);
+        let report = Container::fsck(container_name, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn defrag() {
+        let container_name = "/tmp/canard_defrag";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let mut files = Vec::new();
+        for name in ["canard", "loutre", "baleine"] {
+            let ino = container
+                .create(1, OsStr::new(name), sector::FileType::Regular, PERMISSIONS)
+                .unwrap();
+            files.push(ino);
+        }
+        //Interleaved writes fragment the files
+        let data: Vec<Vec<u8>> = (0..3)
+            .map(|file| (0..DATA_CHUNK_SIZE * 6).map(|i| (i + file) as u8).collect())
+            .collect();
+        for chunk in 0..6 {
+            for (ino, data) in files.iter().zip(&data) {
+                let offset = chunk * DATA_CHUNK_SIZE;
+                container
+                    .write(*ino, offset as i64, &data[offset..offset + DATA_CHUNK_SIZE])
+                    .unwrap();
+            }
+        }
+        container
+            .setxattr(files[2], OsStr::new("user.ocean"), &[7; 500], 0)
+            .unwrap();
+        for i in 0..DIR_SECTOR_SIZE * 3 {
+            container
+                .create(
+                    1,
+                    OsStr::new(&format!("plume{i}")),
+                    sector::FileType::Regular,
+                    PERMISSIONS,
+                )
+                .unwrap();
+        }
+        let long_target = "ocean/".repeat(DATA_CHUNK_SIZE / 2);
+        let inode_link = container
+            .symlink(1, OsStr::new("deep"), OsStr::new(&long_target), 1000, 1000)
+            .unwrap();
+        container.unlink(1, OsStr::new("loutre")).unwrap();
+        let free_blocks = container.statfs().free_blocks;
+
+        //Every file is contiguous and the free sectors are gone
+        let report = container.defrag().unwrap();
+        assert!(report.moved > 0);
+        assert!(report.size_after < report.size_before);
+        assert_eq!(container.statfs().free_blocks, 0);
+        assert_eq!(
+            report.size_before - report.size_after,
+            free_blocks * container.sector_size() as u64
+        );
+        for ino in [files[0], files[2]] {
+            let (sector_id, sector) = container.find_ino_sector(ino).unwrap();
+            let file_metadata = sector.metadata().unwrap().clone();
+            let first = data_sector_id(&mut container, &file_metadata, 0);
+            assert!(first > sector_id);
+            for block in 1..6 {
+                assert_eq!(
+                    data_sector_id(&mut container, &file_metadata, block),
+                    first + block
+                );
+            }
+        }
+
+        //Nothing is lost, even once the container is reopened
+        for container in [
+            &mut container,
+            &mut Container::new(container_name.to_string()).unwrap(),
+        ] {
+            for (ino, data) in [(files[0], &data[0]), (files[2], &data[2])] {
+                let mut read = Vec::new();
+                container
+                    .read(ino, 0, data.len() as u64, &mut read)
+                    .unwrap();
+                assert_eq!(&read, data);
+            }
+            assert_eq!(
+                container
+                    .getxattr(files[2], OsStr::new("user.ocean"))
+                    .unwrap(),
+                [7; 500]
+            );
+            assert!(container
+                .lookup(1, OsStr::new("plume14"))
+                .unwrap()
+                .is_some());
+            assert_eq!(
+                container.readlink(inode_link).unwrap(),
+                long_target.as_bytes()
+            );
+        }
+        drop(container);
+        let report = Container::fsck(container_name, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        remove_file(container_name).unwrap();
+
+        //So is a container with a free list
+        let container_name = "/tmp/canard_defrag_legacy";
+        let mut container = legacy_container(container_name);
+        for name in ["canard", "loutre"] {
+            let ino = container
+                .create(1, OsStr::new(name), sector::FileType::Regular, PERMISSIONS)
+                .unwrap();
+            container.write(ino, 0, &data[0]).unwrap();
+        }
+        container.unlink(1, OsStr::new("canard")).unwrap();
+        //The free list stays linked, even when the container cannot grow to make room
+        let sector_count = container.metadata.sector_count;
+        container
+            .set_max_size(Some(sector_count * container.sector_size() as u64))
+            .unwrap();
+        let free_sectors = container.free_sectors;
+        let report = container.defrag().unwrap();
+        assert!(report.size_after < report.size_before);
+        assert_eq!(
+            container.free_sectors,
+            free_sectors - (sector_count - container.metadata.sector_count)
+        );
+        let (ino, _filetype) = container.lookup(1, OsStr::new("loutre")).unwrap().unwrap();
+        let mut read = Vec::new();
+        container
+            .read(ino, 0, data[0].len() as u64, &mut read)
+            .unwrap();
+        assert_eq!(read, data[0]);
+        drop(container);
+        let report = Container::fsck(container_name, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn sparse() {
+        let container_name = "/tmp/canard_sparse";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let inode_file = container
+            .create(
+                1,
+                OsStr::new("canard.img"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        let used_blocks = |container: &Container| {
+            let stats = container.statfs();
+            stats.blocks - stats.free_blocks
+        };
+        let used = used_blocks(&container);
+
+        //Writing past the end leaves a hole reading as zeros
+        let offset = DATA_CHUNK_SIZE * 10 + 5;
+        container
+            .write(inode_file, offset as i64, &[1; 10])
+            .unwrap();
+        let attr = container.getattr(inode_file).unwrap().unwrap();
+        assert_eq!(attr.size, offset as u64 + 10);
+        assert_eq!(attr.blocks, (SECTOR_SIZE as u64).div_ceil(512));
+        //The data sector and the root of the block map
+        assert_eq!(used_blocks(&container), used + 2);
+        let mut read = Vec::new();
+        container
+            .read(inode_file, 0, attr.size + 100, &mut read)
+            .unwrap();
+        let mut expected = vec![0; offset];
+        expected.extend([1; 10]);
+        assert_eq!(read, expected);
+
+        //So does the rest of a block filled in the hole
+        let offset = DATA_CHUNK_SIZE * 3;
+        container.write(inode_file, offset as i64, &[4; 5]).unwrap();
+        let mut read = Vec::new();
+        container
+            .read(inode_file, offset as i64, 10, &mut read)
+            .unwrap();
+        assert_eq!(read, [4, 4, 4, 4, 4, 0, 0, 0, 0, 0]);
+        assert_eq!(used_blocks(&container), used + 3);
+
+        //Extending allocates nothing, and the bytes cut before read as zeros
+        container.write(inode_file, 0, &[2; 20]).unwrap();
+        container.truncate(inode_file, 5).unwrap();
+        container.truncate(inode_file, 1 << 30).unwrap();
+        let attr = container.getattr(inode_file).unwrap().unwrap();
+        assert_eq!(attr.size, 1 << 30);
+        assert_eq!(used_blocks(&container), used + 2);
+        let mut read = Vec::new();
+        container.read(inode_file, 0, 30, &mut read).unwrap();
+        let mut expected = vec![2; 5];
+        expected.resize(30, 0);
+        assert_eq!(read, expected);
+        container.write(inode_file, 3, &[3; 20]).unwrap();
+        let mut read = Vec::new();
+        container.read(inode_file, 0, 30, &mut read).unwrap();
+        let mut expected = vec![2, 2, 2];
+        expected.extend([3; 20]);
+        expected.resize(30, 0);
+        assert_eq!(read, expected);
+
+        //A file only extended has no data sector at all, one written past a hole a single one
+        let mut sparse_files = Vec::new();
+        for name in ["extended.img", "hole.img"] {
+            let ino = container
+                .create(1, OsStr::new(name), sector::FileType::Regular, PERMISSIONS)
+                .unwrap();
+            sparse_files.push(ino);
+        }
+        container.truncate(sparse_files[0], 1000).unwrap();
+        container
+            .write(sparse_files[1], DATA_CHUNK_SIZE as i64 * 4, &[5; 10])
+            .unwrap();
+
+        drop(container);
+        let report = Container::fsck(container_name, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let attr = container.getattr(sparse_files[0]).unwrap().unwrap();
+        assert_eq!(attr.size, 1000);
+        drop(container);
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn seek() {
+        let container_name = "/tmp/canard_seek";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let inode_file = container
+            .create(
+                1,
+                OsStr::new("canard.img"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        let chunk = DATA_CHUNK_SIZE as u64;
+        //Data in the first block and the fourth one, the file ending inside the fourth one
+        container.write(inode_file, 0, &[1; 10]).unwrap();
+        container
+            .write(inode_file, 3 * chunk as i64, &[2; 10])
+            .unwrap();
+        let length = 3 * chunk + 10;
+
+        assert_eq!(container.seek_data(inode_file, 0).unwrap(), 0);
+        assert_eq!(container.seek_data(inode_file, 5).unwrap(), 5);
+        assert_eq!(container.seek_data(inode_file, chunk).unwrap(), 3 * chunk);
+        assert_eq!(container.seek_hole(inode_file, 0).unwrap(), chunk);
+        assert_eq!(
+            container.seek_hole(inode_file, chunk + 3).unwrap(),
+            chunk + 3
+        );
+        //The end of the file is a hole
+        assert_eq!(container.seek_hole(inode_file, 3 * chunk).unwrap(), length);
+        let err = container.seek_data(inode_file, length).unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::NoSuchAddress));
+        let err = container.seek_hole(inode_file, length).unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::NoSuchAddress));
+
+        //Without data past the offset, there is only the hole
+        container.truncate(inode_file, 10 * chunk).unwrap();
+        let err = container.seek_data(inode_file, 4 * chunk).unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::NoSuchAddress));
+        assert_eq!(
+            container.seek_hole(inode_file, 4 * chunk).unwrap(),
+            4 * chunk
+        );
+        let err = container.seek_data(1, 0).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<FsError>(),
+            Some(&FsError::InvalidArgument)
+        );
+
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn fallocate() {
+        let container_name = "/tmp/canard_fallocate";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let inode_file = container
+            .create(
+                1,
+                OsStr::new("canard.db"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        let used_blocks = |container: &Container| {
+            let stats = container.statfs();
+            stats.blocks - stats.free_blocks
+        };
+        let used = used_blocks(&container);
+        let chunk = DATA_CHUNK_SIZE as u64;
+
+        //Preallocation grows the file unless it keeps its size
+        container.fallocate(inode_file, 0, 3 * chunk, 0).unwrap();
+        let attr = container.getattr(inode_file).unwrap().unwrap();
+        assert_eq!(attr.size, 3 * chunk);
+        assert_eq!(used_blocks(&container), used + 4);
+        container
+            .fallocate(inode_file, 2 * chunk, 3 * chunk, FALLOC_FL_KEEP_SIZE)
+            .unwrap();
+        let attr = container.getattr(inode_file).unwrap().unwrap();
+        assert_eq!(attr.size, 3 * chunk);
+        assert_eq!(used_blocks(&container), used + 6);
+        let mut read = Vec::new();
+        container.read(inode_file, 0, 4 * chunk, &mut read).unwrap();
+        assert_eq!(read, vec![0; 3 * DATA_CHUNK_SIZE]);
+
+        //Punching frees the blocks covered and zeroes the rest of the range
+        let data = vec![1; 3 * DATA_CHUNK_SIZE];
+        container.write(inode_file, 0, &data).unwrap();
+        let mode = FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE;
+        container
+            .fallocate(inode_file, chunk / 2, 2 * chunk, mode)
+            .unwrap();
+        assert_eq!(used_blocks(&container), used + 5);
+        let mut read = Vec::new();
+        container.read(inode_file, 0, 3 * chunk, &mut read).unwrap();
+        let mut expected = data.clone();
+        expected[DATA_CHUNK_SIZE / 2..DATA_CHUNK_SIZE * 5 / 2].fill(0);
+        assert_eq!(read, expected);
+        let err = container
+            .fallocate(inode_file, 0, chunk, FALLOC_FL_PUNCH_HOLE)
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::NotSupported));
+        //Up to the end of the file, including what is preallocated past it
+        container
+            .fallocate(inode_file, 2 * chunk, 10 * chunk, mode)
+            .unwrap();
+        assert_eq!(used_blocks(&container), used + 2);
+        expected[2 * DATA_CHUNK_SIZE..].fill(0);
+
+        //Zeroing keeps the sectors allocated
+        container
+            .fallocate(inode_file, 10, chunk, FALLOC_FL_ZERO_RANGE)
+            .unwrap();
+        assert_eq!(used_blocks(&container), used + 3);
+        let mut read = Vec::new();
+        container.read(inode_file, 0, 3 * chunk, &mut read).unwrap();
+        expected[10..DATA_CHUNK_SIZE / 2].fill(0);
+        assert_eq!(read, expected);
+
+        //Huge ranges fail before anything is allocated
+        container.set_max_size(Some(1 << 20)).unwrap();
+        let used = used_blocks(&container);
+        let err = container.fallocate(inode_file, 0, 1 << 40, 0).unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::NoSpace));
+        assert_eq!(used_blocks(&container), used);
+        assert_eq!(
+            container.getattr(inode_file).unwrap().unwrap().size,
+            3 * chunk
+        );
+        let err = container
+            .fallocate(inode_file, 1 << 62, 1 << 62, 0)
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::FileTooBig));
+
+        drop(container);
+        let report = Container::fsck(container_name, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn copy_range() {
+        let container_name = "/tmp/canard_copy_range";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let mut files = Vec::new();
+        for name in ["canard.db", "loutre.db", "saumon.db", "clone.db"] {
+            files.push(
+                container
+                    .create(1, OsStr::new(name), sector::FileType::Regular, PERMISSIONS)
+                    .unwrap(),
+            );
+        }
+        let [inode_file, inode_copy, inode_part, inode_clone] = files[..] else {
+            unreachable!();
+        };
+        let used_blocks = |container: &Container| {
+            let stats = container.statfs();
+            stats.blocks - stats.free_blocks
+        };
+        let chunk = DATA_CHUNK_SIZE as u64;
+        //A full block, a hole, a full block and a partial one
+        let data: Vec<u8> = (0..DATA_CHUNK_SIZE * 2 + 50).map(|i| i as u8).collect();
+        container
+            .write(inode_file, 0, &data[..DATA_CHUNK_SIZE])
+            .unwrap();
+        container
+            .write(inode_file, 2 * chunk as i64, &data[DATA_CHUNK_SIZE..])
+            .unwrap();
+        let mut expected = Vec::new();
+        container
+            .read(inode_file, 0, 4 * chunk, &mut expected)
+            .unwrap();
+
+        //Sector by sector, stopping at the end of the file and keeping the hole
+        let used = used_blocks(&container);
+        let copied = container
+            .copy_range(inode_file, 0, inode_copy, 0, 10 * chunk, false)
+            .unwrap();
+        assert_eq!(copied, 3 * chunk + 50);
+        assert_eq!(used_blocks(&container), used + 4);
+        let mut read = Vec::new();
+        container.read(inode_copy, 0, 4 * chunk, &mut read).unwrap();
+        assert_eq!(read, expected);
+        assert_eq!(container.seek_hole(inode_copy, 0).unwrap(), chunk);
+
+        //Unaligned ranges are copied through a buffer
+        let copied = container
+            .copy_range(inode_file, 10, inode_part, 5, chunk, false)
+            .unwrap();
+        assert_eq!(copied, chunk);
+        let mut read = Vec::new();
+        container.read(inode_part, 0, 2 * chunk, &mut read).unwrap();
+        assert_eq!(read[..5], [0; 5]);
+        assert_eq!(read[5..], expected[10..10 + DATA_CHUNK_SIZE]);
+        let err = container
+            .copy_range(inode_file, 0, inode_file, chunk / 2, chunk, false)
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<FsError>(),
+            Some(&FsError::InvalidArgument)
+        );
+
+        //A clone shares the full blocks, only the refcount table, its block map and its
+        //partial block are new
+        let used = used_blocks(&container);
+        container
+            .copy_range(inode_file, 0, inode_clone, 0, 4 * chunk, true)
+            .unwrap();
+        assert_eq!(used_blocks(&container), used + 3);
+        let mut read = Vec::new();
+        container
+            .read(inode_clone, 0, 4 * chunk, &mut read)
+            .unwrap();
+        assert_eq!(read, expected);
+        container.defrag().unwrap();
+
+        //Writing a shared block copies it first
+        let used = used_blocks(&container);
+        container.write(inode_clone, 10, b"coin").unwrap();
+        assert_eq!(used_blocks(&container), used + 1);
+        let mut read = Vec::new();
+        container.read(inode_file, 0, 4 * chunk, &mut read).unwrap();
+        assert_eq!(read, expected);
+        drop(container);
+        let report = Container::fsck(container_name, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        //The sectors still shared survive the deletion of the original
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let used = used_blocks(&container);
+        container.unlink(1, OsStr::new("canard.db")).unwrap();
+        assert_eq!(used_blocks(&container), used - 4);
+        let mut read = Vec::new();
+        container
+            .read(inode_clone, 0, 4 * chunk, &mut read)
+            .unwrap();
+        expected[10..14].copy_from_slice(b"coin");
+        assert_eq!(read, expected);
+        container.truncate(inode_clone, chunk * 5 / 2).unwrap();
+
+        //The refcount table grows a sector at a time with the sectors shared
+        let data = vec![7; 300 * chunk as usize];
+        container.write(inode_copy, 0, &data).unwrap();
+        container
+            .copy_range(inode_copy, 0, inode_part, 0, data.len() as u64, true)
+            .unwrap();
+        let table_size = encoding::refcount_table_size(container.sector_size()) as u64;
+        let last_shared = *container.refcounts.keys().last().unwrap();
+        assert_eq!(
+            container.refcount_table.len() as u64,
+            last_shared / table_size + 1
+        );
+
+        //Defragmenting moves the refcount table too, the container shrinking to what is used
+        for name in ["loutre.db", "saumon.db", "clone.db"] {
+            container.unlink(1, OsStr::new(name)).unwrap();
+        }
+        let report = container.defrag().unwrap();
+        assert!(report.size_after < report.size_before);
+        assert_eq!(container.statfs().free_blocks, 0);
+        drop(container);
+        let report = Container::fsck(container_name, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        remove_file(container_name).unwrap();
+    }
+    #[test]
     fn lookup_name() {
         let container_name = "/tmp/canard_lookup_name";
         let _ = remove_file(container_name);
         let mut container = Container::new(container_name.to_string()).unwrap();
 
         let inode1 = container
-            .create(1, OsStr::new("loutre.txt"), sector::FileType::Regular)
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
             .unwrap();
         let inode2 = container
-            .create(1, OsStr::new("canard.txt"), sector::FileType::Regular)
+            .create(
+                1,
+                OsStr::new("canard.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
             .unwrap();
         let inode_dir = container
-            .create(1, OsStr::new("ocean"), sector::FileType::Directory)
+            .create(
+                1,
+                OsStr::new("ocean"),
+                sector::FileType::Directory,
+                PERMISSIONS,
+            )
             .unwrap();
         let inode3 = container
             .create(
                 inode_dir,
                 OsStr::new("saumon.txt"),
                 sector::FileType::Regular,
+                PERMISSIONS,
             )
             .unwrap();
 
@@ -577,4 +1201,1536 @@ mod tests {
         assert_eq!(name_dir, OsString::from_str("ocean").unwrap());
         remove_file(container_name).unwrap();
     }
+    #[test]
+    fn rmdir() {
+        let container_name = "/tmp/canard_rmdir";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+
+        let inode_dir = container
+            .create(
+                1,
+                OsStr::new("ocean"),
+                sector::FileType::Directory,
+                PERMISSIONS,
+            )
+            .unwrap();
+        //Enough entries to span several DirData sectors
+        for i in 0..DIR_SECTOR_SIZE + 1 {
+            let name = format!("saumon{i}.txt");
+            container
+                .create(
+                    inode_dir,
+                    OsStr::new(&name),
+                    sector::FileType::Regular,
+                    PERMISSIONS,
+                )
+                .unwrap();
+        }
+        let entries = container.readdir(inode_dir, 1, 0).unwrap();
+        assert_eq!(entries.len(), DIR_SECTOR_SIZE + 3);
+
+        let err = container.rmdir(1, OsStr::new("ocean")).unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::NotEmpty));
+        let err = container.unlink(1, OsStr::new("ocean")).unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::IsADirectory));
+        let err = container
+            .rmdir(inode_dir, OsStr::new("saumon0.txt"))
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::NotADirectory));
+
+        for i in 0..DIR_SECTOR_SIZE + 1 {
+            let name = format!("saumon{i}.txt");
+            container.unlink(inode_dir, OsStr::new(&name)).unwrap();
+        }
+        container.rmdir(1, OsStr::new("ocean")).unwrap();
+        assert!(container.lookup(1, OsStr::new("ocean")).unwrap().is_none());
+        assert!(container.find_ino_sector(inode_dir).is_err());
+
+        //Only the bitmap, the root metadata, its DirData sector and the inode table remain in use
+        let mut empty_count = 0;
+        for i in 0..container.metadata.sector_count {
+            if let Sector::Empty(_) = container.read_sector(i).unwrap() {
+                empty_count += 1;
+            }
+        }
+        assert_eq!(empty_count, container.metadata.sector_count - 4);
+
+        let err = container.rmdir(1, OsStr::new("ocean")).unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::NotFound));
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn rename() {
+        let container_name = "/tmp/canard_rename";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+
+        let inode_ocean = container
+            .create(
+                1,
+                OsStr::new("ocean"),
+                sector::FileType::Directory,
+                PERMISSIONS,
+            )
+            .unwrap();
+        let inode_deep = container
+            .create(
+                inode_ocean,
+                OsStr::new("deep"),
+                sector::FileType::Directory,
+                PERMISSIONS,
+            )
+            .unwrap();
+        let inode1 = container
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        let inode2 = container
+            .create(
+                1,
+                OsStr::new("canard.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        container.write(inode1, 0, &[1; 10]).unwrap();
+
+        //Cross-directory move
+        container
+            .rename(
+                1,
+                OsStr::new("loutre.txt"),
+                inode_ocean,
+                OsStr::new("saumon.txt"),
+                0,
+            )
+            .unwrap();
+        assert!(container
+            .lookup(1, OsStr::new("loutre.txt"))
+            .unwrap()
+            .is_none());
+        let (ino, _) = container
+            .lookup(inode_ocean, OsStr::new("saumon.txt"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(ino, inode1);
+        let name = container.lookup_name(inode1).unwrap();
+        assert_eq!(name, OsString::from_str("saumon.txt").unwrap());
+
+        //RENAME_NOREPLACE refuses an existing target
+        let err = container
+            .rename(
+                inode_ocean,
+                OsStr::new("saumon.txt"),
+                1,
+                OsStr::new("canard.txt"),
+                RENAME_NOREPLACE,
+            )
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::AlreadyExists));
+
+        //RENAME_EXCHANGE swaps both names
+        container
+            .rename(
+                inode_ocean,
+                OsStr::new("saumon.txt"),
+                1,
+                OsStr::new("canard.txt"),
+                RENAME_EXCHANGE,
+            )
+            .unwrap();
+        let (ino, _) = container
+            .lookup(1, OsStr::new("canard.txt"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(ino, inode1);
+        let (ino, _) = container
+            .lookup(inode_ocean, OsStr::new("saumon.txt"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(ino, inode2);
+        let name = container.lookup_name(inode2).unwrap();
+        assert_eq!(name, OsString::from_str("saumon.txt").unwrap());
+
+        //Replacing an existing target frees the replaced file
+        container
+            .rename(
+                1,
+                OsStr::new("canard.txt"),
+                inode_ocean,
+                OsStr::new("saumon.txt"),
+                0,
+            )
+            .unwrap();
+        let (ino, _) = container
+            .lookup(inode_ocean, OsStr::new("saumon.txt"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(ino, inode1);
+        assert!(container
+            .lookup(1, OsStr::new("canard.txt"))
+            .unwrap()
+            .is_none());
+        assert!(container.find_ino_sector(inode2).is_err());
+        let mut read_data = Vec::new();
+        container.read(inode1, 0, 10, &mut read_data).unwrap();
+        assert_eq!(read_data, vec![1; 10]);
+
+        //A directory cannot be moved into its own subtree
+        let err = container
+            .rename(1, OsStr::new("ocean"), inode_deep, OsStr::new("ocean"), 0)
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<FsError>(),
+            Some(&FsError::InvalidArgument)
+        );
+        let err = container
+            .rename(
+                inode_ocean,
+                OsStr::new("saumon.txt"),
+                1,
+                OsStr::new("ocean"),
+                0,
+            )
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::IsADirectory));
+
+        //Moving a directory updates its parent
+        container
+            .rename(inode_ocean, OsStr::new("deep"), 1, OsStr::new("abyss"), 0)
+            .unwrap();
+        let name = container.lookup_name(inode_deep).unwrap();
+        assert_eq!(name, OsString::from_str("abyss").unwrap());
+        let err = container
+            .rename(1, OsStr::new("abyss"), 1, OsStr::new("ocean"), 0)
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::NotEmpty));
+
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn inode_table() {
+        let container_name = "/tmp/canard_inode_table";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+
+        //Enough inodes to span several inode table sectors
+        let mut inodes = Vec::new();
+        for i in 0..INODE_TABLE_SIZE * 2 {
+            let name = format!("loutre{i}.txt");
+            let ino = container
+                .create(1, OsStr::new(&name), sector::FileType::Regular, PERMISSIONS)
+                .unwrap();
+            inodes.push(ino);
+        }
+        assert_eq!(container.inode_table.len(), 3);
+        container.unlink(1, OsStr::new("loutre0.txt")).unwrap();
+        assert!(container.find_ino_sector(inodes[0]).is_err());
+        drop(container);
+
+        //The table is reloaded from the container
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        assert_eq!(container.inode_table.len(), 3);
+        assert!(container.find_ino_sector(inodes[0]).is_err());
+        for ino in &inodes[1..] {
+            assert!(container.find_ino_sector(*ino).is_ok());
+        }
+
+        //A container without inode table gets one built when opened
+        container.metadata.inode_table = None;
+        container.write_metadata().unwrap();
+        drop(container);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        assert!(container.metadata.inode_table.is_some());
+        assert!(container.find_ino_sector(inodes[0]).is_err());
+        for ino in &inodes[1..] {
+            let (_sector_id, sector) = container.find_ino_sector(*ino).unwrap();
+            assert!(matches!(sector, Sector::FileMetadata(_)));
+        }
+        let name = container.lookup_name(inodes[5]).unwrap();
+        assert_eq!(name, OsString::from_str("loutre5.txt").unwrap());
+
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn permissions() {
+        let container_name = "/tmp/canard_permissions";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+
+        let inode = container
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        let attr = container.getattr(inode).unwrap().unwrap();
+        assert_eq!(attr.perm, 0o644);
+        assert_eq!(attr.uid, 1000);
+        assert_eq!(attr.gid, 1000);
+
+        //chmod then chown
+        container
+            .set_permissions(inode, Some(0o100600), None, None)
+            .unwrap();
+        container
+            .set_permissions(inode, None, Some(0), Some(42))
+            .unwrap();
+        let attr = container.getattr(inode).unwrap().unwrap();
+        assert_eq!(attr.perm, 0o600);
+        assert_eq!(attr.uid, 0);
+        assert_eq!(attr.gid, 42);
+
+        //Setgid directories pass their group down
+        let inode_dir = container
+            .create(
+                1,
+                OsStr::new("ocean"),
+                sector::FileType::Directory,
+                Permissions {
+                    mode: 0o2775,
+                    uid: 1000,
+                    gid: 100,
+                },
+            )
+            .unwrap();
+        let inode_sub = container
+            .create(
+                inode_dir,
+                OsStr::new("deep"),
+                sector::FileType::Directory,
+                PERMISSIONS,
+            )
+            .unwrap();
+        let attr = container.getattr(inode_sub).unwrap().unwrap();
+        assert_eq!(attr.perm, 0o2644);
+        assert_eq!(attr.gid, 100);
+        let inode_file = container
+            .create(
+                inode_dir,
+                OsStr::new("saumon.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        let attr = container.getattr(inode_file).unwrap().unwrap();
+        assert_eq!(attr.perm, 0o644);
+        assert_eq!(attr.gid, 100);
+
+        //Metadata written without permissions keeps the historical ones
+        let (sector_id, _sector) = container.find_ino_sector(inode).unwrap();
+        container
+            .write_sector(
+                sector_id,
+                &Sector::FileMetadata(FileMetadata::new(inode, Some(1))),
+            )
+            .unwrap();
+        let attr = container.getattr(inode).unwrap().unwrap();
+        assert_eq!(attr.perm, 0o777);
+
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn timestamps() {
+        let container_name = "/tmp/canard_timestamps";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+
+        let before = SystemTime::now();
+        let inode = container
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        let attr = container.getattr(inode).unwrap().unwrap();
+        assert!(attr.crtime >= before);
+        assert_eq!(attr.atime, attr.crtime);
+        assert_eq!(attr.mtime, attr.crtime);
+        assert_eq!(attr.ctime, attr.crtime);
+        let root_attr = container.getattr(1).unwrap().unwrap();
+        assert!(root_attr.mtime >= before);
+
+        //touch -d with a date before the epoch
+        let old = UNIX_EPOCH - Duration::new(86_400, 500);
+        container.set_times(inode, Some(old), Some(old)).unwrap();
+        let attr = container.getattr(inode).unwrap().unwrap();
+        assert_eq!(attr.atime, old);
+        assert_eq!(attr.mtime, old);
+        assert!(attr.ctime >= before);
+
+        //write moves mtime and ctime, read then moves atime
+        container.write(inode, 0, &[1; 10]).unwrap();
+        let attr = container.getattr(inode).unwrap().unwrap();
+        assert!(attr.mtime >= before);
+        assert_eq!(attr.atime, old);
+        let mut read_data = Vec::new();
+        container.read(inode, 0, 10, &mut read_data).unwrap();
+        let attr = container.getattr(inode).unwrap().unwrap();
+        assert!(attr.atime >= attr.mtime);
+
+        //truncate moves mtime
+        let old = UNIX_EPOCH + Duration::from_secs(1000);
+        container.set_times(inode, None, Some(old)).unwrap();
+        container.truncate(inode, 5).unwrap();
+        let attr = container.getattr(inode).unwrap().unwrap();
+        assert!(attr.mtime >= before);
+
+        //even to the same size, as open with O_TRUNC on an empty file
+        container.set_times(inode, None, Some(old)).unwrap();
+        container.truncate(inode, 5).unwrap();
+        let attr = container.getattr(inode).unwrap().unwrap();
+        assert!(attr.mtime >= before);
+        assert!(attr.ctime >= before);
+
+        //unlink moves the parent mtime
+        container.set_times(1, None, Some(old)).unwrap();
+        container.unlink(1, OsStr::new("loutre.txt")).unwrap();
+        let root_attr = container.getattr(1).unwrap().unwrap();
+        assert!(root_attr.mtime >= before);
+
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn symlink() {
+        let container_name = "/tmp/canard_symlink";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+
+        //Short target stored inline
+        let inode_short = container
+            .symlink(1, OsStr::new("current"), OsStr::new("v2"), 1000, 1000)
+            .unwrap();
+        let attr = container.getattr(inode_short).unwrap().unwrap();
+        assert_eq!(attr.filetype, FileType::Symlink);
+        assert_eq!(attr.size, 2);
+        assert_eq!(attr.perm, 0o777);
+        assert_eq!(container.readlink(inode_short).unwrap(), b"v2");
+        let (ino, filetype) = container.lookup(1, OsStr::new("current")).unwrap().unwrap();
+        assert_eq!(ino, inode_short);
+        assert_eq!(filetype, FileType::Symlink);
+
+        //Long target stored in a FileData chain
+        let long_target = "ocean/".repeat(DATA_CHUNK_SIZE / 2);
+        let inode_long = container
+            .symlink(1, OsStr::new("deep"), OsStr::new(&long_target), 1000, 1000)
+            .unwrap();
+        let attr = container.getattr(inode_long).unwrap().unwrap();
+        assert_eq!(attr.size, long_target.len() as u64);
+        assert_eq!(
+            container.readlink(inode_long).unwrap(),
+            long_target.as_bytes()
+        );
+
+        //readlink on something else than a symlink
+        let inode_file = container
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        let err = container.readlink(inode_file).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<FsError>(),
+            Some(&FsError::InvalidArgument)
+        );
+
+        //unlink frees the chain of long targets
+        let (metadata_sector_id, sector) = container.find_ino_sector(inode_long).unwrap();
+        let Sector::SymlinkMetadata(symlink_metadata) = sector else {
+            panic!("Sector is not SymlinkMetadata.");
+        };
+        let first_sector = symlink_metadata.metadata().first_sector().unwrap();
+        container.unlink(1, OsStr::new("deep")).unwrap();
+        assert!(matches!(
+            container.read_sector(metadata_sector_id).unwrap(),
+            Sector::Empty(_)
+        ));
+        assert!(matches!(
+            container.read_sector(first_sector).unwrap(),
+            Sector::Empty(_)
+        ));
+
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn link() {
+        let container_name = "/tmp/canard_link";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+
+        let inode_dir = container
+            .create(
+                1,
+                OsStr::new("mare"),
+                sector::FileType::Directory,
+                PERMISSIONS,
+            )
+            .unwrap();
+        assert_eq!(container.getattr(1).unwrap().unwrap().nlink, 3);
+        assert_eq!(container.getattr(inode_dir).unwrap().unwrap().nlink, 2);
+        let inode_file = container
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        container.write(inode_file, 0, b"canard").unwrap();
+        let (metadata_sector_id, _sector) = container.find_ino_sector(inode_file).unwrap();
+
+        //A second name in another directory
+        container
+            .link(inode_file, inode_dir, OsStr::new("castor.txt"))
+            .unwrap();
+        assert_eq!(container.getattr(inode_file).unwrap().unwrap().nlink, 2);
+        let (ino, _filetype) = container
+            .lookup(inode_dir, OsStr::new("castor.txt"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(ino, inode_file);
+
+        //Errors
+        let err = container
+            .link(inode_file, 1, OsStr::new("mare"))
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::AlreadyExists));
+        let err = container
+            .link(inode_dir, 1, OsStr::new("baie"))
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::NotPermitted));
+
+        //Removing the first name keeps the data and moves the parent
+        container.unlink(1, OsStr::new("loutre.txt")).unwrap();
+        assert_eq!(container.getattr(inode_file).unwrap().unwrap().nlink, 1);
+        assert_eq!(
+            container.lookup_name(inode_file).unwrap(),
+            OsString::from("castor.txt")
+        );
+        let mut data = Vec::new();
+        container.read(inode_file, 0, 6, &mut data).unwrap();
+        assert_eq!(data, b"canard");
+
+        //Removing the last name frees the file
+        container
+            .unlink(inode_dir, OsStr::new("castor.txt"))
+            .unwrap();
+        assert!(matches!(
+            container.read_sector(metadata_sector_id).unwrap(),
+            Sector::Empty(_)
+        ));
+
+        //Moving and removing a directory updates the subdirectory count
+        let inode_sub = container
+            .create(
+                1,
+                OsStr::new("baie"),
+                sector::FileType::Directory,
+                PERMISSIONS,
+            )
+            .unwrap();
+        assert_eq!(container.getattr(1).unwrap().unwrap().nlink, 4);
+        container
+            .rename(1, OsStr::new("baie"), inode_dir, OsStr::new("baie"), 0)
+            .unwrap();
+        assert_eq!(container.getattr(1).unwrap().unwrap().nlink, 3);
+        assert_eq!(container.getattr(inode_dir).unwrap().unwrap().nlink, 3);
+        assert_eq!(container.getattr(inode_sub).unwrap().unwrap().nlink, 2);
+        container.rmdir(inode_dir, OsStr::new("baie")).unwrap();
+        assert_eq!(container.getattr(inode_dir).unwrap().unwrap().nlink, 2);
+
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn xattr() {
+        let container_name = "/tmp/canard_xattr";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let inode_file = container
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        let name = OsStr::new("user.canard");
+
+        let err = container.getxattr(inode_file, name).unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::NoData));
+        container.setxattr(inode_file, name, b"coin", 0).unwrap();
+        assert_eq!(container.getxattr(inode_file, name).unwrap(), b"coin");
+
+        //Creation flags
+        let err = container
+            .setxattr(inode_file, name, b"plouf", XATTR_CREATE)
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::AlreadyExists));
+        let err = container
+            .setxattr(inode_file, OsStr::new("user.oie"), b"", XATTR_REPLACE)
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::NoData));
+        container
+            .setxattr(inode_file, name, b"plouf", XATTR_REPLACE)
+            .unwrap();
+        assert_eq!(container.getxattr(inode_file, name).unwrap(), b"plouf");
+
+        //Values larger than a sector spill into a chain
+        let big_value = vec![42; DATA_CHUNK_SIZE * 3];
+        container
+            .setxattr(inode_file, OsStr::new("user.oie"), &big_value, 0)
+            .unwrap();
+        assert_eq!(
+            container
+                .getxattr(inode_file, OsStr::new("user.oie"))
+                .unwrap(),
+            big_value
+        );
+        assert_eq!(
+            container.listxattr(inode_file).unwrap(),
+            b"user.canard\0user.oie\0"
+        );
+
+        //Limits
+        let err = container
+            .setxattr(inode_file, OsStr::new("canard"), b"", 0)
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::NotSupported));
+        let err = container
+            .setxattr(inode_file, name, &vec![0; 65537], 0)
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::TooBig));
+        let long_name = format!("user.{}", "a".repeat(300));
+        let err = container
+            .getxattr(inode_file, OsStr::new(&long_name))
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::OutOfRange));
+
+        //Removal
+        container.removexattr(inode_file, name).unwrap();
+        assert_eq!(container.listxattr(inode_file).unwrap(), b"user.oie\0");
+        let err = container.removexattr(inode_file, name).unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::NoData));
+
+        //Deleting the file frees the chain
+        let (_sector_id, sector) = container.find_ino_sector(inode_file).unwrap();
+        let xattr_sector = sector.metadata().unwrap().xattr_sector().unwrap();
+        container.unlink(1, OsStr::new("loutre.txt")).unwrap();
+        assert!(matches!(
+            container.read_sector(xattr_sector).unwrap(),
+            Sector::Empty(_)
+        ));
+
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn statfs() {
+        let container_name = "/tmp/canard_statfs";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        //The bitmap and the root
+        let stats = container.statfs();
+        assert_eq!(stats.blocks, 2);
+        assert_eq!(stats.free_blocks, 0);
+        assert_eq!(stats.files, 1);
+        assert_eq!(stats.name_max, FILE_NAME_SIZE as u32 - 1);
+
+        let inode_file = container
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        container
+            .write(inode_file, 0, &[1; DATA_CHUNK_SIZE * 2])
+            .unwrap();
+        let stats = container.statfs();
+        assert_eq!(stats.free_blocks, 0);
+        assert_eq!(stats.files, 2);
+        let blocks = stats.blocks;
+
+        //Deleting the file gives its metadata, block map and data sectors back
+        container.unlink(1, OsStr::new("loutre.txt")).unwrap();
+        let stats = container.statfs();
+        assert_eq!(stats.blocks, blocks);
+        assert_eq!(stats.free_blocks, 4);
+        assert_eq!(stats.files, 5);
+        assert_eq!(stats.free_files, 4);
+
+        //The count is rebuilt from the bitmaps on open
+        drop(container);
+        let container = Container::new(container_name.to_string()).unwrap();
+        assert_eq!(container.statfs().free_blocks, 4);
+
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn max_size() {
+        let container_name = "/tmp/canard_max_size";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let sector_size = SECTOR_SIZE as u64;
+        assert!(container.set_max_size(Some(0)).is_err());
+        container.set_max_size(Some(10 * sector_size)).unwrap();
+        let stats = container.statfs();
+        assert_eq!(stats.blocks, 10);
+        assert_eq!(stats.free_blocks, 8);
+
+        //Bitmap, root, its entries, inode table, file metadata and block map leave 4 sectors for
+        //the data
+        let inode_file = container
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        container
+            .write(inode_file, 0, &[1; DATA_CHUNK_SIZE * 3])
+            .unwrap();
+        let err = container
+            .write(inode_file, 0, &[2; DATA_CHUNK_SIZE * 5])
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::NoSpace));
+        //Nothing was written or allocated
+        let attr = container.getattr(inode_file).unwrap().unwrap();
+        assert_eq!(attr.size, DATA_CHUNK_SIZE as u64 * 3);
+        let mut data = Vec::new();
+        container.read(inode_file, 0, 1, &mut data).unwrap();
+        assert_eq!(data, [1]);
+        assert_eq!(container.statfs().free_blocks, 1);
+        container
+            .write(inode_file, 0, &[2; DATA_CHUNK_SIZE * 4])
+            .unwrap();
+        assert_eq!(container.statfs().free_blocks, 0);
+        assert_eq!(container.metadata.sector_count, 10);
+
+        let err = container
+            .create(
+                1,
+                OsStr::new("mare"),
+                sector::FileType::Directory,
+                PERMISSIONS,
+            )
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::NoSpace));
+        assert!(container.lookup(1, OsStr::new("mare")).unwrap().is_none());
+
+        //The limit is stored in the container
+        drop(container);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        assert_eq!(container.statfs().blocks, 10);
+        container.set_max_size(None).unwrap();
+        container
+            .create(
+                1,
+                OsStr::new("mare"),
+                sector::FileType::Directory,
+                PERMISSIONS,
+            )
+            .unwrap();
+
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn superblock() {
+        let container_name = "/tmp/canard_superblock";
+        let _ = remove_file(container_name);
+        let container = Container::new(container_name.to_string()).unwrap();
+        let mut superblock = Superblock::new();
+        superblock.set_uuid(container.uuid());
+        assert_eq!(container.superblock, superblock);
+        drop(container);
+        let mut bytes = std::fs::read(container_name).unwrap();
+        assert!(bytes.starts_with(b"MINI-FS\0"));
+
+        //Newer format versions are refused
+        bytes[8] = 2;
+        std::fs::write(container_name, &bytes).unwrap();
+        let err = Container::new(container_name.to_string()).err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("Unsupported container format version 2"));
+
+        //So are files that are not containers
+        std::fs::write(container_name, "Whale swim like otters").unwrap();
+        let err = Container::new(container_name.to_string()).err().unwrap();
+        assert!(err.to_string().contains("is not a mini-fs container"));
+
+        //Containers written before the superblock start with the metadata
+        let mut container = legacy_container(container_name);
+        assert_eq!(container.superblock, Superblock::legacy());
+        container
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        drop(container);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        assert!(container
+            .lookup(1, OsStr::new("loutre.txt"))
+            .unwrap()
+            .is_some());
+
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn header_encoding() {
+        let superblock = encoding::encode(&Superblock::new(), SUPERBLOCK_SIZE).unwrap();
+        let expected = [
+            &b"MINI-FS\0"[..],
+            &1u32.to_le_bytes(),
+            &7u64.to_le_bytes(),
+            &(SECTOR_SIZE as u32).to_le_bytes(),
+            &(DATA_CHUNK_SIZE as u32).to_le_bytes(),
+            &256u32.to_le_bytes(),
+            &(DIR_SECTOR_SIZE as u32).to_le_bytes(),
+            &(FILE_NAME_SIZE as u32).to_le_bytes(),
+        ]
+        .concat();
+        assert_eq!(&superblock[..expected.len()], expected);
+        assert!(superblock[expected.len()..].iter().all(|byte| *byte == 0));
+
+        let metadata = Metadata {
+            root_dir_sector: 0,
+            sector_count: 9,
+            first_empty_sector: Some(4),
+            last_empty_sector: Some(6),
+            next_ino: 12,
+            inode_table: Some(2),
+            max_sectors: Some(100),
+        };
+        let buff = encoding::encode(&metadata, METADATA_SIZE).unwrap();
+        let expected = [
+            &0u64.to_le_bytes()[..],
+            &9u64.to_le_bytes(),
+            &[1],
+            &4u64.to_le_bytes(),
+            &[1],
+            &6u64.to_le_bytes(),
+            &12u64.to_le_bytes(),
+            &[1],
+            &2u64.to_le_bytes(),
+            &[1],
+            &100u32.to_le_bytes(),
+        ]
+        .concat();
+        //Metadata fills all of its reserved bytes
+        assert_eq!(buff, expected);
+        let decoded: Metadata = encoding::decode(&buff).unwrap();
+        assert_eq!(encoding::encode(&decoded, METADATA_SIZE).unwrap(), buff);
+    }
+    #[test]
+    fn checksums() {
+        let container_name = "/tmp/canard_checksums";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let inode_file = container
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        container.write(inode_file, 0, b"canard").unwrap();
+        let (_sector_id, sector) = container.find_ino_sector(inode_file).unwrap();
+        let data_sector = sector.metadata().unwrap().first_sector().unwrap();
+        assert_eq!(container.sector_owner(data_sector), Some(inode_file));
+
+        //Flip a bit in the data of the file
+        let offset = container.superblock.sectors_offset() + data_sector * SECTOR_SIZE as u64;
+        drop(container);
+        let mut bytes = std::fs::read(container_name).unwrap();
+        bytes[offset as usize + 40] ^= 1;
+        std::fs::write(container_name, &bytes).unwrap();
+
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let mut data = Vec::new();
+        let err = container.read(inode_file, 0, 6, &mut data).unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::Io));
+        assert!(err
+            .to_string()
+            .contains(&format!("sector {data_sector} (inode {inode_file})")));
+        //The rest of the container is still readable
+        assert!(container.getattr(inode_file).unwrap().is_some());
+        drop(container);
+        //fsck names the owner of the sector too
+        let report = Container::fsck(container_name, false).unwrap();
+        assert!(
+            report
+                .problems
+                .iter()
+                .any(|problem| problem
+                    .contains(&format!("sector {data_sector} (inode {inode_file})")))
+        );
+
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn journal() {
+        let container_name = "/tmp/canard_journal";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let inode_file = container
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        container.write(inode_file, 0, b"canard").unwrap();
+
+        //A failed transaction leaves nothing behind
+        let free_sectors = container.free_sectors;
+        let err = container
+            .transaction(|container| -> anyhow::Result<()> {
+                container.create_inner(
+                    1,
+                    OsStr::new("mare"),
+                    sector::FileType::Directory,
+                    PERMISSIONS,
+                )?;
+                container.write_inner(inode_file, 0, b"otter")?;
+                anyhow::bail!(FsError::Io)
+            })
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::Io));
+        assert!(container.lookup(1, OsStr::new("mare")).unwrap().is_none());
+        assert_eq!(container.free_sectors, free_sectors);
+        let mut data = Vec::new();
+        container.read(inode_file, 0, 6, &mut data).unwrap();
+        assert_eq!(data, b"canard");
+
+        //Crash right after a commit, before the sectors reach their place
+        container.pending = Some(BTreeMap::new());
+        container
+            .create_inner(
+                1,
+                OsStr::new("mare"),
+                sector::FileType::Directory,
+                PERMISSIONS,
+            )
+            .unwrap();
+        container.write_inner(inode_file, 0, b"loutre").unwrap();
+        let records: Vec<_> = container.pending.take().unwrap().into_iter().collect();
+        let journal = container.journal.take().unwrap();
+        journal.commit(&mut container.file, &records).unwrap();
+        drop(container);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        assert!(container.lookup(1, OsStr::new("mare")).unwrap().is_some());
+        let mut data = Vec::new();
+        container.read(inode_file, 0, 6, &mut data).unwrap();
+        assert_eq!(data, b"loutre");
+
+        //A torn commit is ignored
+        container.pending = Some(BTreeMap::new());
+        container.unlink_inner(1, OsStr::new("loutre.txt")).unwrap();
+        let records: Vec<_> = container.pending.take().unwrap().into_iter().collect();
+        let journal = container.journal.take().unwrap();
+        journal.commit(&mut container.file, &records).unwrap();
+        drop(container);
+        let mut bytes = std::fs::read(container_name).unwrap();
+        bytes[SUPERBLOCK_SIZE + 100] ^= 1;
+        std::fs::write(container_name, &bytes).unwrap();
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        assert!(container
+            .lookup(1, OsStr::new("loutre.txt"))
+            .unwrap()
+            .is_some());
+
+        //Writes larger than the journal are committed in several steps
+        let data = (0..DATA_CHUNK_SIZE * 400)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        container.write(inode_file, 0, &data).unwrap();
+        drop(container);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let attr = container.getattr(inode_file).unwrap().unwrap();
+        assert_eq!(attr.size, data.len() as u64);
+        let mut read_data = Vec::new();
+        container
+            .read(inode_file, 0, data.len() as u64, &mut read_data)
+            .unwrap();
+        assert_eq!(read_data, data);
+
+        //A transaction larger than the journal fails rather than being split
+        let capacity = container.journal.as_ref().unwrap().capacity();
+        let err = container
+            .transaction(|container| {
+                for sector_id in 0..=capacity as u64 {
+                    let sector = container.read_sector(sector_id)?;
+                    container.write_sector(sector_id, &sector)?;
+                }
+                Ok(())
+            })
+            .unwrap_err();
+        assert!(err.to_string().contains("does not fit in the journal"));
+
+        //A crash while a large file is deleted, after some of its sectors are written back,
+        //leaves the file emptied but whole
+        container
+            .setxattr(inode_file, OsStr::new("user.canard"), &[7; 60000], 0)
+            .unwrap();
+        container.flush().unwrap();
+        container.unlink(1, OsStr::new("loutre.txt")).unwrap();
+        assert!(!container.dirty.is_empty());
+        std::mem::forget(container);
+        let report = Container::fsck(container_name, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        //The superblock is committed along with the refcount table it points to
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        assert!(container.superblock.refcount_table().is_none());
+        container.pending = Some(BTreeMap::new());
+        container.enable_refcounts().unwrap();
+        let records: Vec<_> = container.pending.take().unwrap().into_iter().collect();
+        let journal = container.journal.take().unwrap();
+        journal.commit(&mut container.file, &records).unwrap();
+        drop(container);
+        let container = Container::new(container_name.to_string()).unwrap();
+        assert!(container.superblock.refcount_table().is_some());
+        assert_eq!(container.refcount_table.len(), 1);
+        drop(container);
+        let report = Container::fsck(container_name, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn fsck() {
+        let container_name = "/tmp/canard_fsck";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let inode_dir = container
+            .create(
+                1,
+                OsStr::new("mare"),
+                sector::FileType::Directory,
+                PERMISSIONS,
+            )
+            .unwrap();
+        let inode_file = container
+            .create(
+                inode_dir,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        container
+            .write(inode_file, 0, &[1; DATA_CHUNK_SIZE * 3])
+            .unwrap();
+        container
+            .link(inode_file, 1, OsStr::new("otter.txt"))
+            .unwrap();
+        let long_target = OsString::from("canard/".repeat(20));
+        container
+            .symlink(1, OsStr::new("lien"), &long_target, 1000, 1000)
+            .unwrap();
+        container
+            .setxattr(inode_file, OsStr::new("user.canard"), &[2; 300], 0)
+            .unwrap();
+        let inode_other = container
+            .create(
+                1,
+                OsStr::new("whale.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        container.write(inode_other, 0, b"whale").unwrap();
+        container.unlink(1, OsStr::new("whale.txt")).unwrap();
+        drop(container);
+        let report = Container::fsck(container_name, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        //Orphan the directory, leak a sector and break the length of a data sector
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let (_sector_id, root) = container.find_ino_sector(1).unwrap();
+        let Sector::DirMetadata(root) = root else {
+            panic!("The root is not a directory");
+        };
+        let (sector_id, idx, _entry) = container
+            .find_entry(&root, OsStr::new("mare"))
+            .unwrap()
+            .unwrap();
+        container.clear_entry(sector_id, idx).unwrap();
+        let leaked_sector = container.get_empty_sector().unwrap();
+        let (_sector_id, file_sector) = container.find_ino_sector(inode_file).unwrap();
+        let data_sector_id = data_sector_id(&mut container, file_sector.metadata().unwrap(), 0);
+        let Sector::FileData(mut file_data) = container.read_sector(data_sector_id).unwrap() else {
+            panic!("Sector {data_sector_id} is not FileData");
+        };
+        file_data.set_data_length(3);
+        container
+            .write_sector(data_sector_id, &Sector::FileData(file_data))
+            .unwrap();
+        drop(container);
+
+        let report = Container::fsck(container_name, false).unwrap();
+        let problems = report.problems.join("\n");
+        assert!(problems.contains(&format!("Inode {inode_dir} is not reachable")));
+        assert!(problems.contains(&format!("[{leaked_sector}]")));
+        assert!(problems.contains(&format!("Sector {data_sector_id} of inode {inode_file}")));
+        assert!(!report.repaired);
+        //Checking alone changes nothing
+        let report = Container::fsck(container_name, false).unwrap();
+        assert_eq!(report.problems.join("\n"), problems);
+
+        let report = Container::fsck(container_name, true).unwrap();
+        assert!(report.repaired);
+        let report = Container::fsck(container_name, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let (lost_found, filetype) = container
+            .lookup(1, OsStr::new("lost+found"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(filetype, FileType::Directory);
+        let name = format!("#{inode_dir}");
+        assert_eq!(
+            container.lookup(lost_found, OsStr::new(&name)).unwrap(),
+            Some((inode_dir, FileType::Directory))
+        );
+        assert_eq!(container.getattr(lost_found).unwrap().unwrap().nlink, 3);
+        let mut data = Vec::new();
+        container
+            .read(inode_file, 0, DATA_CHUNK_SIZE as u64 * 3, &mut data)
+            .unwrap();
+        assert_eq!(data, [1; DATA_CHUNK_SIZE * 3]);
+
+        //A chain going through the data of another file is cut
+        let inode_other = container
+            .create(
+                1,
+                OsStr::new("whale.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        let (whale_sector_id, mut whale_sector) = container.find_ino_sector(inode_other).unwrap();
+        let whale_metadata = whale_sector.metadata_mut().unwrap();
+        whale_metadata.set_first_sector(data_sector_id);
+        whale_metadata.set_length_byte(DATA_CHUNK_SIZE as u64 * 3);
+        whale_metadata.set_length_sector(3);
+        container
+            .write_sector(whale_sector_id, &whale_sector)
+            .unwrap();
+        drop(container);
+        let report = Container::fsck(container_name, true).unwrap();
+        assert!(
+            report
+                .problems
+                .iter()
+                .any(|problem| problem.contains(&format!("shares sector {data_sector_id}"))),
+            "{:?}",
+            report.problems
+        );
+        let report = Container::fsck(container_name, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        assert_eq!(container.getattr(inode_other).unwrap().unwrap().size, 0);
+        assert_eq!(
+            container.getattr(inode_file).unwrap().unwrap().size,
+            DATA_CHUNK_SIZE as u64 * 3
+        );
+
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn mkfs() {
+        let container_name = "/tmp/canard_mkfs";
+        let _ = remove_file(container_name);
+        let geometry = Geometry {
+            data_chunk_size: 50,
+            dir_sector_size: 2,
+            file_name_size: 100,
+        };
+        let options = MkfsOptions {
+            geometry,
+            label: "otters".to_string(),
+            uuid: Some([7; 16]),
+            size: Some(1 << 20),
+        };
+        let mut container = Container::mkfs(container_name.to_string(), &options).unwrap();
+        assert_eq!(container.geometry(), geometry);
+        let sector_size = container.sector_size();
+        assert_eq!(sector_size, encoding::sector_size(&geometry).unwrap());
+        let stats = container.statfs();
+        assert_eq!(stats.name_max, 99);
+        assert_eq!(stats.block_size, sector_size as u32);
+        let length = std::fs::metadata(container_name).unwrap().len();
+        assert_eq!(
+            length,
+            container.superblock.sectors_offset() + stats.blocks * sector_size as u64
+        );
+        assert!(length <= 1 << 20 && length > (1 << 20) - sector_size as u64);
+        //Every group starts with its bitmap, the first one is followed by the root
+        let groups = stats
+            .blocks
+            .div_ceil(container.superblock.group_size().unwrap());
+        assert_eq!(stats.free_blocks, stats.blocks - groups - 1);
+
+        //Names and data use the recorded geometry
+        let long_name = "l".repeat(99);
+        let inode_file = container
+            .create(
+                1,
+                OsStr::new(&long_name),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        assert!(container
+            .create(
+                1,
+                OsStr::new(&"l".repeat(100)),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .is_err());
+        for i in 0..3 {
+            container
+                .create(
+                    1,
+                    OsStr::new(&format!("loutre_{i}")),
+                    sector::FileType::Regular,
+                    PERMISSIONS,
+                )
+                .unwrap();
+        }
+        let data: Vec<u8> = (0..=255).collect();
+        container.write(inode_file, 0, &data).unwrap();
+        assert_eq!(
+            container
+                .find_ino_sector(inode_file)
+                .unwrap()
+                .1
+                .metadata()
+                .unwrap()
+                .length_sector(),
+            6
+        );
+        drop(container);
+
+        //Reopening keeps the geometry and the preallocated sectors
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        assert_eq!(container.geometry(), geometry);
+        assert_eq!(container.label(), "otters");
+        assert_eq!(container.uuid(), [7; 16]);
+        assert_eq!(container.statfs().blocks, stats.blocks);
+        let mut read = Vec::new();
+        container.read(inode_file, 0, 256, &mut read).unwrap();
+        assert_eq!(read, data);
+        assert_eq!(container.readdir(1, 0, 0).unwrap().len(), 6);
+        drop(container);
+        assert!(Container::fsck(container_name, false).unwrap().is_clean());
+
+        //An existing file is never overwritten
+        assert!(Container::mkfs(container_name.to_string(), &options).is_err());
+        let invalid = MkfsOptions {
+            geometry: Geometry {
+                data_chunk_size: 0,
+                ..geometry
+            },
+            ..MkfsOptions::default()
+        };
+        assert!(Container::mkfs("/tmp/canard_mkfs_invalid".to_string(), &invalid).is_err());
+
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn bitmap() {
+        let container_name = "/tmp/canard_bitmap";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        assert!(matches!(
+            container.read_sector(0).unwrap(),
+            Sector::Bitmap(_)
+        ));
+        assert_eq!(container.metadata.root_dir_sector, 1);
+
+        //Data sectors allocated one after the other are contiguous
+        let data_sectors = |container: &mut Container, ino: u64| {
+            let (_sector_id, sector) = container.find_ino_sector(ino).unwrap();
+            let metadata = sector.metadata().unwrap();
+            (0..metadata.length_sector())
+                .map(|block| data_sector_id(container, metadata, block))
+                .collect::<Vec<u64>>()
+        };
+        let inode_file = container
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        container
+            .write(inode_file, 0, &[1; DATA_CHUNK_SIZE * 4])
+            .unwrap();
+        let sectors = data_sectors(&mut container, inode_file);
+        assert_eq!(sectors.len(), 4);
+        assert!(sectors.windows(2).all(|pair| pair[1] == pair[0] + 1));
+
+        //Freed sectors are emptied and handed out again
+        let free_blocks = container.statfs().free_blocks;
+        container.unlink(1, OsStr::new("loutre.txt")).unwrap();
+        assert_eq!(container.statfs().free_blocks, free_blocks + 6);
+        assert!(matches!(
+            container.read_sector(sectors[0]).unwrap(),
+            Sector::Empty(_)
+        ));
+        let inode_file = container
+            .create(
+                1,
+                OsStr::new("saumon.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        container
+            .write(inode_file, 0, &[2; DATA_CHUNK_SIZE * 3])
+            .unwrap();
+        let reused = data_sectors(&mut container, inode_file);
+        assert!(reused.windows(2).all(|pair| pair[1] == pair[0] + 1));
+        //The container did not grow
+        assert_eq!(container.metadata.sector_count, sectors[3] + 1);
+        drop(container);
+        remove_file(container_name).unwrap();
+
+        //A container growing past a group starts the next one with its bitmap
+        let options = MkfsOptions {
+            geometry: Geometry {
+                data_chunk_size: 1,
+                ..Geometry::default()
+            },
+            ..MkfsOptions::default()
+        };
+        let mut container = Container::mkfs(container_name.to_string(), &options).unwrap();
+        let group_size = container.superblock.group_size().unwrap();
+        let inode_file = container
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        let data = vec![3; group_size as usize + 10];
+        container.write(inode_file, 0, &data).unwrap();
+        assert!(matches!(
+            container.read_sector(group_size).unwrap(),
+            Sector::Bitmap(_)
+        ));
+        assert!(!data_sectors(&mut container, inode_file).contains(&group_size));
+        assert_eq!(container.statfs().free_blocks, 0);
+        drop(container);
+        let report = Container::fsck(container_name, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        //fsck finds used sectors marked free and rebuilds the bitmaps
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let mut read = Vec::new();
+        container
+            .read(inode_file, 0, data.len() as u64, &mut read)
+            .unwrap();
+        assert_eq!(read, data);
+        let Sector::Bitmap(mut bitmap) = container.read_sector(0).unwrap() else {
+            panic!("Sector 0 is not a bitmap");
+        };
+        bitmap.set_used(1, false);
+        container.write_sector(0, &Sector::Bitmap(bitmap)).unwrap();
+        drop(container);
+        let report = Container::fsck(container_name, true).unwrap();
+        assert!(
+            report
+                .problems
+                .iter()
+                .any(|problem| problem.contains("Sector 1 is used by inode 1 but marked free")),
+            "{:?}",
+            report.problems
+        );
+        let report = Container::fsck(container_name, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn block_map() {
+        let container_name = "/tmp/canard_block_map";
+        let _ = remove_file(container_name);
+        //One byte per sector, so that small files need several levels of nodes
+        let options = MkfsOptions {
+            geometry: Geometry {
+                data_chunk_size: 1,
+                ..Geometry::default()
+            },
+            ..MkfsOptions::default()
+        };
+        let mut container = Container::mkfs(container_name.to_string(), &options).unwrap();
+        let fan_out = encoding::block_map_fan_out(container.sector_size()) as u64;
+        let inode_file = container
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        let mut data: Vec<u8> = (0..fan_out * fan_out + 10).map(|i| i as u8).collect();
+        container.write(inode_file, 0, &data).unwrap();
+        let (_sector_id, sector) = container.find_ino_sector(inode_file).unwrap();
+        let root = sector.metadata().unwrap().first_sector().unwrap();
+        let Sector::BlockMap(root) = container.read_sector(root).unwrap() else {
+            panic!("Sector {root} is not a BlockMap");
+        };
+        assert_eq!(root.depth(), 2);
+
+        //Random access reads and writes
+        for offset in [0, fan_out - 1, fan_out * fan_out + 3, 7 * fan_out + 5] {
+            container
+                .write(inode_file, offset as i64, &[42, 43])
+                .unwrap();
+            data[offset as usize..offset as usize + 2].copy_from_slice(&[42, 43]);
+            let mut read = Vec::new();
+            container
+                .read(inode_file, offset as i64, 4, &mut read)
+                .unwrap();
+            assert_eq!(read, &data[offset as usize..offset as usize + 4]);
+        }
+        drop(container);
+        let report = Container::fsck(container_name, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let mut read = Vec::new();
+        container
+            .read(inode_file, 0, data.len() as u64, &mut read)
+            .unwrap();
+        assert_eq!(read, data);
+
+        //A file written as a chain of FileData is moved to a block map when accessed
+        let inode_chained = container
+            .create(
+                1,
+                OsStr::new("canard.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        let chain: Vec<u64> = (0..3)
+            .map(|_| container.get_empty_sector().unwrap())
+            .collect();
+        for (idx, sector_id) in chain.iter().enumerate() {
+            let mut file_data = FileData::new(1);
+            file_data.write(&[idx as u8 + 1], 0, 1);
+            file_data.set_data_length(1);
+            if let Some(next) = chain.get(idx + 1) {
+                file_data.set_next(*next);
+            }
+            if let Some(previous) = idx.checked_sub(1) {
+                file_data.set_previous(chain[previous]);
+            }
+            container
+                .write_sector(*sector_id, &Sector::FileData(file_data))
+                .unwrap();
+        }
+        let (metadata_sector_id, mut metadata_sector) =
+            container.find_ino_sector(inode_chained).unwrap();
+        let metadata = metadata_sector.metadata_mut().unwrap();
+        metadata.set_first_sector(chain[0]);
+        metadata.set_length_sector(3);
+        metadata.set_length_byte(3);
+        container
+            .write_sector(metadata_sector_id, &metadata_sector)
+            .unwrap();
+        let mut read = Vec::new();
+        container.read(inode_chained, 1, 2, &mut read).unwrap();
+        assert_eq!(read, [2, 3]);
+        let (_sector_id, sector) = container.find_ino_sector(inode_chained).unwrap();
+        let metadata = sector.metadata().unwrap();
+        assert!(container
+            .is_block_map(metadata.first_sector().unwrap())
+            .unwrap());
+        let mapped = container
+            .mapped_sectors(metadata.first_sector(), 0..3)
+            .unwrap();
+        assert_eq!(mapped, chain.into_iter().map(Some).collect::<Vec<_>>());
+        container.write(inode_chained, 3, &[4]).unwrap();
+        drop(container);
+        let report = Container::fsck(container_name, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        remove_file(container_name).unwrap();
+    }
+    #[test]
+    fn cache() {
+        let container_name = "/tmp/canard_cache";
+        let _ = remove_file(container_name);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let inode_file = container
+            .create(
+                1,
+                OsStr::new("loutre.txt"),
+                sector::FileType::Regular,
+                PERMISSIONS,
+            )
+            .unwrap();
+        container.flush().unwrap();
+
+        //Changes only reach the file when written back
+        let size = std::fs::metadata(container_name).unwrap().len();
+        container
+            .write(inode_file, 0, &[1; DATA_CHUNK_SIZE * 3])
+            .unwrap();
+        assert_eq!(std::fs::metadata(container_name).unwrap().len(), size);
+        container.flush().unwrap();
+        assert!(std::fs::metadata(container_name).unwrap().len() > size);
+
+        //Cached sectors are read without I/O
+        assert!(container.getattr(1).unwrap().is_some());
+        let offset = container.superblock.sectors_offset() as usize
+            + container.metadata.root_dir_sector as usize * container.sector_size();
+        let mut bytes = std::fs::read(container_name).unwrap();
+        bytes[offset + 10] ^= 1;
+        std::fs::write(container_name, &bytes).unwrap();
+        assert!(container.getattr(1).unwrap().is_some());
+        //Until they are evicted
+        container.set_cache_size(0);
+        let err = container.getattr(1).unwrap_err();
+        assert_eq!(err.downcast_ref::<FsError>(), Some(&FsError::Io));
+        bytes[offset + 10] ^= 1;
+        std::fs::write(container_name, &bytes).unwrap();
+        assert!(container.getattr(1).unwrap().is_some());
+
+        //Closing the container writes it back
+        container.write(inode_file, 0, &[2; 5]).unwrap();
+        drop(container);
+        let mut container = Container::new(container_name.to_string()).unwrap();
+        let mut data = Vec::new();
+        container.read(inode_file, 0, 6, &mut data).unwrap();
+        assert_eq!(data, [2, 2, 2, 2, 2, 1]);
+
+        remove_file(container_name).unwrap();
+    }
 }
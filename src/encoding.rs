@@ -0,0 +1,190 @@
+//! On-disk encoding of the container.
+//!
+//! Every structure is written with the same explicit encoding, so the format depends on the
+//! order of the fields, never on how rustc lays out the Rust types in memory:
+//!
+//! - `u8`, `u16`, `u32`, `u64` and `i64` are little-endian, on 1, 2, 4, 8 and 8 bytes.
+//! - `bool` is one byte, 0 or 1.
+//! - `Option<T>` is a one-byte tag, 0 for `None` and 1 for `Some`, followed by `T` if present.
+//! - An enum is its variant index as a `u32`, followed by the fields of the variant.
+//! - `Vec`, `String`, their `heapless` counterparts and bytes stored as `Bytes` are their
+//!   length as a `u64`, followed by their items.
+//! - Structures are their fields in declaration order, without any padding.
+//!
+//! The container file is laid out as follows:
+//!
+//! | Offset           | Size                | Content                       |
+//! |------------------|---------------------|-------------------------------|
+//! | 0                | `SUPERBLOCK_SIZE`   | `Superblock`, zero padded     |
+//! | 128              | 16 + r × (8 + S)    | Journal of r records          |
+//! | J                | `METADATA_SIZE`     | `Metadata`, zero padded       |
+//! | J + 56 + n × S   | S                   | `Sector` n, zero padded       |
+//!
+//! S is the sector size recorded in the superblock, 320 with the default geometry, and J is
+//! the end of the journal. Containers from before the superblock have no superblock and start
+//! with `Metadata`. Containers without the journal feature have `Metadata` right after the
+//! superblock.
+//!
+//! The journal header is the number of committed records as a `u32`, then the CRC32C of
+//! the records as a `u32`. Each record is its target as a `u64`, `u64::MAX` for `Metadata`
+//! and `u64::MAX - 1` for the `Superblock`, then the image written there, zero padded to S.
+//!
+//! A `Sector` starts with its variant index: 0 `Empty`, 1 `FileMetadata`, 2 `FileData`,
+//! 3 `DirMetadata`, 4 `DirData`, 5 `InodeTable`, 6 `SymlinkMetadata`, 7 `XattrData`,
+//! 8 `Bitmap`, 9 `BlockMap` and 10 `RefcountTable`.
+//! New variants and new fields must only be appended, so that older sectors decode the
+//! zero padding as `None` or 0.
+//!
+//! With the bitmap feature, sectors are split into groups of 8 × `bitmap_size(S)` sectors.
+//! The first sector of each group is a `Bitmap` whose bit i, least significant first, is set
+//! when sector i of the group is used, the bitmap itself included. Free sectors are `Empty`
+//! without links and `Metadata` has no free list. Without the feature, free sectors form the
+//! doubly linked list of `Empty` sectors from `first_empty_sector` to `last_empty_sector`.
+//!
+//! The data of a regular file is mapped by a tree of `BlockMap` nodes of
+//! `block_map_fan_out(S)` entries, rooted at the first sector of its `FileMetadata`. Files
+//! written before the block map have their first sector pointing to a chain of `FileData`
+//! instead, and are moved to a block map when they are next accessed. Long symlink targets
+//! are always a chain.
+//!
+//! With the refcounts feature, data sectors can be mapped by several files. The superblock
+//! points to a chain of `RefcountTable` sectors of `refcount_table_size(S)` counts, the n-th
+//! one holding the references beyond the first of the sectors `refcount_table_size(S)` × n
+//! and after. A shared sector is copied before it is written.
+//!
+//! With the checksums feature, the last `CHECKSUM_SIZE` bytes of every sector hold the
+//! CRC32C of the bytes before them, little-endian. They are zero otherwise.
+use anyhow::{bail, Result};
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::sector::{
+    DirData, DirEntry, Empty, FileData, FileMetadata, Geometry, InodeTable, Permissions, Sector,
+    SymlinkMetadata, XattrData, INODE_TABLE_SIZE, SYMLINK_INLINE_SIZE,
+};
+
+/// Bytes at the end of each sector reserved for its checksum
+pub const CHECKSUM_SIZE: usize = 4;
+/// Sector sizes are rounded up to a multiple of this
+const SECTOR_ALIGN: usize = 64;
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .allow_trailing_bytes()
+}
+
+/// Encode `value` into exactly `size` bytes, failing if it does not fit.
+pub fn encode<T: Serialize>(value: &T, size: usize) -> Result<Vec<u8>> {
+    let mut buff = options().serialize(value)?;
+    if buff.len() > size {
+        bail!(
+            "Encoded value takes {} bytes, more than the {size} available.",
+            buff.len()
+        );
+    }
+    buff.resize(size, 0);
+    Ok(buff)
+}
+
+/// Encode `value` with no size constraint.
+pub fn encode_unsized<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    Ok(options().serialize(value)?)
+}
+
+/// Decode a value from the beginning of `buff`, ignoring the padding after it.
+pub fn decode<T: DeserializeOwned>(buff: &[u8]) -> Result<T> {
+    Ok(options().deserialize(buff)?)
+}
+
+/// Encode `sector` into `sector_size` bytes, ending with its checksum if `checksum` is set.
+pub fn encode_sector(sector: &Sector, sector_size: usize, checksum: bool) -> Result<Vec<u8>> {
+    let mut buff = encode(sector, sector_size - CHECKSUM_SIZE)?;
+    let crc = if checksum { crc32c::crc32c(&buff) } else { 0 };
+    buff.extend_from_slice(&crc.to_le_bytes());
+    Ok(buff)
+}
+
+/// Check the checksum at the end of an encoded sector.
+pub fn verify_sector(buff: &[u8]) -> bool {
+    let (payload, checksum) = buff.split_at(buff.len().saturating_sub(CHECKSUM_SIZE));
+    crc32c::crc32c(payload).to_le_bytes() == checksum
+}
+
+/// Bytes of the `Bitmap` filling a sector of `sector_size` bytes: what remains after the
+/// variant index, the length of the bits and the checksum.
+pub const fn bitmap_size(sector_size: usize) -> usize {
+    sector_size - 4 - 8 - CHECKSUM_SIZE
+}
+
+/// Entries of a `BlockMap` filling a sector of `sector_size` bytes: what remains after the
+/// variant index, the depth, the length of the entries and the checksum, in entries of an
+/// `Option<u64>`.
+pub const fn block_map_fan_out(sector_size: usize) -> usize {
+    (sector_size - 4 - 1 - 8 - CHECKSUM_SIZE) / 9
+}
+
+/// Counts of a `RefcountTable` filling a sector of `sector_size` bytes: what remains after the
+/// variant index, the next sector, the length of the counts and the checksum, in `u32`.
+pub const fn refcount_table_size(sector_size: usize) -> usize {
+    (sector_size - 4 - 9 - 8 - CHECKSUM_SIZE) / 4
+}
+
+/// Smallest sector size, a multiple of `SECTOR_ALIGN`, holding the largest sector of
+/// `geometry` with its checksum.
+pub fn sector_size(geometry: &Geometry) -> Result<usize> {
+    let mut metadata = FileMetadata::new(u64::MAX, Some(u64::MAX));
+    metadata.set_first_sector(u64::MAX);
+    metadata.set_permissions(Permissions {
+        mode: u16::MAX,
+        uid: u32::MAX,
+        gid: u32::MAX,
+    });
+    metadata.set_nlink(u32::MAX);
+    metadata.set_xattr_sector(Some(u64::MAX));
+    let mut symlink = SymlinkMetadata::new(metadata);
+    symlink.set_inline_target(&[0; SYMLINK_INLINE_SIZE]);
+
+    let mut file_data = FileData::new(geometry.data_chunk_size);
+    file_data.set_next(u64::MAX);
+    file_data.set_previous(u64::MAX);
+    let mut xattr_data = XattrData::new(geometry.data_chunk_size);
+    xattr_data.set_next(Some(u64::MAX));
+    let mut dir_data = DirData::new(geometry.dir_sector_size);
+    dir_data.set_next(u64::MAX);
+    dir_data.set_previous(u64::MAX);
+    for entry in dir_data.entries_mut() {
+        *entry = DirEntry {
+            name: "x".repeat(geometry.file_name_size - 1),
+            ..DirEntry::empty()
+        };
+    }
+    let mut inode_table = InodeTable::new();
+    inode_table.set_next(u64::MAX);
+    for idx in 0..INODE_TABLE_SIZE {
+        inode_table.set_sector(idx, Some(u64::MAX));
+    }
+    let mut empty = Empty::default();
+    empty.set_previous(u64::MAX);
+    empty.set_next(u64::MAX);
+
+    let largest = [
+        Sector::SymlinkMetadata(symlink),
+        Sector::FileData(file_data),
+        Sector::XattrData(xattr_data),
+        Sector::DirData(dir_data),
+        Sector::InodeTable(inode_table),
+        Sector::Empty(empty),
+    ]
+    .iter()
+    .map(|sector| encode_unsized(sector).map(|buff| buff.len()))
+    .collect::<Result<Vec<usize>>>()?
+    .into_iter()
+    .max()
+    .unwrap_or_default();
+    Ok((largest + CHECKSUM_SIZE).next_multiple_of(SECTOR_ALIGN))
+}
+
+mod test;
@@ -0,0 +1,323 @@
+#[cfg(test)]
+mod tests {
+    use crate::encoding::{
+        bitmap_size, block_map_fan_out, decode, encode, encode_sector, refcount_table_size,
+        sector_size, verify_sector, CHECKSUM_SIZE,
+    };
+    use crate::sector::{
+        self, Bitmap, BlockMap, DirData, DirEntry, Empty, FileData, FileMetadata, Geometry,
+        InodeTable, Permissions, RefcountTable, Sector, SymlinkMetadata, XattrData,
+        DATA_CHUNK_SIZE, DIR_SECTOR_SIZE, FILE_NAME_SIZE, INODE_TABLE_SIZE, SECTOR_SIZE,
+        SYMLINK_INLINE_SIZE,
+    };
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// Check that `sector` encodes to `expected` followed by zeros, and decodes back to the
+    /// same bytes.
+    fn assert_pinned(sector: &Sector, expected: &[u8]) {
+        let buff = encode(sector, SECTOR_SIZE).unwrap();
+        assert_eq!(buff.len(), SECTOR_SIZE);
+        assert_eq!(&buff[..expected.len()], expected);
+        assert!(buff[expected.len()..].iter().all(|byte| *byte == 0));
+        let decoded: Sector = decode(&buff).unwrap();
+        assert_eq!(encode(&decoded, SECTOR_SIZE).unwrap(), buff);
+    }
+    fn some_u64(value: u64) -> Vec<u8> {
+        [&[1][..], &value.to_le_bytes()].concat()
+    }
+    fn file_metadata() -> FileMetadata {
+        let mut metadata = FileMetadata::new(7, Some(1));
+        metadata.set_length_byte(420);
+        metadata.increase_length_sector();
+        metadata.set_first_sector(12);
+        metadata.set_permissions(Permissions {
+            mode: 0o644,
+            uid: 1000,
+            gid: 100,
+        });
+        metadata.set_created(UNIX_EPOCH + Duration::new(1_700_000_000, 5));
+        metadata.set_nlink(2);
+        metadata.set_xattr_sector(Some(20));
+        metadata
+    }
+    fn file_metadata_bytes() -> Vec<u8> {
+        let timestamp = [&1_700_000_000i64.to_le_bytes()[..], &5u32.to_le_bytes()].concat();
+        [
+            &7u64.to_le_bytes()[..],
+            &some_u64(1),
+            &420u64.to_le_bytes(),
+            &1u64.to_le_bytes(),
+            &some_u64(12),
+            &[1],
+            &0o644u16.to_le_bytes(),
+            &1000u32.to_le_bytes(),
+            &100u32.to_le_bytes(),
+            &timestamp,
+            &timestamp,
+            &timestamp,
+            &timestamp,
+            &[1],
+            &2u32.to_le_bytes(),
+            &some_u64(20),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn empty() {
+        let mut empty = Empty::default();
+        assert_pinned(&Sector::Empty(Empty::default()), &[0, 0, 0, 0, 0, 0]);
+        empty.set_previous(3);
+        empty.set_next(9);
+        assert_pinned(
+            &Sector::Empty(empty),
+            &[
+                0, 0, 0, 0, //variant
+                1, 3, 0, 0, 0, 0, 0, 0, 0, //previous
+                1, 9, 0, 0, 0, 0, 0, 0, 0, //next
+            ],
+        );
+    }
+    #[test]
+    fn file_metadata_sectors() {
+        let expected = [&1u32.to_le_bytes()[..], &file_metadata_bytes()].concat();
+        assert_pinned(&Sector::FileMetadata(file_metadata()), &expected);
+        let expected = [&3u32.to_le_bytes()[..], &file_metadata_bytes()].concat();
+        assert_pinned(&Sector::DirMetadata(file_metadata()), &expected);
+
+        //Metadata written before the newer fields decode them from the padding
+        let legacy = [&1u32.to_le_bytes()[..], &7u64.to_le_bytes(), &[0; 26]].concat();
+        let mut buff = legacy.clone();
+        buff.resize(SECTOR_SIZE, 0);
+        let Sector::FileMetadata(metadata) = decode(&buff).unwrap() else {
+            panic!("Sector is not FileMetadata.");
+        };
+        assert_eq!(metadata.ino(), 7);
+        assert_eq!(metadata.nlink(), None);
+        assert_eq!(metadata.xattr_sector(), None);
+    }
+    #[test]
+    fn file_data() {
+        let mut file_data = FileData::new(DATA_CHUNK_SIZE);
+        file_data.write(b"canard", 0, 6);
+        file_data.set_data_length(6);
+        file_data.set_next(4);
+        file_data.set_previous(2);
+        let expected = [
+            &2u32.to_le_bytes()[..],
+            &6u64.to_le_bytes(),
+            &some_u64(4),
+            &some_u64(2),
+            &(DATA_CHUNK_SIZE as u64).to_le_bytes(),
+            b"canard",
+        ]
+        .concat();
+        assert_pinned(&Sector::FileData(file_data), &expected);
+    }
+    #[test]
+    fn dir_data() {
+        let mut dir_data = DirData::new(DIR_SECTOR_SIZE);
+        dir_data.set_next(5);
+        dir_data.entries_mut()[1] = DirEntry {
+            ino: 8,
+            name: String::from("loutre"),
+            filetype: sector::FileType::Symlink,
+            empty: false,
+        };
+        let empty_entry = [
+            &0u64.to_le_bytes()[..],
+            &0u64.to_le_bytes(),
+            &0u32.to_le_bytes(),
+            &[1],
+        ]
+        .concat();
+        let entry = [
+            &8u64.to_le_bytes()[..],
+            &6u64.to_le_bytes(),
+            b"loutre",
+            &2u32.to_le_bytes(),
+            &[0],
+        ]
+        .concat();
+        let expected = [
+            &4u32.to_le_bytes()[..],
+            &some_u64(5),
+            &[0],
+            &(DIR_SECTOR_SIZE as u64).to_le_bytes(),
+            &empty_entry,
+            &entry,
+            &empty_entry,
+            &empty_entry,
+            &empty_entry,
+        ]
+        .concat();
+        assert_pinned(&Sector::DirData(dir_data), &expected);
+    }
+    #[test]
+    fn inode_table() {
+        let mut inode_table = InodeTable::new();
+        inode_table.set_sector(1, Some(6));
+        let expected = [
+            &5u32.to_le_bytes()[..],
+            &[0],
+            &(INODE_TABLE_SIZE as u64).to_le_bytes(),
+            &[0],
+            &some_u64(6),
+            &[0; INODE_TABLE_SIZE - 2],
+        ]
+        .concat();
+        assert_pinned(&Sector::InodeTable(inode_table), &expected);
+    }
+    #[test]
+    fn symlink_metadata() {
+        let mut symlink_metadata = SymlinkMetadata::new(file_metadata());
+        assert!(symlink_metadata.set_inline_target(b"v2"));
+        let expected = [
+            &6u32.to_le_bytes()[..],
+            &file_metadata_bytes(),
+            &2u64.to_le_bytes(),
+            b"v2",
+        ]
+        .concat();
+        assert_pinned(&Sector::SymlinkMetadata(symlink_metadata), &expected);
+    }
+    #[test]
+    fn xattr_data() {
+        let mut xattr_data = XattrData::new(DATA_CHUNK_SIZE);
+        xattr_data.set_data(b"oie");
+        xattr_data.set_next(Some(11));
+        let expected = [
+            &7u32.to_le_bytes()[..],
+            &3u64.to_le_bytes(),
+            &some_u64(11),
+            &(DATA_CHUNK_SIZE as u64).to_le_bytes(),
+            b"oie",
+        ]
+        .concat();
+        assert_pinned(&Sector::XattrData(xattr_data), &expected);
+    }
+    #[test]
+    fn bitmap() {
+        let size = bitmap_size(SECTOR_SIZE);
+        let mut bitmap = Bitmap::new(size);
+        bitmap.set_used(0, true);
+        bitmap.set_used(9, true);
+        let mut bits = vec![0; size];
+        bits[0] = 1;
+        bits[1] = 2;
+        let expected = [&8u32.to_le_bytes()[..], &(size as u64).to_le_bytes(), &bits].concat();
+        assert_pinned(&Sector::Bitmap(bitmap), &expected);
+        //It fills the sector up to the checksum
+        assert_eq!(expected.len(), SECTOR_SIZE - CHECKSUM_SIZE);
+    }
+    #[test]
+    fn block_map() {
+        let fan_out = block_map_fan_out(SECTOR_SIZE);
+        assert_eq!(fan_out, 33);
+        let mut node = BlockMap::new(1, fan_out);
+        node.set_entry(1, Some(7));
+        let expected = [
+            &9u32.to_le_bytes()[..],
+            &[1],
+            &(fan_out as u64).to_le_bytes(),
+            &[0, 1],
+            &7u64.to_le_bytes(),
+            &[0; 31],
+        ]
+        .concat();
+        assert_pinned(&Sector::BlockMap(node), &expected);
+        //A full node fits in the sector
+        let mut node = BlockMap::new(u8::MAX, fan_out);
+        for idx in 0..fan_out {
+            node.set_entry(idx, Some(u64::MAX));
+        }
+        assert!(encode_sector(&Sector::BlockMap(node), SECTOR_SIZE, true).is_ok());
+    }
+    #[test]
+    fn refcount_table() {
+        let size = refcount_table_size(SECTOR_SIZE);
+        assert_eq!(size, 73);
+        let mut refcount_table = RefcountTable::new(size);
+        refcount_table.set_next(4);
+        refcount_table.set_count(1, 3);
+        let expected = [
+            &10u32.to_le_bytes()[..],
+            &some_u64(4),
+            &(size as u64).to_le_bytes(),
+            &0u32.to_le_bytes(),
+            &3u32.to_le_bytes(),
+            &vec![0; 4 * (size - 2)],
+        ]
+        .concat();
+        assert_pinned(&Sector::RefcountTable(refcount_table), &expected);
+        //A full table fits in the sector
+        let mut refcount_table = RefcountTable::new(size);
+        refcount_table.set_next(u64::MAX);
+        for idx in 0..size {
+            refcount_table.set_count(idx, u32::MAX);
+        }
+        assert!(encode_sector(&Sector::RefcountTable(refcount_table), SECTOR_SIZE, true).is_ok());
+    }
+    #[test]
+    fn largest_sectors_fit() {
+        //Every field set to its largest encoding
+        let name = "a".repeat(FILE_NAME_SIZE);
+        let mut dir_data = DirData::new(DIR_SECTOR_SIZE);
+        dir_data.set_next(u64::MAX);
+        dir_data.set_previous(u64::MAX);
+        for entry in dir_data.entries_mut() {
+            entry.name = String::from(&name);
+        }
+        let mut symlink_metadata = SymlinkMetadata::new(file_metadata());
+        assert!(symlink_metadata.set_inline_target(&[1; SYMLINK_INLINE_SIZE]));
+        let mut inode_table = InodeTable::new();
+        inode_table.set_next(u64::MAX);
+        for idx in 0..INODE_TABLE_SIZE {
+            inode_table.set_sector(idx, Some(u64::MAX));
+        }
+        let mut file_data = FileData::new(DATA_CHUNK_SIZE);
+        file_data.set_next(u64::MAX);
+        file_data.set_previous(u64::MAX);
+        for sector in [
+            Sector::DirData(dir_data),
+            Sector::SymlinkMetadata(symlink_metadata),
+            Sector::InodeTable(inode_table),
+            Sector::FileData(file_data),
+        ] {
+            encode(&sector, SECTOR_SIZE).unwrap();
+        }
+        //Too large values are refused instead of being truncated, a Vec also stores its length
+        assert!(encode(&vec![0u8; SECTOR_SIZE], SECTOR_SIZE).is_err());
+    }
+    #[test]
+    fn sector_checksum() {
+        let mut empty = Empty::default();
+        empty.set_next(9);
+        let sector = Sector::Empty(empty);
+        let mut buff = encode_sector(&sector, SECTOR_SIZE, true).unwrap();
+        let payload = encode(&sector, SECTOR_SIZE - CHECKSUM_SIZE).unwrap();
+        assert_eq!(&buff[..payload.len()], payload);
+        assert_eq!(
+            buff[payload.len()..],
+            crc32c::crc32c(&payload).to_le_bytes()
+        );
+        assert!(verify_sector(&buff));
+        buff[5] ^= 1;
+        assert!(!verify_sector(&buff));
+
+        //Without the feature the checksum bytes stay zero
+        let buff = encode_sector(&sector, SECTOR_SIZE, false).unwrap();
+        assert!(buff[SECTOR_SIZE - CHECKSUM_SIZE..]
+            .iter()
+            .all(|byte| *byte == 0));
+    }
+    #[test]
+    fn default_sector_size() {
+        assert_eq!(sector_size(&Geometry::default()).unwrap(), SECTOR_SIZE);
+        let geometry = Geometry {
+            data_chunk_size: 4000,
+            ..Geometry::default()
+        };
+        assert_eq!(sector_size(&geometry).unwrap(), 4096);
+    }
+}
@@ -0,0 +1,86 @@
+use libc::{
+    c_int, E2BIG, EEXIST, EFBIG, EINVAL, EIO, EISDIR, EMLINK, ENAMETOOLONG, ENODATA, ENOENT,
+    ENOSPC, ENOTDIR, ENOTEMPTY, ENXIO, EOPNOTSUPP, EPERM, ERANGE,
+};
+use std::fmt;
+
+/// Errors raised by the container that must reach the kernel with a specific errno.
+///
+/// They are raised through `anyhow` like any other error and recovered in `FuseFs`
+/// with `downcast_ref`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    NotEmpty,
+    AlreadyExists,
+    InvalidArgument,
+    NameTooLong,
+    NotPermitted,
+    TooManyLinks,
+    NoData,
+    OutOfRange,
+    TooBig,
+    FileTooBig,
+    NotSupported,
+    NoSuchAddress,
+    NoSpace,
+    Io,
+}
+
+impl FsError {
+    pub const fn errno(self) -> c_int {
+        match self {
+            Self::NotFound => ENOENT,
+            Self::NotADirectory => ENOTDIR,
+            Self::IsADirectory => EISDIR,
+            Self::NotEmpty => ENOTEMPTY,
+            Self::AlreadyExists => EEXIST,
+            Self::InvalidArgument => EINVAL,
+            Self::NameTooLong => ENAMETOOLONG,
+            Self::NotPermitted => EPERM,
+            Self::TooManyLinks => EMLINK,
+            Self::NoData => ENODATA,
+            Self::OutOfRange => ERANGE,
+            Self::TooBig => E2BIG,
+            Self::FileTooBig => EFBIG,
+            Self::NotSupported => EOPNOTSUPP,
+            Self::NoSuchAddress => ENXIO,
+            Self::NoSpace => ENOSPC,
+            Self::Io => EIO,
+        }
+    }
+    /// Return the errno carried by `err`, or `default` if it is not an `FsError`.
+    pub fn errno_or(err: &anyhow::Error, default: c_int) -> c_int {
+        err.downcast_ref::<Self>()
+            .map_or(default, |fs_error| fs_error.errno())
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::NotFound => "No such file or directory",
+            Self::NotADirectory => "Not a directory",
+            Self::IsADirectory => "Is a directory",
+            Self::NotEmpty => "Directory not empty",
+            Self::AlreadyExists => "File exists",
+            Self::InvalidArgument => "Invalid argument",
+            Self::NameTooLong => "File name too long",
+            Self::NotPermitted => "Operation not permitted",
+            Self::TooManyLinks => "Too many links",
+            Self::NoData => "No data available",
+            Self::OutOfRange => "Numerical result out of range",
+            Self::TooBig => "Argument list too long",
+            Self::FileTooBig => "File too large",
+            Self::NotSupported => "Operation not supported",
+            Self::NoSuchAddress => "No such device or address",
+            Self::NoSpace => "No space left on device",
+            Self::Io => "Input/output error",
+        };
+        write!(f, "{s}")
+    }
+}
+
+impl std::error::Error for FsError {}
@@ -1,91 +1,91 @@
-use crate::container::Container;
+use crate::container::{Attr, Container};
+use crate::error::FsError;
 use crate::logger::{EventType, Logger};
-use crate::sector;
+use crate::sector::{self, Permissions};
 use anyhow::Result;
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyLseek, Request, TimeOrNow,
+    FileAttr, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyLseek,
+    ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow, FUSE_ROOT_ID,
 };
-use libc::{EIO, ENOENT, ENOSYS};
+use libc::{c_int, EINVAL, EIO, ENOENT, ENOSYS, ERANGE, SEEK_DATA, SEEK_END, SEEK_HOLE, SEEK_SET};
 use std::ffi::OsStr;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
 
 const TTL: Duration = Duration::from_secs(1); // 1 second
+/// Extended attribute whose setting on the root defragments the mounted container
+pub const DEFRAG_XATTR: &str = "user.mini-fs.defrag";
 
 pub struct FuseFs {
     container: Container,
     logger: Logger,
+    /// copy_file_range shares the sectors copied instead of duplicating them
+    reflink: bool,
 }
 
 impl FuseFs {
-    pub fn new(container_name: String, logger: Logger) -> Result<Self> {
+    /// `max_size` replaces the maximum size stored in the container, 0 lifts the limit.
+    /// `cache_size` replaces the default memory budget of the sector cache.
+    /// With `reflink`, copies made with copy_file_range share their sectors until written.
+    pub fn new(
+        container_name: String,
+        max_size: Option<u64>,
+        cache_size: Option<usize>,
+        reflink: bool,
+        logger: Logger,
+    ) -> Result<Self> {
+        let mut container = Container::new(container_name)?;
+        if let Some(max_size) = max_size {
+            container.set_max_size((max_size > 0).then_some(max_size))?;
+        }
+        if let Some(cache_size) = cache_size {
+            container.set_cache_size(cache_size);
+        }
         Ok(Self {
-            container: Container::new(container_name)?,
+            container,
             logger,
+            reflink,
         })
     }
 }
 
 impl Filesystem for FuseFs {
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let Ok(ret) = self.container.lookup(parent, name) else {
-            reply.error(ENOENT);
-            return;
+        let ret = match self.container.lookup(parent, name) {
+            Ok(ret) => ret,
+            Err(err) => {
+                reply.error(errno_or(&err, ENOENT));
+                return;
+            }
         };
         let Some((ino, _filetype)) = ret else {
             reply.error(ENOENT);
             return;
         };
-        let Ok(ret) = self.container.getattr(ino) else {
-            reply.error(ENOENT);
-            return;
+        let ret = match self.container.getattr(ino) {
+            Ok(ret) => ret,
+            Err(err) => {
+                reply.error(errno_or(&err, ENOENT));
+                return;
+            }
         };
         if let Some(file_attr) = ret {
-            let attr: FileAttr = FileAttr {
-                ino: file_attr.ino,
-                size: file_attr.size,
-                blocks: 1,
-                atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-                mtime: UNIX_EPOCH,
-                ctime: UNIX_EPOCH,
-                crtime: UNIX_EPOCH,
-                kind: file_attr.filetype,
-                perm: 0o777,
-                nlink: 1,
-                uid: 501,
-                gid: 20,
-                rdev: 0,
-                flags: 0,
-                blksize: 512,
-            };
+            let attr = to_file_attr(&file_attr);
             reply.entry(&TTL, &attr, 0);
         } else {
             reply.error(ENOENT);
         }
     }
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        let Ok(ret) = self.container.getattr(ino) else {
-            reply.error(ENOENT);
-            return;
+        let ret = match self.container.getattr(ino) {
+            Ok(ret) => ret,
+            Err(err) => {
+                reply.error(errno_or(&err, ENOENT));
+                return;
+            }
         };
         if let Some(file_attr) = ret {
-            let attr: FileAttr = FileAttr {
-                ino,
-                size: file_attr.size,
-                blocks: 1,
-                atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-                mtime: UNIX_EPOCH,
-                ctime: UNIX_EPOCH,
-                crtime: UNIX_EPOCH,
-                kind: file_attr.filetype,
-                perm: 0o777,
-                nlink: 1,
-                uid: 501,
-                gid: 20,
-                rdev: 0,
-                flags: 0,
-                blksize: 512,
-            };
+            let attr = to_file_attr(&file_attr);
             reply.attr(&TTL, &attr);
         } else {
             reply.error(ENOENT);
@@ -105,10 +105,9 @@ impl Filesystem for FuseFs {
     ) {
         let mut data = Vec::new();
         let ret = self.container.read(ino, offset, size as u64, &mut data);
-        if let Ok(_read) = ret {
-            reply.data(&data);
-        } else {
-            reply.error(ENOENT);
+        match ret {
+            Ok(_read) => reply.data(&data),
+            Err(err) => reply.error(errno_or(&err, ENOENT)),
         }
     }
     fn write(
@@ -124,10 +123,9 @@ impl Filesystem for FuseFs {
         reply: fuser::ReplyWrite,
     ) {
         let result = self.container.write(ino, offset, data);
-        if let Ok(written) = result {
-            reply.written(written as u32);
-        } else {
-            reply.error(ENOENT);
+        match result {
+            Ok(written) => reply.written(written as u32),
+            Err(err) => reply.error(errno_or(&err, ENOENT)),
         }
     }
     fn opendir(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
@@ -138,10 +136,9 @@ impl Filesystem for FuseFs {
         } else {
             self.logger.log(EventType::OpenDir, &format!("{ino:?}"));
         }
-        if let Ok(fd) = fd {
-            reply.opened(fd, flags as u32);
-        } else {
-            reply.error(ENOENT);
+        match fd {
+            Ok(fd) => reply.opened(fd, flags as u32),
+            Err(err) => reply.error(errno_or(&err, ENOENT)),
         }
     }
     fn readdir(
@@ -154,8 +151,8 @@ impl Filesystem for FuseFs {
     ) {
         let ret = self.container.readdir(ino, fh, offset);
         match ret {
-            Err(_err) => {
-                reply.error(ENOENT);
+            Err(err) => {
+                reply.error(errno_or(&err, ENOENT));
             }
             Ok(entries) => {
                 for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
@@ -171,42 +168,35 @@ impl Filesystem for FuseFs {
 
     fn create(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
-        _umask: u32,
+        mode: u32,
+        umask: u32,
         _flags: i32,
         reply: fuser::ReplyCreate,
     ) {
-        let ret = self
-            .container
-            .create(parent, name, sector::FileType::Regular);
-        if let Ok(ino) = ret {
-            self.logger
-                .log(EventType::Open, &format!("{name:?} (inode={ino:?})"));
-            let attr: FileAttr = FileAttr {
-                ino,
-                size: 0,
-                blocks: 1,
-                atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-                mtime: UNIX_EPOCH,
-                ctime: UNIX_EPOCH,
-                crtime: UNIX_EPOCH,
-                kind: FileType::RegularFile,
-                perm: 0o777,
-                nlink: 1,
-                uid: 501,
-                gid: 20,
-                rdev: 0,
-                flags: 0,
-                blksize: 512,
-            };
-
-            reply.created(&TTL, &attr, 1, 0, 0);
-        } else {
-            self.logger.log(EventType::Open, &format!("{name:?}"));
-            reply.error(ENOSYS);
+        let ret = self.container.create(
+            parent,
+            name,
+            sector::FileType::Regular,
+            new_permissions(req, mode, umask),
+        );
+        match ret {
+            Ok(ino) => {
+                self.logger
+                    .log(EventType::Open, &format!("{name:?} (inode={ino:?})"));
+                let Ok(Some(file_attr)) = self.container.getattr(ino) else {
+                    reply.error(EIO);
+                    return;
+                };
+                let attr = to_file_attr(&file_attr);
+                reply.created(&TTL, &attr, 1, 0, 0);
+            }
+            Err(err) => {
+                self.logger.log(EventType::Open, &format!("{name:?}"));
+                reply.error(errno_or(&err, ENOSYS));
+            }
         }
     }
     fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
@@ -228,12 +218,13 @@ impl Filesystem for FuseFs {
         flags: u32,
         reply: ReplyEmpty,
     ) {
-        eprintln!(
-            "[Not Implemented] rename(parent: {:#x?}, name: {:?}, newparent: {:#x?}, \
-            newname: {:?}, flags: {})",
-            parent, name, newparent, newname, flags,
-        );
-        reply.error(ENOSYS);
+        let ret = self
+            .container
+            .rename(parent, name, newparent, newname, flags);
+        match ret {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno_or(&err, EIO)),
+        }
     }
     fn mknod(
         &mut self,
@@ -256,27 +247,95 @@ impl Filesystem for FuseFs {
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        fh: u64,
+        _fh: u64,
         offset: i64,
         whence: i32,
         reply: ReplyLseek,
     ) {
-        eprintln!(
-            "[Not Implemented] lseek(ino: {:#x?}, fh: {}, offset: {}, whence: {})",
-            ino, fh, offset, whence
-        );
-        reply.error(ENOSYS);
+        let ret = match whence {
+            SEEK_SET => Ok(offset),
+            SEEK_END => self.container.getattr(ino).and_then(|attr| {
+                let size = attr.map_or(0, |attr| attr.size);
+                Ok(i64::try_from(size)?.saturating_add(offset))
+            }),
+            SEEK_DATA | SEEK_HOLE if offset >= 0 => {
+                let found = if whence == SEEK_DATA {
+                    self.container.seek_data(ino, offset as u64)
+                } else {
+                    self.container.seek_hole(ino, offset as u64)
+                };
+                found.and_then(|found| Ok(i64::try_from(found)?))
+            }
+            //The kernel keeps the position of open files and resolves SEEK_CUR itself
+            _ => Err(FsError::InvalidArgument.into()),
+        };
+        match ret {
+            Ok(position) if position >= 0 => reply.offset(position),
+            Ok(_) => reply.error(EINVAL),
+            Err(err) => reply.error(errno_or(&err, EIO)),
+        }
+    }
+    fn fallocate(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        let (Ok(offset), Ok(length)) = (u64::try_from(offset), u64::try_from(length)) else {
+            reply.error(EINVAL);
+            return;
+        };
+        match self.container.fallocate(ino, offset, length, mode) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno_or(&err, EIO)),
+        }
+    }
+    fn copy_file_range(
+        &mut self,
+        _req: &Request<'_>,
+        ino_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        _fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        let (Ok(offset_in), Ok(offset_out)) = (u64::try_from(offset_in), u64::try_from(offset_out))
+        else {
+            reply.error(EINVAL);
+            return;
+        };
+        if flags != 0 {
+            reply.error(EINVAL);
+            return;
+        }
+        //The reply counts the bytes copied on 32 bits, the caller asks again for the rest
+        let len = len.min(u64::from(u32::MAX));
+        let result =
+            self.container
+                .copy_range(ino_in, offset_in, ino_out, offset_out, len, self.reflink);
+        match result {
+            Ok(copied) => reply.written(copied as u32),
+            Err(err) => reply.error(errno_or(&err, EIO)),
+        }
     }
     fn setattr(
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
         size: Option<u64>,
-        _atime: Option<TimeOrNow>,
-        _mtime: Option<TimeOrNow>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
         _ctime: Option<SystemTime>,
         _fh: Option<u64>,
         _crtime: Option<SystemTime>,
@@ -285,11 +344,26 @@ impl Filesystem for FuseFs {
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
+        if mode.is_some() || uid.is_some() || gid.is_some() {
+            let ret = self.container.set_permissions(ino, mode, uid, gid);
+            if let Err(err) = ret {
+                reply.error(errno_or(&err, EIO));
+                return;
+            }
+        }
         if let Some(size) = size {
-            let ret = self.container.truncate(ino, size);
-            if ret.is_err() {
-                eprintln!("{ret:?}");
-                reply.error(EIO);
+            if let Err(err) = self.container.truncate(ino, size) {
+                reply.error(errno_or(&err, EIO));
+                return;
+            }
+        }
+        //After the size, whose change stamps the file with the current time
+        if atime.is_some() || mtime.is_some() {
+            let ret =
+                self.container
+                    .set_times(ino, atime.map(to_system_time), mtime.map(to_system_time));
+            if let Err(err) = ret {
+                reply.error(errno_or(&err, EIO));
                 return;
             }
         }
@@ -298,23 +372,7 @@ impl Filesystem for FuseFs {
             return;
         };
         if let Some(file_attr) = ret {
-            let attr: FileAttr = FileAttr {
-                ino,
-                size: file_attr.size,
-                blocks: 1,
-                atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-                mtime: UNIX_EPOCH,
-                ctime: UNIX_EPOCH,
-                crtime: UNIX_EPOCH,
-                kind: file_attr.filetype,
-                perm: 0o777,
-                nlink: 1,
-                uid: 501,
-                gid: 20,
-                rdev: 0,
-                flags: 0,
-                blksize: 512,
-            };
+            let attr = to_file_attr(&file_attr);
             reply.attr(&TTL, &attr);
         } else {
             reply.error(ENOENT);
@@ -368,50 +426,254 @@ impl Filesystem for FuseFs {
         } else {
             self.logger.log(EventType::Close, &format!("{ino:?}"));
         }
-        reply.ok();
+        match self.container.flush() {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno_or(&err, EIO)),
+        }
     }
-    fn mkdir(
+    fn fsync(
         &mut self,
         _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.container.flush() {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno_or(&err, EIO)),
+        }
+    }
+    fn fsyncdir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.container.flush() {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno_or(&err, EIO)),
+        }
+    }
+    fn destroy(&mut self) {
+        if let Err(err) = self.container.flush() {
+            eprintln!("Failed to write back the container: {err:?}");
+        }
+    }
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
-        _umask: u32,
+        mode: u32,
+        umask: u32,
         reply: ReplyEntry,
     ) {
-        let ret = self
-            .container
-            .create(parent, name, sector::FileType::Directory);
-        if let Ok(ino) = ret {
-            let attr: FileAttr = FileAttr {
-                ino,
-                size: 0,
-                blocks: 1,
-                atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-                mtime: UNIX_EPOCH,
-                ctime: UNIX_EPOCH,
-                crtime: UNIX_EPOCH,
-                kind: FileType::Directory,
-                perm: 0o777,
-                nlink: 1,
-                uid: 501,
-                gid: 20,
-                rdev: 0,
-                flags: 0,
-                blksize: 512,
-            };
-
-            reply.entry(&TTL, &attr, 1);
-        } else {
-            reply.error(ENOSYS);
+        let ret = self.container.create(
+            parent,
+            name,
+            sector::FileType::Directory,
+            new_permissions(req, mode, umask),
+        );
+        match ret {
+            Ok(ino) => {
+                let Ok(Some(file_attr)) = self.container.getattr(ino) else {
+                    reply.error(EIO);
+                    return;
+                };
+                let attr = to_file_attr(&file_attr);
+                reply.entry(&TTL, &attr, 1);
+            }
+            Err(err) => reply.error(errno_or(&err, ENOSYS)),
+        }
+    }
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        let ret =
+            self.container
+                .symlink(parent, link_name, target.as_os_str(), req.uid(), req.gid());
+        match ret {
+            Ok(ino) => {
+                let Ok(Some(file_attr)) = self.container.getattr(ino) else {
+                    reply.error(EIO);
+                    return;
+                };
+                let attr = to_file_attr(&file_attr);
+                reply.entry(&TTL, &attr, 0);
+            }
+            Err(err) => reply.error(errno_or(&err, EIO)),
+        }
+    }
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.container.readlink(ino) {
+            Ok(target) => reply.data(&target),
+            Err(err) => reply.error(errno_or(&err, ENOENT)),
         }
     }
     fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let ret = self.container.unlink(parent, name);
-        if ret.is_ok() {
-            reply.ok();
-        } else {
-            reply.error(ENOSYS);
+        match ret {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno_or(&err, ENOSYS)),
+        }
+    }
+    fn link(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        match self.container.link(ino, newparent, newname) {
+            Ok(()) => {
+                let Ok(Some(file_attr)) = self.container.getattr(ino) else {
+                    reply.error(EIO);
+                    return;
+                };
+                let attr = to_file_attr(&file_attr);
+                reply.entry(&TTL, &attr, 0);
+            }
+            Err(err) => reply.error(errno_or(&err, EIO)),
+        }
+    }
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        let stats = self.container.statfs();
+        reply.statfs(
+            stats.blocks,
+            stats.free_blocks,
+            stats.free_blocks,
+            stats.files,
+            stats.free_files,
+            stats.block_size,
+            stats.name_max,
+            stats.block_size,
+        );
+    }
+    fn setxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        if ino == FUSE_ROOT_ID && name == DEFRAG_XATTR {
+            match self.container.defrag() {
+                Ok(report) => {
+                    self.logger.log(
+                        EventType::Defrag,
+                        &format!(
+                            "{} sectors moved, {} -> {} bytes",
+                            report.moved, report.size_before, report.size_after
+                        ),
+                    );
+                    reply.ok();
+                }
+                Err(err) => reply.error(errno_or(&err, EIO)),
+            }
+            return;
+        }
+        match self.container.setxattr(ino, name, value, flags) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno_or(&err, EIO)),
         }
     }
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        match self.container.getxattr(ino, name) {
+            Ok(value) => reply_xattr(&value, size, reply),
+            Err(err) => reply.error(errno_or(&err, EIO)),
+        }
+    }
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        match self.container.listxattr(ino) {
+            Ok(names) => reply_xattr(&names, size, reply),
+            Err(err) => reply.error(errno_or(&err, EIO)),
+        }
+    }
+    fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.container.removexattr(ino, name) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno_or(&err, EIO)),
+        }
+    }
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let ret = self.container.rmdir(parent, name);
+        match ret {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno_or(&err, EIO)),
+        }
+    }
+}
+
+/// Errno to reply with for `err`, printing the I/O errors whose context would be lost.
+fn errno_or(err: &anyhow::Error, default: c_int) -> c_int {
+    let errno = FsError::errno_or(err, default);
+    if errno == EIO {
+        eprintln!("{err:#}");
+    }
+    errno
+}
+
+fn new_permissions(req: &Request<'_>, mode: u32, umask: u32) -> Permissions {
+    Permissions {
+        mode: (mode & !umask & 0o7777) as u16,
+        uid: req.uid(),
+        gid: req.gid(),
+    }
+}
+
+/// A zero `size` asks for the size of the data, a smaller buffer is ERANGE.
+fn reply_xattr(data: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+fn to_system_time(time: TimeOrNow) -> SystemTime {
+    match time {
+        TimeOrNow::SpecificTime(time) => time,
+        TimeOrNow::Now => SystemTime::now(),
+    }
+}
+
+fn to_file_attr(attr: &Attr) -> FileAttr {
+    FileAttr {
+        ino: attr.ino,
+        size: attr.size,
+        blocks: attr.blocks,
+        atime: attr.atime,
+        mtime: attr.mtime,
+        ctime: attr.ctime,
+        crtime: attr.crtime,
+        kind: attr.filetype,
+        perm: attr.perm,
+        nlink: attr.nlink,
+        uid: attr.uid,
+        gid: attr.gid,
+        rdev: 0,
+        flags: 0,
+        blksize: 512,
+    }
 }
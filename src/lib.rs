@@ -1,4 +1,6 @@
 pub mod container;
+pub mod encoding;
+pub mod error;
 pub mod fuse_interface;
 pub mod sector;
 pub mod logger;
@@ -12,6 +12,7 @@ pub enum EventType {
     Close,
     OpenDir,
     CloseDir,
+    Defrag,
 }
 
 impl Logger {
@@ -40,6 +41,7 @@ impl fmt::Display for EventType {
             Self::Close => "Close",
             Self::OpenDir => "OpenDir",
             Self::CloseDir => "CloseDir",
+            Self::Defrag => "Defrag",
         };
         write!(f, "{s}")
     }
@@ -1,24 +1,224 @@
-use clap::Parser;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
 use fuser::MountOption;
-use anyhow::{Context, Result};
 
-use mini_fs::{fuse_interface::FuseFs, logger::Logger};
+use mini_fs::container::{Container, MkfsOptions};
+use mini_fs::fuse_interface::{FuseFs, DEFRAG_XATTR};
+use mini_fs::logger::Logger;
+use mini_fs::sector::Geometry;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Cli {
-    mountpoint : String,
-    container : String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(required = true)]
+    mountpoint: Option<String>,
+    #[arg(required = true)]
+    container: Option<String>,
     #[arg(short = 'n', long)]
-    allow_notification : bool,
+    allow_notification: bool,
+    /// Maximum size of the container in bytes, with an optional K, M or G suffix (0 for no limit)
+    #[arg(short = 's', long, value_parser = parse_size)]
+    max_size: Option<u64>,
+    /// Memory used to cache sectors in bytes, with an optional K, M or G suffix (4M by default)
+    #[arg(short = 'c', long, value_parser = parse_size)]
+    cache_size: Option<u64>,
+    /// Make copy_file_range share the sectors copied until either file writes them
+    #[arg(long)]
+    reflink: bool,
 }
 
-fn main() -> Result<()>{
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check the consistency of a container
+    Fsck {
+        container: String,
+        /// Fix the problems found, moving orphaned inodes to lost+found
+        #[arg(short, long)]
+        repair: bool,
+    },
+    /// Make the data of every file contiguous and shrink a container to the sectors in use
+    Defrag {
+        /// Container, or mountpoint of a mounted container with --online
+        path: String,
+        /// Defragment the container mounted on the path
+        #[arg(long)]
+        online: bool,
+    },
+    /// Create a container with a chosen geometry
+    Mkfs {
+        container: String,
+        /// Bytes of file data held by a sector
+        #[arg(long, default_value_t = Geometry::default().data_chunk_size)]
+        chunk_size: usize,
+        /// Entries held by a directory sector
+        #[arg(long, default_value_t = Geometry::default().dir_sector_size)]
+        dir_entries: usize,
+        /// Maximum length of a name in bytes
+        #[arg(long, default_value_t = Geometry::default().file_name_size - 1)]
+        name_max: usize,
+        /// Name of the container
+        #[arg(short, long, default_value = "")]
+        label: String,
+        /// Identifier of the container, random by default
+        #[arg(short, long, value_parser = parse_uuid)]
+        uuid: Option<[u8; 16]>,
+        /// Bytes to preallocate, with an optional K, M or G suffix
+        #[arg(short, long, value_parser = parse_size)]
+        size: Option<u64>,
+    },
+}
+
+fn parse_size(size: &str) -> Result<u64> {
+    let (number, unit) = match size.char_indices().last() {
+        Some((idx, 'K' | 'k')) => (&size[..idx], 1 << 10),
+        Some((idx, 'M' | 'm')) => (&size[..idx], 1 << 20),
+        Some((idx, 'G' | 'g')) => (&size[..idx], 1 << 30),
+        _ => (size, 1),
+    };
+    let number: u64 = number.parse().context("invalid size")?;
+    number.checked_mul(unit).context("size too large")
+}
+
+/// Parse a UUID written as 32 hexadecimal digits, optionally grouped with dashes.
+fn parse_uuid(uuid: &str) -> Result<[u8; 16]> {
+    let digits: Vec<u8> = uuid.bytes().filter(|byte| *byte != b'-').collect();
+    if digits.len() != 32 {
+        bail!("a UUID has 32 hexadecimal digits");
+    }
+    let mut bytes = [0; 16];
+    for (byte, pair) in bytes.iter_mut().zip(digits.chunks(2)) {
+        let pair = std::str::from_utf8(pair).context("invalid UUID")?;
+        *byte = u8::from_str_radix(pair, 16).context("invalid UUID")?;
+    }
+    Ok(bytes)
+}
+
+fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex: String = uuid.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+fn main() -> Result<()> {
     let appname = "mini-fs";
     let cli = Cli::parse();
-    let options = vec![MountOption::RW, MountOption::FSName(appname.to_string())];
+    match cli.command {
+        Some(Command::Fsck { container, repair }) => return fsck(&container, repair),
+        Some(Command::Defrag { path, online }) => return defrag(&path, online),
+        Some(Command::Mkfs {
+            container,
+            chunk_size,
+            dir_entries,
+            name_max,
+            label,
+            uuid,
+            size,
+        }) => {
+            let options = MkfsOptions {
+                geometry: Geometry {
+                    data_chunk_size: chunk_size,
+                    dir_sector_size: dir_entries,
+                    file_name_size: name_max.saturating_add(1),
+                },
+                label,
+                uuid,
+                size,
+            };
+            return mkfs(container, &options);
+        }
+        None => {}
+    }
+    let (Some(mountpoint), Some(container)) = (cli.mountpoint, cli.container) else {
+        unreachable!("clap requires the mountpoint and the container");
+    };
+    let options = vec![
+        MountOption::RW,
+        MountOption::FSName(appname.to_string()),
+        MountOption::DefaultPermissions,
+    ];
     let logger = Logger::new(appname.to_string(), cli.allow_notification);
-    let fuse_fs = FuseFs::new(cli.container, logger)?;
-    fuser::mount2(fuse_fs, cli.mountpoint, &options).context("fuser::mount2 ")?;
+    let cache_size = cli.cache_size.map(usize::try_from).transpose()?;
+    let fuse_fs = FuseFs::new(container, cli.max_size, cache_size, cli.reflink, logger)?;
+    fuser::mount2(fuse_fs, mountpoint, &options).context("fuser::mount2 ")?;
     Ok(())
 }
+
+fn mkfs(container_name: String, options: &MkfsOptions) -> Result<()> {
+    let container = Container::mkfs(container_name.clone(), options)?;
+    println!(
+        "{container_name}: {}-byte sectors, UUID {}, label \"{}\"",
+        container.sector_size(),
+        format_uuid(&container.uuid()),
+        container.label()
+    );
+    Ok(())
+}
+
+fn defrag(path: &str, online: bool) -> Result<()> {
+    if online {
+        //The mounted filesystem defragments its container when asked through the root
+        let c_path = CString::new(Path::new(path).as_os_str().as_bytes())?;
+        let name = CString::new(DEFRAG_XATTR)?;
+        let value = b"1";
+        // SAFETY: both strings are NUL-terminated and the value outlives the call
+        let ret = unsafe {
+            libc::setxattr(
+                c_path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("defragmenting the container mounted on {path}"));
+        }
+        println!("{path}: defragmented");
+        return Ok(());
+    }
+    if !Path::new(path).exists() {
+        bail!("The file {path} does not exist.");
+    }
+    let report = Container::new(path.to_string())?.defrag()?;
+    println!(
+        "{path}: {} sectors moved, {} -> {} bytes",
+        report.moved, report.size_before, report.size_after
+    );
+    Ok(())
+}
+
+/// Exit like fsck(8): 0 if the container is clean, 1 if it was repaired and 4 otherwise.
+fn fsck(container: &str, repair: bool) -> Result<()> {
+    let report = Container::fsck(container, repair)?;
+    for problem in &report.problems {
+        println!("{problem}");
+    }
+    if report.is_clean() {
+        println!("{container}: clean");
+        return Ok(());
+    }
+    if report.repaired {
+        println!("{container}: {} problems repaired", report.problems.len());
+        std::process::exit(1);
+    }
+    println!("{container}: {} problems found", report.problems.len());
+    std::process::exit(4);
+}
@@ -1,31 +1,108 @@
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 
+pub use self::bitmap::Bitmap;
+pub use self::block_map::BlockMap;
 pub use self::dir_data::DirData;
 pub use self::dir_entry::DirEntry;
 pub use self::empty::Empty;
 pub use self::file_data::FileData;
-pub use self::file_metadata::FileMetadata;
+pub use self::file_metadata::{FileMetadata, Permissions};
+pub use self::inode_table::InodeTable;
+pub use self::refcount_table::RefcountTable;
+pub use self::symlink_metadata::SymlinkMetadata;
+pub use self::xattr_data::XattrData;
 
+mod bitmap;
+mod block_map;
 mod dir_data;
 mod dir_entry;
 mod empty;
 mod file_data;
 mod file_metadata;
+mod inode_table;
+mod refcount_table;
+mod symlink_metadata;
+mod xattr_data;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Sector {
     Empty(Empty),
     FileMetadata(FileMetadata),
     FileData(FileData),
     DirMetadata(FileMetadata),
     DirData(DirData),
+    InodeTable(InodeTable),
+    SymlinkMetadata(SymlinkMetadata),
+    XattrData(XattrData),
+    Bitmap(Bitmap),
+    BlockMap(BlockMap),
+    RefcountTable(RefcountTable),
 }
+impl Sector {
+    /// Inode metadata held by this sector, if it is a metadata sector.
+    pub const fn metadata(&self) -> Option<&FileMetadata> {
+        match self {
+            Self::FileMetadata(metadata) | Self::DirMetadata(metadata) => Some(metadata),
+            Self::SymlinkMetadata(symlink) => Some(symlink.metadata()),
+            _ => None,
+        }
+    }
+    pub fn metadata_mut(&mut self) -> Option<&mut FileMetadata> {
+        match self {
+            Self::FileMetadata(metadata) | Self::DirMetadata(metadata) => Some(metadata),
+            Self::SymlinkMetadata(symlink) => Some(symlink.metadata_mut()),
+            _ => None,
+        }
+    }
+}
+/// Bytes taken by every sector of a container with the default geometry
+pub const SECTOR_SIZE: usize = 320;
+/// Default geometry, the one of containers created before it could be chosen
 pub const DATA_CHUNK_SIZE: usize = 200;
 pub const FILE_NAME_SIZE: usize = 30;
 pub const DIR_SECTOR_SIZE: usize = 5;
+pub const INODE_TABLE_SIZE: usize = 16;
+pub const SYMLINK_INLINE_SIZE: usize = 64;
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum FileType {
     Regular,
     Directory,
+    Symlink,
+}
+
+/// Sizes chosen when a container is created and recorded in its superblock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    /// Bytes of file data or extended attributes held by a sector
+    pub data_chunk_size: usize,
+    /// Entries held by a directory sector
+    pub dir_sector_size: usize,
+    /// Bytes reserved for a name, names are at most one byte shorter
+    pub file_name_size: usize,
+}
+impl Geometry {
+    /// Check that every size is in a range the container can handle.
+    pub fn check(&self) -> Result<()> {
+        if !(1..=65536).contains(&self.data_chunk_size) {
+            bail!("The data chunk size must be between 1 and 65536 bytes.");
+        }
+        if !(1..=1024).contains(&self.dir_sector_size) {
+            bail!("Directory sectors must hold between 1 and 1024 entries.");
+        }
+        if !(2..=256).contains(&self.file_name_size) {
+            bail!("Names must be between 1 and 255 bytes long.");
+        }
+        Ok(())
+    }
+}
+impl Default for Geometry {
+    fn default() -> Self {
+        Self {
+            data_chunk_size: DATA_CHUNK_SIZE,
+            dir_sector_size: DIR_SECTOR_SIZE,
+            file_name_size: FILE_NAME_SIZE,
+        }
+    }
 }
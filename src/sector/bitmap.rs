@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, Bytes};
+
+/// Allocation state of a group of sectors, one bit per sector set when it is used
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Bitmap {
+    #[serde_as(as = "Bytes")]
+    bits: Vec<u8>,
+}
+impl Bitmap {
+    /// Bitmap of `size` bytes with every sector free.
+    pub fn new(size: usize) -> Self {
+        Self {
+            bits: vec![0; size],
+        }
+    }
+    /// Number of sectors described
+    pub fn len(&self) -> usize {
+        self.bits.len() * 8
+    }
+    pub fn is_empty(&self) -> bool {
+        self.bits.is_empty()
+    }
+    pub fn is_used(&self, idx: usize) -> bool {
+        self.bits
+            .get(idx / 8)
+            .is_some_and(|byte| byte & (1 << (idx % 8)) != 0)
+    }
+    pub fn set_used(&mut self, idx: usize, used: bool) {
+        if let Some(byte) = self.bits.get_mut(idx / 8) {
+            if used {
+                *byte |= 1 << (idx % 8);
+            } else {
+                *byte &= !(1 << (idx % 8));
+            }
+        }
+    }
+    /// First free sector in `range`.
+    pub fn first_free(&self, range: std::ops::Range<usize>) -> Option<usize> {
+        let end = range.end.min(self.len());
+        let mut idx = range.start;
+        while idx < end {
+            //Skip full bytes at once
+            if idx % 8 == 0 && self.bits[idx / 8] == u8::MAX {
+                idx += 8;
+                continue;
+            }
+            if !self.is_used(idx) {
+                return Some(idx);
+            }
+            idx += 1;
+        }
+        None
+    }
+    /// Number of free sectors among the first `count`.
+    pub fn free_count(&self, count: usize) -> u64 {
+        let count = count.min(self.len());
+        let full_bytes = &self.bits[..count / 8];
+        let used = full_bytes
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum::<usize>()
+            + (count / 8 * 8..count)
+                .filter(|idx| self.is_used(*idx))
+                .count();
+        (count - used) as u64
+    }
+}
@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+
+/// Node of the block map of a file.
+///
+/// Entry i of a node of depth 0 is the `FileData` sector of block i of its range, entry i of
+/// a deeper node the node of depth - 1 mapping the i-th part of its range. Unmapped blocks
+/// are `None`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockMap {
+    depth: u8,
+    entries: Vec<Option<u64>>,
+}
+impl BlockMap {
+    /// Node of `depth` with `fan_out` unmapped entries.
+    pub fn new(depth: u8, fan_out: usize) -> Self {
+        Self {
+            depth,
+            entries: vec![None; fan_out],
+        }
+    }
+    pub const fn depth(&self) -> u8 {
+        self.depth
+    }
+    pub fn entries(&self) -> &[Option<u64>] {
+        &self.entries
+    }
+    pub fn entry(&self, idx: usize) -> Option<u64> {
+        self.entries.get(idx).copied().flatten()
+    }
+    pub fn set_entry(&mut self, idx: usize, sector_id: Option<u64>) {
+        if let Some(entry) = self.entries.get_mut(idx) {
+            *entry = sector_id;
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(Option::is_none)
+    }
+}
@@ -1,23 +1,20 @@
-use heapless::Vec;
 use serde::{Deserialize, Serialize};
 
 use crate::sector::DirEntry;
-use crate::sector::DIR_SECTOR_SIZE;
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct DirData {
     next_sector: Option<u64>,
     previous_sector: Option<u64>,
-    files: Vec<DirEntry, DIR_SECTOR_SIZE>,
+    files: Vec<DirEntry>,
 }
 impl DirData {
-    pub fn new() -> Self {
-        let mut files = Vec::new();
-        let _ = files.resize(DIR_SECTOR_SIZE, DirEntry::empty());
+    /// Sector of `dir_sector_size` empty entries.
+    pub fn new(dir_sector_size: usize) -> Self {
         Self {
             next_sector: None,
             previous_sector: None,
-            files,
+            files: vec![DirEntry::empty(); dir_sector_size],
         }
     }
     pub fn set_previous(&mut self, previous: u64) {
@@ -26,13 +23,19 @@ impl DirData {
     pub fn set_next(&mut self, next: u64) {
         self.next_sector = Some(next);
     }
+    pub fn clear_next(&mut self) {
+        self.next_sector = None;
+    }
     pub const fn next_sector(&self) -> Option<u64> {
         self.next_sector
     }
-    pub const fn entries(&self) -> &Vec<DirEntry, DIR_SECTOR_SIZE> {
+    pub const fn previous_sector(&self) -> Option<u64> {
+        self.previous_sector
+    }
+    pub fn entries(&self) -> &[DirEntry] {
         &self.files
     }
-    pub fn entries_mut(&mut self) -> &mut Vec<DirEntry, DIR_SECTOR_SIZE> {
+    pub fn entries_mut(&mut self) -> &mut [DirEntry] {
         &mut self.files
     }
 }
@@ -1,24 +1,23 @@
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, Bytes};
 
-use crate::sector::DATA_CHUNK_SIZE;
-
 #[serde_as]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileData {
     data_length: u64,
     next_sector: Option<u64>,
     previous_sector: Option<u64>,
     #[serde_as(as = "Bytes")]
-    data: [u8; DATA_CHUNK_SIZE],
+    data: Vec<u8>,
 }
 impl FileData {
-    pub const fn new() -> Self {
+    /// Empty sector holding up to `chunk_size` bytes.
+    pub fn new(chunk_size: usize) -> Self {
         Self {
             data_length: 0,
             next_sector: None,
             previous_sector: None,
-            data: [0; DATA_CHUNK_SIZE],
+            data: vec![0; chunk_size],
         }
     }
     pub const fn next(&self) -> Option<u64> {
@@ -27,25 +26,26 @@ impl FileData {
     pub fn set_next(&mut self, next: u64) {
         self.next_sector = Some(next);
     }
+    pub fn clear_next(&mut self) {
+        self.next_sector = None;
+    }
+    pub const fn previous(&self) -> Option<u64> {
+        self.previous_sector
+    }
     pub fn set_previous(&mut self, prev: u64) {
         self.previous_sector = Some(prev);
     }
-    pub const fn data(&self) -> &[u8] {
+    pub fn data(&self) -> &[u8] {
         &self.data
     }
     pub const fn data_length(&self) -> u64 {
         self.data_length
     }
     pub fn set_data_length(&mut self, data_length: u64) {
-        self.data_length = data_length.min(DATA_CHUNK_SIZE as u64);
+        self.data_length = data_length.min(self.data.len() as u64);
     }
     pub fn write(&mut self, data: &[u8], start: usize, end: usize) {
         let slice = &mut self.data[start..end];
         slice.clone_from_slice(data);
     }
 }
-impl Default for FileData {
-    fn default() -> Self {
-        Self::new()
-    }
-}
@@ -1,12 +1,76 @@
 use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Permissions reported for files written before permissions were stored in the container.
+const LEGACY_PERMISSIONS: Permissions = Permissions {
+    mode: 0o777,
+    uid: 501,
+    gid: 20,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    /// Permission bits, including setuid, setgid and sticky bits
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Point in time stored as seconds and nanoseconds relative to the UNIX epoch
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    secs: i64,
+    nanos: u32,
+}
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => Self {
+                secs: duration.as_secs() as i64,
+                nanos: duration.subsec_nanos(),
+            },
+            Err(err) => {
+                //Before the epoch, nanos still count forward from secs
+                let duration = err.duration();
+                let secs = -(duration.as_secs() as i64);
+                match duration.subsec_nanos() {
+                    0 => Self { secs, nanos: 0 },
+                    nanos => Self {
+                        secs: secs - 1,
+                        nanos: 1_000_000_000 - nanos,
+                    },
+                }
+            }
+        }
+    }
+}
+impl From<Timestamp> for SystemTime {
+    fn from(timestamp: Timestamp) -> Self {
+        let nanos = Duration::from_nanos(timestamp.nanos.into());
+        if timestamp.secs >= 0 {
+            UNIX_EPOCH + Duration::from_secs(timestamp.secs as u64) + nanos
+        } else {
+            UNIX_EPOCH - Duration::from_secs(timestamp.secs.unsigned_abs()) + nanos
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileMetadata {
     ino: u64,
     parent: Option<u64>,
     length_byte: u64,
     length_sector: u64,
     first_sector: Option<u64>,
+    permissions: Option<Permissions>,
+    atime: Timestamp,
+    mtime: Timestamp,
+    ctime: Timestamp,
+    crtime: Timestamp,
+    /// Number of names of the inode, None if written before it was stored
+    nlink: Option<u32>,
+    /// First sector of the extended attributes chain
+    xattr_sector: Option<u64>,
 }
 impl FileMetadata {
     pub const fn new(ino: u64, parent: Option<u64>) -> Self {
@@ -16,6 +80,13 @@ impl FileMetadata {
             length_byte: 0,
             length_sector: 0,
             first_sector: None,
+            permissions: None,
+            atime: Timestamp { secs: 0, nanos: 0 },
+            mtime: Timestamp { secs: 0, nanos: 0 },
+            ctime: Timestamp { secs: 0, nanos: 0 },
+            crtime: Timestamp { secs: 0, nanos: 0 },
+            nlink: None,
+            xattr_sector: None,
         }
     }
     pub const fn ino(&self) -> u64 {
@@ -24,15 +95,27 @@ impl FileMetadata {
     pub const fn parent(&self) -> Option<u64> {
         self.parent
     }
+    pub fn set_parent(&mut self, parent: u64) {
+        self.parent = Some(parent);
+    }
     pub const fn first_sector(&self) -> Option<u64> {
         self.first_sector
     }
     pub fn set_first_sector(&mut self, sector_id: u64) {
         self.first_sector = Some(sector_id);
     }
+    pub fn clear_first_sector(&mut self) {
+        self.first_sector = None;
+    }
+    pub const fn length_sector(&self) -> u64 {
+        self.length_sector
+    }
     pub fn increase_length_sector(&mut self) {
         self.length_sector += 1;
     }
+    pub fn set_length_sector(&mut self, length_sector: u64) {
+        self.length_sector = length_sector;
+    }
     pub const fn length_byte(&self) -> u64 {
         self.length_byte
     }
@@ -42,4 +125,51 @@ impl FileMetadata {
     pub fn set_length_byte(&mut self, length_byte: u64) {
         self.length_byte = length_byte;
     }
+    pub fn permissions(&self) -> Permissions {
+        self.permissions.unwrap_or(LEGACY_PERMISSIONS)
+    }
+    pub fn set_permissions(&mut self, permissions: Permissions) {
+        self.permissions = Some(permissions);
+    }
+    pub fn atime(&self) -> SystemTime {
+        self.atime.into()
+    }
+    pub fn set_atime(&mut self, time: SystemTime) {
+        self.atime = time.into();
+    }
+    pub fn mtime(&self) -> SystemTime {
+        self.mtime.into()
+    }
+    pub fn set_mtime(&mut self, time: SystemTime) {
+        self.mtime = time.into();
+    }
+    pub fn ctime(&self) -> SystemTime {
+        self.ctime.into()
+    }
+    pub fn set_ctime(&mut self, time: SystemTime) {
+        self.ctime = time.into();
+    }
+    pub fn crtime(&self) -> SystemTime {
+        self.crtime.into()
+    }
+    /// Set every timestamp of a newly created file.
+    pub fn set_created(&mut self, time: SystemTime) {
+        let timestamp = time.into();
+        self.atime = timestamp;
+        self.mtime = timestamp;
+        self.ctime = timestamp;
+        self.crtime = timestamp;
+    }
+    pub const fn nlink(&self) -> Option<u32> {
+        self.nlink
+    }
+    pub fn set_nlink(&mut self, nlink: u32) {
+        self.nlink = Some(nlink);
+    }
+    pub const fn xattr_sector(&self) -> Option<u64> {
+        self.xattr_sector
+    }
+    pub fn set_xattr_sector(&mut self, xattr_sector: Option<u64>) {
+        self.xattr_sector = xattr_sector;
+    }
 }
@@ -0,0 +1,34 @@
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::sector::INODE_TABLE_SIZE;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct InodeTable {
+    next_sector: Option<u64>,
+    sectors: Vec<Option<u64>, INODE_TABLE_SIZE>,
+}
+impl InodeTable {
+    pub fn new() -> Self {
+        let mut sectors = Vec::new();
+        let _ = sectors.resize(INODE_TABLE_SIZE, None);
+        Self {
+            next_sector: None,
+            sectors,
+        }
+    }
+    pub fn set_next(&mut self, next: u64) {
+        self.next_sector = Some(next);
+    }
+    pub const fn next_sector(&self) -> Option<u64> {
+        self.next_sector
+    }
+    pub const fn sectors(&self) -> &Vec<Option<u64>, INODE_TABLE_SIZE> {
+        &self.sectors
+    }
+    pub fn set_sector(&mut self, idx: usize, sector_id: Option<u64>) {
+        if let Some(slot) = self.sectors.get_mut(idx) {
+            *slot = sector_id;
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use serde::{Deserialize, Serialize};
+
+/// References to consecutive sectors besides the first one, 0 for sectors not shared.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RefcountTable {
+    next_sector: Option<u64>,
+    counts: Vec<u32>,
+}
+impl RefcountTable {
+    /// Table of `size` sectors, none of them shared.
+    pub fn new(size: usize) -> Self {
+        Self {
+            next_sector: None,
+            counts: vec![0; size],
+        }
+    }
+    pub fn set_next(&mut self, next: u64) {
+        self.next_sector = Some(next);
+    }
+    pub const fn next_sector(&self) -> Option<u64> {
+        self.next_sector
+    }
+    pub fn counts(&self) -> &[u32] {
+        &self.counts
+    }
+    pub fn set_count(&mut self, idx: usize, count: u32) {
+        if let Some(slot) = self.counts.get_mut(idx) {
+            *slot = count;
+        }
+    }
+}
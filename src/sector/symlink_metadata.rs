@@ -0,0 +1,42 @@
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::sector::FileMetadata;
+use crate::sector::SYMLINK_INLINE_SIZE;
+
+/// Metadata of a symbolic link.
+///
+/// Targets up to `SYMLINK_INLINE_SIZE` bytes are stored inline, longer ones in the
+/// `FileData` chain starting at the metadata's first sector.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SymlinkMetadata {
+    metadata: FileMetadata,
+    inline_target: Vec<u8, SYMLINK_INLINE_SIZE>,
+}
+impl SymlinkMetadata {
+    pub const fn new(metadata: FileMetadata) -> Self {
+        Self {
+            metadata,
+            inline_target: Vec::new(),
+        }
+    }
+    pub const fn metadata(&self) -> &FileMetadata {
+        &self.metadata
+    }
+    pub fn metadata_mut(&mut self) -> &mut FileMetadata {
+        &mut self.metadata
+    }
+    pub fn inline_target(&self) -> &[u8] {
+        &self.inline_target
+    }
+    /// Store `target` inline, returns false if it is too long to fit.
+    pub fn set_inline_target(&mut self, target: &[u8]) -> bool {
+        match Vec::from_slice(target) {
+            Ok(inline_target) => {
+                self.inline_target = inline_target;
+                true
+            }
+            Err(()) => false,
+        }
+    }
+}
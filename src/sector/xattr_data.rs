@@ -0,0 +1,39 @@
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, Bytes};
+
+/// Chunk of the encoded extended attributes of an inode, chained from its metadata
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct XattrData {
+    data_length: u64,
+    next_sector: Option<u64>,
+    #[serde_as(as = "Bytes")]
+    data: Vec<u8>,
+}
+impl XattrData {
+    /// Empty sector holding up to `chunk_size` bytes.
+    pub fn new(chunk_size: usize) -> Self {
+        Self {
+            data_length: 0,
+            next_sector: None,
+            data: vec![0; chunk_size],
+        }
+    }
+    pub const fn next_sector(&self) -> Option<u64> {
+        self.next_sector
+    }
+    pub fn set_next(&mut self, next: Option<u64>) {
+        self.next_sector = next;
+    }
+    pub fn data(&self) -> &[u8] {
+        let length = (self.data_length as usize).min(self.data.len());
+        &self.data[..length]
+    }
+    /// Store as much of `data` as fits and return the number of bytes stored.
+    pub fn set_data(&mut self, data: &[u8]) -> usize {
+        let length = data.len().min(self.data.len());
+        self.data[..length].copy_from_slice(&data[..length]);
+        self.data_length = length as u64;
+        length
+    }
+}